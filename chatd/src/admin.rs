@@ -0,0 +1,615 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use base64::Engine;
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error, info, warn};
+use crate::{AuthFinder, Ban, ClientOptions, ClientType, Config, Mutex, RwLock, Uuid};
+use crate::channel::{Channel, ChannelOptions};
+use crate::message::{Message as CMessage, MessageSendable, MessageType};
+use crate::server::Server;
+
+const MAX_REQUEST: usize = 64 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10); // to send the whole request in
+
+type Auth = Arc<RwLock<Box<dyn AuthFinder + Send + Sync>>>;
+
+struct Request {
+    method: String,
+    path: String,
+    headers: HashMap<String, String>, // names are lowercase
+    body: Vec<u8>,
+    peer: IpAddr,
+}
+
+struct Response {
+    status: u16,
+    body: Value,
+}
+
+impl Response {
+    fn ok(body: Value) -> Self {
+        Response { status: 200, body }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Response { status, body: json!({ "error": message }) }
+    }
+}
+
+#[derive(Serialize)]
+struct ClientEntry {
+    id: Uuid,
+    name: String,
+    r#type: ClientType,
+    ip: IpAddr,
+    account: Option<String>,
+    connected: u64, // unix time in seconds
+    channels: Vec<(Uuid, String)>,
+}
+
+#[derive(Serialize)]
+struct ChannelEntry {
+    id: Uuid,
+    name: String,
+    topic: Option<String>,
+    modes: Vec<String>,
+    member_limit: u64,
+    members: Vec<(Uuid, String)>,
+}
+
+#[derive(Deserialize)]
+struct KillBody {
+    reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct KlineBody {
+    target: String, // ip address or cidr range
+    reason: Option<String>,
+    expires_sec: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct UnklineBody {
+    target: String,
+}
+
+#[derive(Deserialize)]
+struct TopicBody {
+    topic: String,
+}
+
+#[derive(Deserialize)]
+struct ModesBody {
+    modes: u64,
+}
+
+#[derive(Deserialize)]
+struct WallBody {
+    message: String,
+}
+
+/// Serve the admin JSON api.
+///
+/// Requests need `Authorization: Bearer <admin_api_key>`, or Basic credentials of an agent
+/// with the Admin flag. This is plain http, keep it on a private address.
+pub async fn serve(server: Arc<Mutex<Server>>, authfinder: Auth, config: Config) {
+    let listener = match TcpListener::bind(SocketAddr::new(config.admin_address, config.admin_port)).await {
+        Ok(bind)    => bind,
+        Err(e)      => {
+            error!(address = %config.admin_address, port = config.admin_port, error = %e, "unable to bind admin api");
+            return;
+        }
+    };
+
+    info!(address = %config.admin_address, port = config.admin_port, "admin api listening");
+
+    loop {
+        // errors like running out of file descriptors pass, so wait a moment and keep listening
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted)    => accepted,
+            Err(e)          => {
+                warn!(error = %e, "admin api: accept failed");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let server = server.clone();
+        let authfinder = authfinder.clone();
+        let config = config.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = answer(stream, peer.ip(), &server, authfinder, &config).await {
+                warn!(ip = %peer.ip(), error = %e, "admin api: request failed");
+            }
+        });
+    }
+}
+
+async fn answer(mut stream: TcpStream, peer: IpAddr, server: &Arc<Mutex<Server>>, authfinder: Auth, config: &Config) -> std::io::Result<()> {
+    let request = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream, peer)).await {
+        Ok(request) => request?,
+        Err(_)      => {
+            debug!(ip = %peer, "admin api: request timed out");
+            return Ok(());
+        }
+    };
+
+    let response = match request {
+        None            => Response::error(400, "Malformed request."),
+        Some(_) if server.lock().await.auth_throttled(peer) => Response::error(429, "Too many failed logins, try again later."),
+        Some(request)   => match authenticate(&request, authfinder, config).await {
+            None        => {
+                server.lock().await.count_auth_failure(peer);
+                Response::error(401, "Invalid api key or credentials.")
+            },
+            Some(who)   => route(server, config, &request, &who).await
+        }
+    };
+
+    let body = response.body.to_string();
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        429 => "Too Many Requests",
+        _   => "Internal Server Error"
+    };
+    let head = format!("HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", response.status, reason, body.len());
+
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Read one request, None if it is not valid http or too large.
+async fn read_request(stream: &mut TcpStream, peer: IpAddr) -> std::io::Result<Option<Request>> {
+    let mut data: Vec<u8> = Vec::new();
+    let mut buffer = [0u8; 4096];
+
+    let header_end = loop {
+        // a read can bring in the end of headers that are already over the limit
+        if let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            if end > MAX_REQUEST {
+                return Ok(None);
+            }
+            break end;
+        }
+
+        if data.len() > MAX_REQUEST {
+            return Ok(None);
+        }
+
+        let read = stream.read(&mut buffer).await?;
+
+        if read == 0 {
+            return Ok(None);
+        }
+        data.extend_from_slice(&buffer[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&data[..header_end]).to_string();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let (method, path) = match (request_line.next(), request_line.next()) {
+        (Some(m), Some(p))  => (m.to_string(), p.split('?').next().unwrap_or_default().to_string()),
+        _                   => return Ok(None)
+    };
+
+    let headers: HashMap<String, String> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .collect();
+
+    let length = headers.get("content-length").and_then(|l| l.parse::<usize>().ok()).unwrap_or(0);
+
+    if length > MAX_REQUEST {
+        return Ok(None);
+    }
+
+    let mut body = data[header_end + 4..].to_vec();
+
+    while body.len() < length {
+        let read = stream.read(&mut buffer).await?;
+
+        if read == 0 {
+            return Ok(None);
+        }
+        body.extend_from_slice(&buffer[..read]);
+    }
+    body.truncate(length);
+
+    Ok(Some(Request { method, path, headers, body, peer }))
+}
+
+/// Name of whoever made the request, for logs and wallops. None if not allowed.
+async fn authenticate(request: &Request, authfinder: Auth, config: &Config) -> Option<String> {
+    let header = request.headers.get("authorization")?;
+
+    if let Some(key) = header.strip_prefix("Bearer ") {
+        if !config.admin_api_key.is_empty() && constant_time_eq(key.trim().as_bytes(), config.admin_api_key.as_bytes()) {
+            return Some("admin api".to_string());
+        }
+        return None;
+    }
+
+    let encoded = header.strip_prefix("Basic ")?;
+    let decoded = base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    let agent = authfinder.read().await.by_username_password(username, password).await?;
+
+    if agent.disabled || agent.permissions & ClientOptions::Admin.bits() == 0 {
+        return None;
+    }
+    Some(username.to_string())
+}
+
+/// Compare two secrets in a time that only depends on their lengths.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn parse_body<T: for<'de> Deserialize<'de>>(request: &Request) -> Result<T, Response> {
+    serde_json::from_slice::<T>(&request.body).map_err(|e| Response::error(400, &format!("Invalid request body: {}", e)))
+}
+
+async fn route(server: &Arc<Mutex<Server>>, config: &Config, request: &Request, who: &str) -> Response {
+    let segments: Vec<&str> = request.path.split('/').filter(|s| !s.is_empty()).collect();
+    info!(who = %who, method = %request.method, path = %request.path, ip = %request.peer, "admin api request");
+
+    let result = match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["clients"])                    => Ok(list_clients(server).await),
+        ("POST", ["clients", id, "kill"])       => kill(server, request, who, id).await,
+        ("GET", ["bans"])                       => Ok(list_bans(server).await),
+        ("POST", ["bans"])                      => kline(server, request, who).await,
+        ("DELETE", ["bans"])                    => unkline(server, request, who).await,
+        ("GET", ["channels"])                   => Ok(list_channels(server).await),
+        ("POST", ["channels", id, "topic"])     => set_topic(server, config, request, who, id).await,
+        ("POST", ["channels", id, "modes"])     => set_modes(server, request, who, id).await,
+        ("POST", ["wall"])                      => wall(server, config, request, who).await,
+        (_, ["clients"] | ["clients", _, "kill"] | ["bans"] | ["channels"] | ["channels", _, "topic" | "modes"] | ["wall"])
+                                                => Err(Response::error(405, "Method not allowed on this endpoint.")),
+        _                                       => Err(Response::error(404, "No such endpoint."))
+    };
+
+    result.unwrap_or_else(|e| e)
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+async fn list_clients(server: &Mutex<Server>) -> Response {
+    let mut list: Vec<ClientEntry> = Vec::new();
+
+    if let Some(clients) = server.lock().await.get_clients().await {
+        for client in clients.lock().await.values() {
+            let lock = client.read().await;
+
+            list.push(ClientEntry {
+                id: lock.id(),
+                name: lock.name(),
+                r#type: lock.gettype(),
+                ip: lock.ip(),
+                account: lock.account(),
+                connected: unix_time(lock.connected_time()),
+                channels: lock.channels().await.into_iter().collect(),
+            });
+        }
+    }
+
+    Response::ok(json!(list))
+}
+
+async fn kill(server: &Arc<Mutex<Server>>, request: &Request, who: &str, id: &str) -> Result<Response, Response> {
+    let body: KillBody = parse_body(request)?;
+    let id = Uuid::from_str(id).map_err(|_| Response::error(400, "Invalid client id."))?;
+    let target = server.lock().await.get_client_ref(id).await.ok_or(Response::error(404, "No matching client was found."))?;
+
+    if target.read().await.has_options(ClientOptions::Admin) {
+        return Err(Response::error(403, "You may not kill ADMIN flag users."));
+    }
+
+    let reason = CMessage::sanitize_text_message(body.reason.unwrap_or("no reason was provided.".to_string()));
+    let (name, ip) = {
+        let lock = target.read().await;
+        (lock.name(), lock.ip())
+    };
+
+    crate::disconnect_client(server, &target, format!("Killed ({})", reason)).await;
+    server.lock().await.sendto_wallops(CMessage::new(MessageType::Wallop(format!("<i>{}</i> killed <i>{}@{}</i> ({})", who, name, ip, reason)), Uuid::nil(), Uuid::nil(), None)).await;
+    Ok(Response::ok(json!({ "killed": id })))
+}
+
+async fn list_bans(server: &Mutex<Server>) -> Response {
+    let bans = server.lock().await.bans();
+    let list: Vec<Value> = bans.read().await.iter()
+        .filter(|ban| !ban.is_expired())
+        .map(|ban| json!({
+            "network": ban.network().to_string(),
+            "reason": ban.reason,
+            "added_by": ban.added_by,
+            "added_on": unix_time(ban.added_on),
+            "expires": unix_time(ban.expires),
+        }))
+        .collect();
+
+    Response::ok(json!(list))
+}
+
+async fn kline(server: &Arc<Mutex<Server>>, request: &Request, who: &str) -> Result<Response, Response> {
+    let body: KlineBody = parse_body(request)?;
+    let (min_v4, min_v6) = {
+        let slock = server.lock().await;
+        (slock.conf.kline_min_prefix_v4, slock.conf.kline_min_prefix_v6)
+    };
+    let network = Ban::parse_network(&body.target, min_v4, min_v6).map_err(|e| Response::error(400, &e))?;
+    let reason = CMessage::sanitize_text_message(body.reason.unwrap_or("no reason was provided.".to_string()));
+    let expires = SystemTime::now().checked_add(Duration::from_secs(body.expires_sec.unwrap_or(3600)))
+        .ok_or(Response::error(400, "The ban duration is too long."))?;
+    let mut slock = server.lock().await;
+
+    if slock.add_ban(network, &reason, expires, who).await.is_err() {
+        return Err(Response::error(409, "The ip is already in the ban list."));
+    }
+
+    let ban_file = slock.conf.ban_db.to_owned();
+
+    if let Err(e) = slock.save_ban_db(&ban_file).await {
+        error!(file = %ban_file, error = %e, "unable to save bans");
+    }
+    std::mem::drop(slock);
+
+    let found = crate::clients_in_network(server, network).await;
+    let killed = found.len();
+
+    for client in found {
+        crate::disconnect_client(server, &client, format!("Banned ({})", reason)).await;
+    }
+
+    server.lock().await.sendto_wallops(CMessage::new(MessageType::Wallop(
+        format!("<i>{}</i> server banned <i>{}</i> affecting <strong>{}</strong> clients ({})", who, network, killed, reason)), Uuid::nil(), Uuid::nil(), None)).await;
+    Ok(Response::ok(json!({ "banned": network.to_string(), "killed": killed })))
+}
+
+async fn unkline(server: &Mutex<Server>, request: &Request, who: &str) -> Result<Response, Response> {
+    let body: UnklineBody = parse_body(request)?;
+    // no prefix floor, bans wider than the current one must still be removable
+    let network = Ban::parse_network(&body.target, 0, 0).map_err(|e| Response::error(400, &e))?;
+    let mut slock = server.lock().await;
+
+    if slock.remove_ban(network).await.is_err() {
+        return Err(Response::error(404, "The ip is not in the ban list."));
+    }
+
+    let ban_file = slock.conf.ban_db.to_owned();
+
+    if let Err(e) = slock.save_ban_db(&ban_file).await {
+        error!(file = %ban_file, error = %e, "unable to save bans");
+    }
+
+    slock.sendto_wallops(CMessage::new(MessageType::Wallop(format!("<i>{}</i> removed the server ban on <i>{}</i>", who, network)), Uuid::nil(), Uuid::nil(), None)).await;
+    Ok(Response::ok(json!({ "removed": network.to_string() })))
+}
+
+async fn list_channels(server: &Mutex<Server>) -> Response {
+    let channels = server.lock().await.get_channels().await;
+    let mut list: Vec<ChannelEntry> = Vec::new();
+
+    for channel in channels.values() {
+        let lock = channel.read().await;
+
+        list.push(ChannelEntry {
+            id: lock.id(),
+            name: lock.name(),
+            topic: lock.topic(),
+            modes: lock.options_vec_string(),
+            member_limit: lock.member_limit(),
+            members: lock.member_list().await.into_iter().map(|(id, name, _)| (id, name)).collect(),
+        });
+    }
+
+    Response::ok(json!(list))
+}
+
+async fn channel_ref(server: &Mutex<Server>, id: &str) -> Result<Arc<RwLock<Channel>>, Response> {
+    let id = Uuid::from_str(id).map_err(|_| Response::error(400, "Invalid channel id."))?;
+    server.lock().await.get_channel_ref(id).await.ok_or(Response::error(404, "No matching channel was found."))
+}
+
+async fn set_topic(server: &Mutex<Server>, config: &Config, request: &Request, who: &str, id: &str) -> Result<Response, Response> {
+    let body: TopicBody = parse_body(request)?;
+    let chan_ref = channel_ref(server, id).await?;
+    let store = server.lock().await.channel_store();
+    let clean_topic = body.topic.replace('<', "&lt;").replace('>', "&gt;");
+
+    if clean_topic.char_indices().count() > config.max_topic_length as usize {
+        return Err(Response::error(400, &format!("Topics must be shorter than {} characters.", config.max_topic_length)));
+    }
+
+    let mut chan_lock = chan_ref.write().await;
+    let chan_id = chan_lock.id();
+
+    if clean_topic.is_empty() {
+        chan_lock.set_topic(None).await;
+    } else {
+        chan_lock.set_topic(Some(clean_topic.clone())).await;
+    }
+
+    if let Some(store) = &store {
+        store.persist(&chan_lock).await;
+
+        if chan_lock.has_option(ChannelOptions::SaveHistory) {
+            let history = CMessage::new(MessageType::Topic(clean_topic.clone()), Uuid::nil(), chan_id, Some(who.to_string()));
+
+            if let Err(e) = store.add_history(&chan_lock, &history, request.peer).await {
+                error!(channel = %chan_id, error = %e, "channel store: unable to save history");
+            }
+        }
+    }
+
+    let _ = chan_lock.sendto_all(&CMessage::new(MessageType::Message("<i class=\"ichat-modechange\"> has changed the channel topic.</i>".to_string()), Uuid::nil(), chan_id, Some(who.to_string()))).await;
+    let _ = chan_lock.sendto_all(&CMessage::new(MessageType::Topic(clean_topic.clone()), Uuid::nil(), chan_id, None)).await;
+    Ok(Response::ok(json!({ "channel": chan_id, "topic": clean_topic })))
+}
+
+async fn set_modes(server: &Mutex<Server>, request: &Request, who: &str, id: &str) -> Result<Response, Response> {
+    let body: ModesBody = parse_body(request)?;
+    let chan_ref = channel_ref(server, id).await?;
+    let store = server.lock().await.channel_store();
+    let limit_step = server.lock().await.conf.limit_step;
+    let mut chan_lock = chan_ref.write().await;
+    let chan_id = chan_lock.id();
+    let had_limit = chan_lock.has_option(ChannelOptions::Limit);
+    let options = chan_lock.get_options_from_u64(body.modes);
+
+    chan_lock.set_options(options);
+
+    if !chan_lock.has_option(ChannelOptions::Limit) {
+        chan_lock.clear_raised_limit();
+    } else if !had_limit {
+        chan_lock.raise_limit(limit_step).await;
+    }
+
+    if let Some(store) = &store {
+        store.persist(&chan_lock).await;
+    }
+
+    let chanopt = chan_lock.options_vec_string();
+    let _ = chan_lock.sendto_all(&CMessage::new(MessageType::Message("<i class=\"fa fa-gear\"> </i><i class=\"ichat-modechange\"> has changed the channel modes.</i>".to_string()), Uuid::nil(), chan_id, Some(who.to_string()))).await;
+    let _ = chan_lock.sendto_all(&CMessage::new(MessageType::ChannelModes(Some(chanopt.clone())), Uuid::nil(), chan_id, None)).await;
+    Ok(Response::ok(json!({ "channel": chan_id, "modes": chanopt })))
+}
+
+async fn wall(server: &Mutex<Server>, config: &Config, request: &Request, who: &str) -> Result<Response, Response> {
+    let body: WallBody = parse_body(request)?;
+    let message = CMessage::sanitize_text_message(body.message);
+    let length = message.char_indices().count();
+
+    if length == 0 || length > 512 {
+        return Err(Response::error(400, "Broadcasts must be between 1 and 512 characters."));
+    }
+
+    let count = server.lock().await.sendto_wall(CMessage::new(MessageType::Wall(message.clone()), Uuid::nil(), Uuid::nil(), Some(who.to_string()))).await;
+    info!(who = %who, reached = count, "wall (admin api)");
+
+    if config.log_message_bodies {
+        debug!(who = %who, body = %message, "wall (admin api)");
+    }
+    Ok(Response::ok(json!({ "reached": count })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use tokio::sync::mpsc;
+    use crate::auth::tests::sqlite;
+    use crate::server::tests::test_config;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    async fn admin(config: &Config) -> (Arc<Mutex<Server>>, Auth) {
+        let (_, server_rx) = mpsc::unbounded_channel();
+        let server = Arc::new(Mutex::new(Server::new(server_rx, config.clone())));
+        let mut agents = sqlite("s4lt").await;
+        agents.add("boss", "secret password", ClientOptions::Admin).await.unwrap();
+        agents.add("ray", "secret password", ClientOptions::JoinChannels).await.unwrap();
+        let authfinder: Auth = Arc::new(RwLock::new(Box::new(agents)));
+        (server, authfinder)
+    }
+
+    /// Send `request` as is over a local connection and return the status line of the answer.
+    async fn exchange(server: &Arc<Mutex<Server>>, authfinder: &Auth, config: &Config, request: &[u8]) -> String {
+        let listener = TcpListener::bind((LOCALHOST, 0)).await.unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (accepted, peer) = listener.accept().await.unwrap();
+        let answering = tokio::spawn({
+            let (server, authfinder, config) = (server.clone(), authfinder.clone(), config.clone());
+            async move { answer(accepted, peer.ip(), &server, authfinder, &config).await }
+        });
+
+        stream.write_all(request).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        answering.await.unwrap().unwrap();
+        response.lines().next().unwrap_or_default().to_string()
+    }
+
+    fn get(path: &str, authorization: &str) -> Vec<u8> {
+        format!("GET {} HTTP/1.1\r\nAuthorization: {}\r\n\r\n", path, authorization).into_bytes()
+    }
+
+    fn basic(username: &str, password: &str) -> String {
+        format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", username, password)))
+    }
+
+    #[tokio::test]
+    async fn requests_need_the_key_or_an_admin_agent() {
+        let config = Config { admin_api_key: "k3y".to_string(), ..test_config() };
+        let (server, authfinder) = admin(&config).await;
+
+        assert_eq!(exchange(&server, &authfinder, &config, &get("/clients", "Bearer k3y")).await, "HTTP/1.1 200 OK");
+        assert_eq!(exchange(&server, &authfinder, &config, &get("/clients", &basic("boss", "secret password"))).await, "HTTP/1.1 200 OK");
+        assert_eq!(exchange(&server, &authfinder, &config, &get("/clients", "Bearer nope")).await, "HTTP/1.1 401 Unauthorized");
+        assert_eq!(exchange(&server, &authfinder, &config, &get("/clients", &basic("ray", "secret password"))).await, "HTTP/1.1 401 Unauthorized");
+        assert_eq!(exchange(&server, &authfinder, &config, &get("/clients", &basic("boss", "wrong"))).await, "HTTP/1.1 401 Unauthorized");
+        assert_eq!(exchange(&server, &authfinder, &config, b"GET /clients HTTP/1.1\r\n\r\n").await, "HTTP/1.1 401 Unauthorized");
+
+        // without a configured key no bearer key is accepted, not even an empty one
+        let config = test_config();
+        assert_eq!(exchange(&server, &authfinder, &config, &get("/clients", "Bearer ")).await, "HTTP/1.1 401 Unauthorized");
+    }
+
+    #[tokio::test]
+    async fn failed_logins_are_throttled_per_ip() {
+        let config = Config { admin_api_key: "k3y".to_string(), auth_max_failures: 3, ..test_config() };
+        let (server, authfinder) = admin(&config).await;
+
+        for _ in 0..3 {
+            assert_eq!(exchange(&server, &authfinder, &config, &get("/clients", "Bearer nope")).await, "HTTP/1.1 401 Unauthorized");
+        }
+        assert_eq!(exchange(&server, &authfinder, &config, &get("/clients", "Bearer k3y")).await, "HTTP/1.1 429 Too Many Requests");
+        assert!(!server.lock().await.auth_throttled(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))));
+    }
+
+    #[tokio::test]
+    async fn oversized_requests_are_refused() {
+        let config = Config { admin_api_key: "k3y".to_string(), ..test_config() };
+        let (server, authfinder) = admin(&config).await;
+
+        let declared = format!("POST /wall HTTP/1.1\r\nAuthorization: Bearer k3y\r\nContent-Length: {}\r\n\r\n", MAX_REQUEST + 1);
+        assert_eq!(exchange(&server, &authfinder, &config, declared.as_bytes()).await, "HTTP/1.1 400 Bad Request");
+
+        let mut headers = b"GET /clients HTTP/1.1\r\nAuthorization: Bearer k3y\r\nX-Padding: ".to_vec();
+        headers.extend(vec![b'a'; MAX_REQUEST + 1]);
+        headers.extend_from_slice(b"\r\n\r\n");
+        assert_eq!(exchange(&server, &authfinder, &config, &headers).await, "HTTP/1.1 400 Bad Request");
+
+        assert_eq!(exchange(&server, &authfinder, &config, b"nonsense\r\n\r\n").await, "HTTP/1.1 400 Bad Request");
+    }
+
+    #[tokio::test]
+    async fn unknown_routes_and_methods() {
+        let config = Config { admin_api_key: "k3y".to_string(), ..test_config() };
+        let (server, authfinder) = admin(&config).await;
+
+        assert_eq!(exchange(&server, &authfinder, &config, &get("/nothing", "Bearer k3y")).await, "HTTP/1.1 404 Not Found");
+        assert_eq!(exchange(&server, &authfinder, &config, &get("/clients/x/kill/now", "Bearer k3y")).await, "HTTP/1.1 404 Not Found");
+        assert_eq!(exchange(&server, &authfinder, &config, &get("/wall", "Bearer k3y")).await, "HTTP/1.1 405 Method Not Allowed");
+        assert_eq!(exchange(&server, &authfinder, &config, &get("/channels/x/topic", "Bearer k3y")).await, "HTTP/1.1 405 Method Not Allowed");
+        assert_eq!(exchange(&server, &authfinder, &config, b"DELETE /clients HTTP/1.1\r\nAuthorization: Bearer k3y\r\n\r\n").await, "HTTP/1.1 405 Method Not Allowed");
+        assert_eq!(exchange(&server, &authfinder, &config, &get("/bans?all=1", "Bearer k3y")).await, "HTTP/1.1 200 OK");
+    }
+}
@@ -1,11 +1,21 @@
-// use std::collections::HashMap;
+use std::collections::HashMap;
 // use crate::{Sqlite, SqlitePoolOptions, query, Pool};
 use sha2::{Sha256, Digest};
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier, password_hash::{SaltString, rand_core::OsRng}};
 // use std::sync::Arc;
 // use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
 use crate::ClientOptions;
+use crate::config::LEGACY_AUTH_SALT;
 use async_trait::async_trait;
+use serde::Deserialize;
 use sqlx::{sqlite::SqlitePoolOptions, Pool, Sqlite, Row, Error as SQLError};
+use tracing::{error, info};
+
+const ARGON2_ALGORITHM: &str = "argon2id";
+const LEGACY_ALGORITHM: &str = "sha256";
+/// Most directory replies kept by `AuthInvent`, the oldest is dropped past this.
+const INVENT_CACHE_MAX: usize = 4096;
 
 #[derive(Clone)]
 pub struct AuthSqLite {
@@ -14,9 +24,37 @@ pub struct AuthSqLite {
     salt: String
 }
 
+/// Looks up agents in an external user directory over HTTP/JSON.
+///
+/// Every request carries `Authorization: Bearer <api key>`. The directory must provide:
+///
+/// - `POST {url}/auth` with `{"username", "password"}`, replying 200 with a user or 401
+/// - `GET {url}/users` replying with a list of users
+/// - `GET {url}/users/{username}` replying with a user or 404
+/// - `POST {url}/users` with `{"username", "password", "roles"}`, replying with the new user
+/// - `DELETE {url}/users/{username}` replying 2xx once removed
+///
+/// A user is `{"username": "...", "roles": ["agent", ...], "disabled": false}`. Roles are
+/// mapped to `ClientOptions` bits with `Config::invent_roles`, and users without any mapped
+/// role are not treated as agents. Plain `http://` urls work, so a local stub server can
+/// stand in for the directory.
 pub struct AuthInvent {
     pub url: String,
-    pub session: String
+    base: reqwest::Url,
+    pub session: String,
+    client: reqwest::Client,
+    roles: HashMap<String, u64>,
+    cache_ttl: Duration,
+    cache: std::sync::Mutex<HashMap<String, (Instant, Option<UserAuthEntry>)>>
+}
+
+#[derive(Deserialize)]
+struct InventUser {
+    username: String,
+    #[serde(default)]
+    roles: Vec<String>,
+    #[serde(default)]
+    disabled: bool
 }
 
 #[derive(Clone)]
@@ -25,6 +63,7 @@ pub struct UserAuthEntry {
     pub username: String,
     // password: String, // this is actually a hash
     pub permissions: u64,
+    pub disabled: bool,
 }
 
 impl AuthSqLite {
@@ -35,33 +74,280 @@ impl AuthSqLite {
         };
     }
 
+    /// Hashes a password with argon2id and a fresh random salt, returning a PHC string.
+    ///
+    /// Runs on the blocking pool since the KDF is deliberately slow.
+    async fn kdf_hash(password: &str) -> Result<String, String> {
+        let password = password.to_string();
+
+        match tokio::task::spawn_blocking(move || {
+            let salt = SaltString::generate(&mut OsRng);
+            Argon2::default().hash_password(password.as_bytes(), &salt).map(|hash| hash.to_string())
+        }).await {
+            Ok(Ok(hash))    => Ok(hash),
+            Ok(Err(e))      => Err(e.to_string()),
+            Err(e)          => Err(e.to_string())
+        }
+    }
+
+    /// Checks a password against a stored argon2 PHC string.
+    async fn kdf_verify(password: &str, hash: &str) -> bool {
+        let password = password.to_string();
+        let hash = hash.to_string();
+
+        tokio::task::spawn_blocking(move || {
+            match PasswordHash::new(&hash) {
+                Ok(parsed)  => Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+                Err(_)      => false
+            }
+        }).await.unwrap_or(false)
+    }
 }
+impl AuthInvent {
+    pub fn new(url: String, session: String, timeout_sec: u64, cache_ttl_sec: u64, roles: HashMap<String, u64>) -> Result<Self, String> {
+        let client = match reqwest::Client::builder().timeout(Duration::from_secs(timeout_sec)).build() {
+            Ok(c)   => c,
+            Err(e)  => return Err(e.to_string())
+        };
+
+        let url = url.trim_end_matches('/').to_string();
+        let base = match reqwest::Url::parse(&url) {
+            Ok(base) if !base.cannot_be_a_base()   => base,
+            Ok(_)   => return Err(format!("{} can not be used as a base url", url)),
+            Err(e)  => return Err(e.to_string())
+        };
+
+        Ok(AuthInvent {
+            url, base,
+            session, client, roles,
+            cache_ttl: Duration::from_secs(cache_ttl_sec),
+            cache: std::sync::Mutex::new(HashMap::new())
+        })
+    }
+
+    /// Maps a directory user to an auth entry, None if disabled or without a known role.
+    fn to_entry(&self, user: InventUser) -> Option<UserAuthEntry> {
+        if user.disabled {
+            return None;
+        }
+
+        let mut permissions = 0u64;
+        let mut mapped = false;
+
+        for role in user.roles.iter() {
+            if let Some(bits) = self.roles.get(role) {
+                permissions |= bits;
+                mapped = true;
+            }
+        }
+
+        if !mapped {
+            return None;
+        }
+
+        Some(UserAuthEntry { username: user.username, permissions, disabled: false })
+    }
+
+    /// `{url}/users/{username}` with the username escaped as a single path segment.
+    fn user_url(&self, username: &str) -> reqwest::Url {
+        let mut url = self.base.clone();
+
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.pop_if_empty().push("users").push(username);
+        }
+        url
+    }
+
+    fn cache_get(&self, key: &str) -> Option<Option<UserAuthEntry>> {
+        let mut cache = self.cache.lock().unwrap();
+
+        match cache.get(key) {
+            Some((added, entry)) if added.elapsed() < self.cache_ttl => Some(entry.clone()),
+            Some(_) => { cache.remove(key); None },
+            None    => None
+        }
+    }
+
+    fn cache_put(&self, key: String, entry: Option<UserAuthEntry>) {
+        if self.cache_ttl.is_zero() {
+            return;
+        }
+
+        let mut cache = self.cache.lock().unwrap();
+
+        // unknown usernames are cached too, so keep random ones from growing the cache forever
+        if cache.len() >= INVENT_CACHE_MAX && !cache.contains_key(&key) {
+            cache.retain(|_, (added, _)| added.elapsed() < self.cache_ttl);
+
+            if cache.len() >= INVENT_CACHE_MAX {
+                if let Some(oldest) = cache.iter().min_by_key(|(_, (added, _))| *added).map(|(k, _)| k.clone()) {
+                    cache.remove(&oldest);
+                }
+            }
+        }
+
+        cache.insert(key, (Instant::now(), entry));
+    }
+
+    fn cache_clear(&self, username: &str) {
+        let user_key = format!("user:{}", username);
+        let auth_key = format!("auth:{}:", username);
+        self.cache.lock().unwrap().retain(|k, _| *k != user_key && !k.starts_with(&auth_key));
+    }
+}
+
 #[async_trait]
 impl AuthFinder for AuthInvent {
-    async fn by_username_password(&self, _username: &str, _password: &str) -> Option<UserAuthEntry> {
-        unimplemented!("Not for this version");
+    async fn by_username_password(&self, username: &str, password: &str) -> Option<UserAuthEntry> {
+        // failed logins are not cached, so a fixed password works right away
+        let key = format!("auth:{}:{}", username, self.hash_password(password, &self.session));
+
+        if let Some(Some(entry)) = self.cache_get(&key) {
+            return Some(entry);
+        }
+
+        let response = self.client.post(format!("{}/auth", self.url))
+            .bearer_auth(&self.session)
+            .json(&serde_json::json!({ "username": username, "password": password }))
+            .send()
+            .await;
+
+        let response = match response {
+            Ok(r) if r.status().is_success()  => r,
+            Ok(_)   => return None,
+            Err(e)  => { error!(error = %e, "invent auth request failed"); return None; }
+        };
+
+        let entry = match response.json::<InventUser>().await {
+            Ok(user)    => self.to_entry(user),
+            Err(e)      => { error!(error = %e, "invent auth reply invalid"); return None; }
+        };
+
+        if entry.is_some() {
+            self.cache_put(key, entry.clone());
+        }
+        entry
     }
-    async fn by_username(&self, _username: &str) -> Option<UserAuthEntry> {
-        unimplemented!("Not for this version");
+
+    async fn by_username(&self, username: &str) -> Option<UserAuthEntry> {
+        let key = format!("user:{}", username);
+
+        if let Some(entry) = self.cache_get(&key) {
+            return entry;
+        }
+
+        let response = self.client.get(self.user_url(username))
+            .bearer_auth(&self.session)
+            .send()
+            .await;
+
+        let entry = match response {
+            Ok(r) if r.status().is_success() => match r.json::<InventUser>().await {
+                Ok(user)    => self.to_entry(user),
+                Err(e)      => { error!(error = %e, "invent user reply invalid"); return None; }
+            },
+            Ok(r) if r.status() == reqwest::StatusCode::NOT_FOUND => None,
+            Ok(r)   => { error!(status = %r.status(), "invent user lookup failed"); return None; }
+            Err(e)  => { error!(error = %e, "invent user request failed"); return None; }
+        };
+
+        self.cache_put(key, entry.clone());
+        entry
     }
-    fn all(&self) -> Option<Vec<UserAuthEntry>> {
-        unimplemented!("Not for this version");
+
+    async fn all(&self) -> Option<Vec<UserAuthEntry>> {
+        let response = self.client.get(format!("{}/users", self.url))
+            .bearer_auth(&self.session)
+            .send()
+            .await;
+
+        match response {
+            Ok(r) if r.status().is_success() => match r.json::<Vec<InventUser>>().await {
+                Ok(users)   => Some(users.into_iter().filter_map(|u| self.to_entry(u)).collect()),
+                Err(e)      => { error!(error = %e, "invent users reply invalid"); None }
+            },
+            Ok(r)   => { error!(status = %r.status(), "invent users list failed"); None }
+            Err(e)  => { error!(error = %e, "invent users request failed"); None }
+        }
     }
-    async fn add(&mut self, _username: &str, _password: &str, _permissions: ClientOptions) -> Result<UserAuthEntry, String> {
-        unimplemented!("Not for this version");
+
+    async fn add(&mut self, username: &str, password: &str, permissions: ClientOptions) -> Result<UserAuthEntry, String> {
+        let roles: Vec<String> = self.roles.iter()
+            .filter(|(_, bits)| **bits != 0 && permissions.bits() & **bits == **bits)
+            .map(|(role, _)| role.to_owned())
+            .collect();
+
+        let response = self.client.post(format!("{}/users", self.url))
+            .bearer_auth(&self.session)
+            .json(&serde_json::json!({ "username": username, "password": password, "roles": roles }))
+            .send()
+            .await;
+
+        let response = match response {
+            Ok(r) if r.status().is_success() => r,
+            Ok(r)   => return Err(format!("directory returned {}", r.status())),
+            Err(e)  => return Err(e.to_string())
+        };
+
+        self.cache_clear(username);
+
+        match response.json::<InventUser>().await {
+            Ok(user)    => self.to_entry(user).ok_or("user has no agent role".to_string()),
+            Err(e)      => Err(e.to_string())
+        }
     }
-    fn delete(&mut self, _username: &str) -> Result<(), String> {
-        unimplemented!("Not for this version");
+
+    async fn delete(&mut self, username: &str) -> Result<(), String> {
+        let response = self.client.delete(self.user_url(username))
+            .bearer_auth(&self.session)
+            .send()
+            .await;
+
+        self.cache_clear(username);
+
+        match response {
+            Ok(r) if r.status().is_success() => Ok(()),
+            Ok(r)   => Err(format!("directory returned {}", r.status())),
+            Err(e)  => Err(e.to_string())
+        }
     }
-    fn hash_password(&self, _password: &str, _salt: &str) -> String {
-        unimplemented!("Not for this version");
+
+    async fn set_disabled(&mut self, _username: &str, _disabled: bool) -> Result<(), String> {
+        Err("agents are managed by the Invent directory".to_string())
     }
+
+    async fn set_password(&mut self, _username: &str, _password: &str) -> Result<(), String> {
+        Err("agents are managed by the Invent directory".to_string())
+    }
+
+    async fn set_permissions(&mut self, _username: &str, _permissions: u64) -> Result<(), String> {
+        Err("agents are managed by the Invent directory".to_string())
+    }
+
+    /// Only used for cache keys, the directory checks passwords itself.
+    fn hash_password(&self, password: &str, salt: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(format!("{}+{}", password, salt));
+        hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    /// The directory owns its accounts, so we never generate a local admin for it.
     async fn has_any(&self) -> Result<bool, ()> {
-        unimplemented!("Not for this version");
+        Ok(true)
     }
 
     async fn create_tables(&mut self) -> i8 {
-        unimplemented!("Not for this version");
+        0
+    }
+
+    async fn by_device(&self, _device: &str, _username: &str) -> Option<UserAuthEntry> {
+        None
+    }
+    async fn add_device(&mut self, _device: &str, _username: &str, _added_by: &str) -> Result<(), String> {
+        Err("devices are not supported by the Invent auth backend".to_string())
+    }
+    async fn delete_devices(&mut self, _username: &str) -> Result<u64, String> {
+        Err("devices are not supported by the Invent auth backend".to_string())
     }
 }
 
@@ -70,21 +356,61 @@ impl AuthFinder for AuthSqLite {
     /// Searches for a record using a username and password.
     ///
     /// This is generally used to look up a record during auth
+    ///
+    /// Legacy sha256 entries are rehashed with argon2id after a successful login.
     async fn by_username_password(&self, username: &str, password: &str) -> Option<UserAuthEntry> {
-        let query = sqlx::query("SELECT `permissions` FROM `agents` WHERE `username` = ? AND `password` = ? LIMIT 1")
+        let query = sqlx::query("SELECT `permissions`, `password`, `algorithm` FROM `agents` WHERE `username` = ? AND `disabled` = 0 LIMIT 1")
             .bind(username)
-            .bind(self.hash_password(password, &self.salt))
             .fetch_one(&self.connection)
             .await;
 
         if query.is_err() { return None; }
+
+        let row = query.unwrap();
+        let permissions: u64 = row.get(0);
+        let stored: String = row.get(1);
+        let algorithm: String = row.get(2);
+
+        match algorithm.as_str() {
+            ARGON2_ALGORITHM    => {
+                if !AuthSqLite::kdf_verify(password, &stored).await {
+                    return None;
+                }
+            },
+            LEGACY_ALGORITHM    => {
+                if self.hash_password(password, &self.salt) != stored && self.hash_password(password, LEGACY_AUTH_SALT) != stored {
+                    return None;
+                }
+
+                match AuthSqLite::kdf_hash(password).await {
+                    Ok(hash)    => {
+                        let update = sqlx::query("UPDATE `agents` SET `password` = ?, `algorithm` = ? WHERE `username` = ?")
+                            .bind(hash)
+                            .bind(ARGON2_ALGORITHM)
+                            .bind(username)
+                            .execute(&self.connection)
+                            .await;
+
+                        match update {
+                            Ok(_)   => info!(username = %username, algorithm = ARGON2_ALGORITHM, "database: migrated password hash"),
+                            Err(e)  => error!(username = %username, error = %e, "unable to migrate password hash")
+                        }
+                    },
+                    Err(e)  => error!(username = %username, error = %e, "unable to migrate password hash")
+                }
+            },
+            _   => {
+                error!(username = %username, algorithm = %algorithm, "unknown password algorithm");
+                return None;
+            }
+        }
         
-        Some(UserAuthEntry { username: username.to_string(), permissions: query.unwrap().get(0) }) 
+        Some(UserAuthEntry { username: username.to_string(), permissions, disabled: false }) 
     }
     
     /// Searches for a record using just a username
     async fn by_username(&self, username: &str) -> Option<UserAuthEntry> {
-        let query = sqlx::query("SELECT `permissions` FROM `agents` WHERE `username` = ? LIMIT 1")
+        let query = sqlx::query("SELECT `permissions`, `disabled` FROM `agents` WHERE `username` = ? LIMIT 1")
             .bind(username)
             .fetch_one(&self.connection)
             .await;
@@ -93,17 +419,25 @@ impl AuthFinder for AuthSqLite {
             match query {
                 Ok(_) => { return None; }
                 Err(SQLError::RowNotFound) => { return None; }
-                Err(e)  => { println!("other error {}", e.to_string()); return None; }
+                Err(e)  => { error!(error = %e, "agent lookup failed"); return None; }
             }
             // return None; 
         }
         
-        Some(UserAuthEntry { username: username.to_string(), permissions: query.unwrap().get(0) })
+        let row = query.unwrap();
+        Some(UserAuthEntry { username: username.to_string(), permissions: row.get(0), disabled: row.get(1) })
     }
 
-    fn all(&self) -> Option<Vec<UserAuthEntry>> {
+    /// Lists every agent in the database, ordered by username.
+    async fn all(&self) -> Option<Vec<UserAuthEntry>> {
+        let query = sqlx::query("SELECT `username`, `permissions`, `disabled` FROM `agents` ORDER BY `username`")
+            .fetch_all(&self.connection)
+            .await;
 
-        None
+        match query {
+            Ok(rows)    => Some(rows.iter().map(|row| UserAuthEntry { username: row.get(0), permissions: row.get(1), disabled: row.get(2) }).collect()),
+            Err(e)      => { error!(error = %e, "unable to list agents"); None }
+        }
     }
 
     /// Adds a username and password to the database.
@@ -114,25 +448,81 @@ impl AuthFinder for AuthSqLite {
             return Err("already exists".to_string());
         }
 
-        let hash = self.hash_password(password, &self.salt);
+        let hash = AuthSqLite::kdf_hash(password).await?;
         // let permission_int = permissions.
-        let query = sqlx::query("INSERT INTO `agents` (`username`, `password`, `permissions`) VALUES (?, ?, ?)")
+        let query = sqlx::query("INSERT INTO `agents` (`username`, `password`, `permissions`, `algorithm`) VALUES (?, ?, ?, ?)")
             .bind(username)
             .bind(hash)
             .bind(permissions.bits() as i64)
+            .bind(ARGON2_ALGORITHM)
             .execute(&self.connection)
             .await;
 
         if query.is_err() { 
             return Err(query.unwrap_err().to_string()); }
 
-        Ok(UserAuthEntry { username: username.to_string(), permissions: permissions.bits() })
+        Ok(UserAuthEntry { username: username.to_string(), permissions: permissions.bits(), disabled: false })
+
+    }
+
+    /// Removes an agent from the database.
+    async fn delete(&mut self, username: &str) -> Result<(), String> {
+        let query = sqlx::query("DELETE FROM `agents` WHERE `username` = ?")
+            .bind(username)
+            .execute(&self.connection)
+            .await;
 
+        match query {
+            Ok(result) if result.rows_affected() == 0 => Err("does not exist".to_string()),
+            Ok(_)   => Ok(()),
+            Err(e)  => Err(e.to_string())
+        }
     }
 
-    fn delete(&mut self, username: &str) -> Result<(), String> {
+    /// Disables or enables an agent. Disabled agents cannot log in.
+    async fn set_disabled(&mut self, username: &str, disabled: bool) -> Result<(), String> {
+        let query = sqlx::query("UPDATE `agents` SET `disabled` = ? WHERE `username` = ?")
+            .bind(disabled)
+            .bind(username)
+            .execute(&self.connection)
+            .await;
 
-        Ok(())
+        match query {
+            Ok(result) if result.rows_affected() == 0 => Err("does not exist".to_string()),
+            Ok(_)   => Ok(()),
+            Err(e)  => Err(e.to_string())
+        }
+    }
+
+    /// Replaces an agents password. The password will be encrypted automatically.
+    async fn set_password(&mut self, username: &str, password: &str) -> Result<(), String> {
+        let query = sqlx::query("UPDATE `agents` SET `password` = ?, `algorithm` = ? WHERE `username` = ?")
+            .bind(AuthSqLite::kdf_hash(password).await?)
+            .bind(ARGON2_ALGORITHM)
+            .bind(username)
+            .execute(&self.connection)
+            .await;
+
+        match query {
+            Ok(result) if result.rows_affected() == 0 => Err("does not exist".to_string()),
+            Ok(_)   => Ok(()),
+            Err(e)  => Err(e.to_string())
+        }
+    }
+
+    /// Replaces an agents ClientOptions bits.
+    async fn set_permissions(&mut self, username: &str, permissions: u64) -> Result<(), String> {
+        let query = sqlx::query("UPDATE `agents` SET `permissions` = ? WHERE `username` = ?")
+            .bind(permissions as i64)
+            .bind(username)
+            .execute(&self.connection)
+            .await;
+
+        match query {
+            Ok(result) if result.rows_affected() == 0 => Err("does not exist".to_string()),
+            Ok(_)   => Ok(()),
+            Err(e)  => Err(e.to_string())
+        }
     }
 
     /// Checks if the database has any agents, returns result ok or false or err if a problem.
@@ -144,31 +534,93 @@ impl AuthFinder for AuthSqLite {
         match query {
             Ok(_)   => return Ok(true),
             Err(sqlx::Error::RowNotFound) => return Ok(false),
-            Err(e) => { println!("error: {}", e.to_string()); return Err(()) }
+            Err(e) => { error!(error = %e, "unable to check for agents"); return Err(()) }
         }
     }
 
+    /// Single pass sha256 with the shared config salt.
+    ///
+    /// Only used to check legacy agent passwords and to hash device ids, which are random.
     fn hash_password(&self, password: &str, salt: &str) -> String {
         let mut hasher = Sha256::new();
         hasher.update(format!("{}+{}", password, salt));
         hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
     }
-    async fn create_tables(&mut self) -> i8 {
-        let query = sqlx::query("SELECT `id` FROM `agents` LIMIT 1")
-            .fetch_one(&self.connection)
+    /// Searches for a registered device belonging to a username.
+    ///
+    /// Devices are stored hashed, so the raw device id is never kept on disk.
+    async fn by_device(&self, device: &str, username: &str) -> Option<UserAuthEntry> {
+        // devices registered before auth_salt was generated are hashed with the legacy salt
+        for salt in [self.salt.as_str(), LEGACY_AUTH_SALT] {
+            let query = sqlx::query("SELECT `username` FROM `devices` WHERE `device` = ? AND `username` = ? LIMIT 1")
+                .bind(self.hash_password(device, salt))
+                .bind(username)
+                .fetch_one(&self.connection)
+                .await;
+
+            match query {
+                Ok(_)   => return Some(UserAuthEntry { username: username.to_string(), permissions: 0, disabled: false }),
+                Err(SQLError::RowNotFound) => continue,
+                Err(e)  => { error!(error = %e, "device lookup failed"); return None; }
+            }
+        }
+        None
+    }
+
+    /// Registers a device id for a username, so it can log in as a contact.
+    async fn add_device(&mut self, device: &str, username: &str, added_by: &str) -> Result<(), String> {
+        let added_on = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs();
+        let query = sqlx::query("INSERT INTO `devices` (`device`, `username`, `added_by`, `added_on`) VALUES (?, ?, ?, ?)")
+            .bind(self.hash_password(device, &self.salt))
+            .bind(username)
+            .bind(added_by)
+            .bind(added_on as i64)
+            .execute(&self.connection)
+            .await;
+
+        match query {
+            Ok(_)   => Ok(()),
+            Err(e)  => Err(e.to_string())
+        }
+    }
+
+    /// Revokes every device registered to a username, returning how many were removed.
+    async fn delete_devices(&mut self, username: &str) -> Result<u64, String> {
+        let query = sqlx::query("DELETE FROM `devices` WHERE `username` = ?")
+            .bind(username)
+            .execute(&self.connection)
             .await;
 
         match query {
-            Ok(_)   => return 018,
-            Err(_) => {} // not found, try and create it next       
+            Ok(result)  => Ok(result.rows_affected()),
+            Err(e)      => Err(e.to_string())
         }
+    }
 
-        match sqlx::query("CREATE TABLE `agents` (id INTEGER PRIMARY KEY, username TEXT NOT NULL UNIQUE, password BLOB NOT NULL, permissions INTEGER NOT NULL DEFAULT 0)")
+    async fn create_tables(&mut self) -> i8 {
+        if let Err(e) = sqlx::query("CREATE TABLE IF NOT EXISTS `devices` (id INTEGER PRIMARY KEY, device TEXT NOT NULL UNIQUE, username TEXT NOT NULL, added_by TEXT NOT NULL, added_on INTEGER NOT NULL)")
             .execute(&self.connection)
             .await {
-            Ok(_) => return 1i8,
-            Err(_)=> return -1i8
+            error!(error = %e, "unable to create devices table");
+            return -1i8;
         }
+
+        if let Err(e) = sqlx::query("CREATE TABLE IF NOT EXISTS `agents` (id INTEGER PRIMARY KEY, username TEXT NOT NULL UNIQUE, password BLOB NOT NULL, permissions INTEGER NOT NULL DEFAULT 0, disabled INTEGER NOT NULL DEFAULT 0, algorithm TEXT NOT NULL DEFAULT 'sha256')")
+            .execute(&self.connection)
+            .await {
+            error!(error = %e, "unable to create agents table");
+            return -1i8;
+        }
+
+        // older databases predate these columns, this fails harmlessly once they exist
+        let _ = sqlx::query("ALTER TABLE `agents` ADD COLUMN `disabled` INTEGER NOT NULL DEFAULT 0")
+            .execute(&self.connection)
+            .await;
+        let _ = sqlx::query("ALTER TABLE `agents` ADD COLUMN `algorithm` TEXT NOT NULL DEFAULT 'sha256'")
+            .execute(&self.connection)
+            .await;
+
+        1i8
     }
 }
 
@@ -176,10 +628,211 @@ impl AuthFinder for AuthSqLite {
 pub trait AuthFinder: Send + Sync { 
     async fn by_username_password(&self, username: &str, password: &str) -> Option<UserAuthEntry>;
     async fn by_username(&self, username: &str) -> Option<UserAuthEntry>;
-    fn all(&self) -> Option<Vec<UserAuthEntry>>;
+    async fn all(&self) -> Option<Vec<UserAuthEntry>>;
     async fn add(&mut self, username: &str, password: &str, permissions: ClientOptions) -> Result<UserAuthEntry, String>;
-    fn delete(&mut self, username: &str) -> Result<(), String>;
+    async fn delete(&mut self, username: &str) -> Result<(), String>;
+    async fn set_disabled(&mut self, username: &str, disabled: bool) -> Result<(), String>;
+    async fn set_password(&mut self, username: &str, password: &str) -> Result<(), String>;
+    async fn set_permissions(&mut self, username: &str, permissions: u64) -> Result<(), String>;
     fn hash_password(&self, password: &str, salt: &str) -> String;
     async fn has_any(&self) -> Result<bool,()>;
     async fn create_tables(&mut self) -> i8;
+    async fn by_device(&self, device: &str, username: &str) -> Option<UserAuthEntry>;
+    async fn add_device(&mut self, device: &str, username: &str, added_by: &str) -> Result<(), String>;
+    async fn delete_devices(&mut self, username: &str) -> Result<u64, String>;
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// A local stand-in for the Invent directory, returns its url and the request lines it saw.
+    async fn stub_directory() -> (String, Arc<std::sync::Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = seen.clone();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buffer = vec![0u8; 8192];
+                let read = stream.read(&mut buffer).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&buffer[..read]).to_string();
+                let line = request.lines().next().unwrap_or("").to_string();
+                let authorized = request.contains("authorization: Bearer k3y") || request.contains("Authorization: Bearer k3y");
+                log.lock().unwrap().push(line.clone());
+
+                let (status, body) = if !authorized {
+                    ("401 Unauthorized", String::new())
+                } else if line.starts_with("POST /auth ") && request.contains("\"password\":\"right\"") {
+                    ("200 OK", r#"{"username":"ray","roles":["agent"]}"#.to_string())
+                } else if line.starts_with("POST /auth ") {
+                    ("401 Unauthorized", String::new())
+                } else if line.starts_with("GET /users/ray ") {
+                    ("200 OK", r#"{"username":"ray","roles":["admin"]}"#.to_string())
+                } else if line.starts_with("GET /users/guest ") {
+                    ("200 OK", r#"{"username":"guest","roles":["customer"]}"#.to_string())
+                } else if line.starts_with("GET /users ") {
+                    ("200 OK", r#"[{"username":"ray","roles":["agent"]},{"username":"off","roles":["agent"],"disabled":true}]"#.to_string())
+                } else {
+                    ("404 Not Found", String::new())
+                };
+
+                let response = format!("HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body);
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+
+        (url, seen)
+    }
+
+    fn invent(url: &str) -> AuthInvent {
+        AuthInvent::new(url.to_string(), "k3y".to_string(), 5, 300, HashMap::from([
+            ("agent".to_string(), 60u64),
+            ("admin".to_string(), 62u64),
+        ])).unwrap()
+    }
+
+    /// An agents database in a fresh temporary file.
+    pub(crate) async fn sqlite(salt: &str) -> AuthSqLite {
+        let file = std::env::temp_dir().join(format!("chatd-test-{}.auth", uuid::Uuid::new_v4()));
+        std::fs::File::create(&file).unwrap();
+        let mut auth = AuthSqLite::new(file.to_string_lossy().to_string(), salt.to_string()).await.unwrap();
+        auth.create_tables().await;
+        auth
+    }
+
+    async fn add_legacy(auth: &AuthSqLite, username: &str, password: &str, salt: &str) {
+        sqlx::query("INSERT INTO `agents` (`username`, `password`, `permissions`, `algorithm`) VALUES (?, ?, 60, 'sha256')")
+            .bind(username)
+            .bind(auth.hash_password(password, salt))
+            .execute(&auth.connection)
+            .await
+            .unwrap();
+    }
+
+    async fn algorithm(auth: &AuthSqLite, username: &str) -> String {
+        sqlx::query("SELECT `algorithm` FROM `agents` WHERE `username` = ?")
+            .bind(username)
+            .fetch_one(&auth.connection)
+            .await
+            .unwrap()
+            .get(0)
+    }
+
+    #[tokio::test]
+    async fn sqlite_migrates_legacy_hashes_on_login() {
+        let auth = sqlite("s4lt").await;
+        add_legacy(&auth, "ray", "secret password", "s4lt").await;
+
+        assert!(auth.by_username_password("ray", "wrong password").await.is_none());
+        assert_eq!(algorithm(&auth, "ray").await, LEGACY_ALGORITHM);
+
+        assert_eq!(auth.by_username_password("ray", "secret password").await.unwrap().permissions, 60);
+        assert_eq!(algorithm(&auth, "ray").await, ARGON2_ALGORITHM);
+
+        assert!(auth.by_username_password("ray", "secret password").await.is_some());
+        assert!(auth.by_username_password("ray", "wrong password").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn sqlite_checks_legacy_hashes_with_the_legacy_salt() {
+        let auth = sqlite("a new salt").await;
+        add_legacy(&auth, "ray", "secret password", LEGACY_AUTH_SALT).await;
+
+        assert!(auth.by_username_password("ray", "secret password").await.is_some());
+        assert_eq!(algorithm(&auth, "ray").await, ARGON2_ALGORITHM);
+    }
+
+    #[tokio::test]
+    async fn sqlite_devices_log_in_until_revoked() {
+        let mut auth = sqlite("s4lt").await;
+        auth.add_device("device-1", "customer", "ray").await.unwrap();
+        sqlx::query("INSERT INTO `devices` (`device`, `username`, `added_by`, `added_on`) VALUES (?, 'customer', 'ray', 0)")
+            .bind(auth.hash_password("old-device", LEGACY_AUTH_SALT))
+            .execute(&auth.connection)
+            .await
+            .unwrap();
+
+        assert_eq!(auth.by_device("device-1", "customer").await.unwrap().username, "customer");
+        assert!(auth.by_device("old-device", "customer").await.is_some());
+        assert!(auth.by_device("device-1", "someone else").await.is_none());
+        assert!(auth.by_device("device-2", "customer").await.is_none());
+
+        assert_eq!(auth.delete_devices("customer").await, Ok(2));
+        assert!(auth.by_device("device-1", "customer").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn sqlite_hashes_new_agents_with_argon2() {
+        let mut auth = sqlite("s4lt").await;
+        auth.add("ray", "secret password", ClientOptions::Admin).await.unwrap();
+
+        assert_eq!(algorithm(&auth, "ray").await, ARGON2_ALGORITHM);
+        assert!(auth.by_username_password("ray", "secret password").await.is_some());
+        assert!(auth.by_username_password("ray", "secret").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn invent_checks_passwords_with_the_directory() {
+        let (url, _) = stub_directory().await;
+        let auth = invent(&url);
+
+        let entry = auth.by_username_password("ray", "right").await.unwrap();
+        assert_eq!(entry.username, "ray");
+        assert_eq!(entry.permissions, 60);
+        assert!(auth.by_username_password("ray", "wrong").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn invent_maps_roles_and_skips_unknown_users() {
+        let (url, _) = stub_directory().await;
+        let auth = invent(&url);
+
+        assert_eq!(auth.by_username("ray").await.unwrap().permissions, 62);
+        assert!(auth.by_username("guest").await.is_none());
+        assert!(auth.by_username("nobody").await.is_none());
+
+        let all = auth.all().await.unwrap();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].username, "ray");
+    }
+
+    #[tokio::test]
+    async fn invent_escapes_usernames_in_the_path() {
+        let (url, seen) = stub_directory().await;
+        let auth = invent(&url);
+
+        assert!(auth.by_username("../admin?x=").await.is_none());
+        let line = seen.lock().unwrap().last().unwrap().clone();
+        assert!(line.starts_with("GET /users/..%2Fadmin%3Fx= "), "{}", line);
+    }
+
+    #[tokio::test]
+    async fn invent_caches_replies() {
+        let (url, seen) = stub_directory().await;
+        let auth = invent(&url);
+
+        auth.by_username("ray").await;
+        auth.by_username("ray").await;
+        auth.by_username("nobody").await;
+        auth.by_username("nobody").await;
+        assert_eq!(seen.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn invent_cache_is_bounded() {
+        let auth = invent("http://127.0.0.1:9");
+
+        for n in 0..INVENT_CACHE_MAX + 10 {
+            auth.cache_put(format!("user:{}", n), None);
+        }
+
+        let cache = auth.cache.lock().unwrap();
+        assert_eq!(cache.len(), INVENT_CACHE_MAX);
+        assert!(cache.contains_key(&format!("user:{}", INVENT_CACHE_MAX + 9)));
+    }
 }
@@ -5,12 +5,16 @@ use tokio::sync::RwLock;
 use serde::Deserialize;
 use serde::Serialize;
 
+use ipnet::IpNet;
+
 use crate::{IpAddr, Ipv4Addr};
 use crate::SystemTime;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Ban {
-    pub ip: IpAddr,
+    pub ip: IpAddr, // network address of the ban
+    #[serde(default)]
+    pub prefix: Option<u8>, // CIDR prefix length, None bans only `ip`
     pub reason: String,
     pub added_by: String,
     pub added_on: SystemTime,
@@ -20,6 +24,7 @@ impl Default for Ban {
     fn default() -> Ban {
         Ban {
             ip: IpAddr::V4(Ipv4Addr::new(0,0,0,0)),
+            prefix: None,
             reason: "No reason was provided".to_string(),
             added_by: "Server".to_string(),
             added_on: SystemTime::now(),
@@ -29,15 +34,73 @@ impl Default for Ban {
 }
 
 impl Ban {
-    pub fn new(ip: IpAddr, reason: String, expires: SystemTime, added_by: String) -> Self {
+    pub fn new(network: IpNet, reason: String, expires: SystemTime, added_by: String) -> Self {
+        let prefix = match network.prefix_len() == network.max_prefix_len() {
+            true    => None,
+            false   => Some(network.prefix_len())
+        };
+
         Ban {
-            ip, reason, expires, added_by, ..Ban::default()
+            ip: network.network(), prefix, reason, expires, added_by, ..Ban::default()
+        }
+    }
+
+    /// Parse a ban target, an IPv4 or IPv6 address with an optional /prefix.
+    ///
+    /// Host bits are cleared, so 10.0.0.7/24 bans 10.0.0.0/24. Ranges shorter than `min_v4` or
+    /// `min_v6` are refused.
+    pub fn parse_network(target: &str, min_v4: u8, min_v6: u8) -> Result<IpNet, String> {
+        let target = target.trim();
+
+        if target.contains('/') {
+            let network = match target.parse::<IpNet>() {
+                Ok(network) => network.trunc(),
+                Err(_)      => return Err(format!("'{}' is not a valid CIDR range.", target))
+            };
+
+            let min_prefix = match network {
+                IpNet::V4(_)    => min_v4,
+                IpNet::V6(_)    => min_v6
+            };
+
+            if network.prefix_len() < min_prefix {
+                return Err(format!("'{}' is too wide, the shortest prefix allowed is /{}.", target, min_prefix));
+            }
+            return Ok(network);
         }
+
+        match target.parse::<IpAddr>() {
+            Ok(ip)  => Ok(IpNet::from(ip.to_canonical())),
+            Err(_)  => Err(format!("'{}' is not a valid ip address.", target))
+        }
+    }
+
+    pub fn network(&self) -> IpNet {
+        let prefix = self.prefix.unwrap_or(match self.ip {
+            IpAddr::V4(_)   => 32,
+            IpAddr::V6(_)   => 128
+        });
+
+        IpNet::new(self.ip, prefix).unwrap_or(IpNet::from(self.ip))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires <= SystemTime::now()
+    }
+
+    /// True if the ban covers the address. IPv4-mapped IPv6 addresses match IPv4 bans.
+    pub fn matches(&self, ip: IpAddr) -> bool {
+        self.network().contains(&ip.to_canonical())
     }
 
+    /// True if any ban that has not expired covers the address.
     pub async fn exists(bans: &Arc<RwLock<Vec<Ban>>>, ip: IpAddr) -> bool {
+        bans.read().await.iter().any(|ban| !ban.is_expired() && ban.matches(ip))
+    }
 
-        bans.read().await.iter().any(|ban| ban.ip == ip)
+    /// True if this exact address or range is in the ban list and has not expired.
+    pub async fn listed(bans: &Arc<RwLock<Vec<Ban>>>, network: IpNet) -> bool {
+        bans.read().await.iter().any(|ban| !ban.is_expired() && ban.network() == network)
     }
 
     pub fn load_bans(file: &str) -> Result<Vec<Ban>, std::io::Error> {
@@ -60,12 +123,8 @@ impl Ban {
 
     }
 
+    /// Write the ban list to `file`. An empty list is written too, so removed bans stay removed.
     pub fn save_to_disk(file: &str, bans: &Vec<Ban>) -> Result<(), String> {
-        // if there are no bans we can bail early
-        if bans.len() == 0 {
-            return Ok(());
-        }
-
         let serialized = match serde_json::to_vec(&bans) {
             Ok(encoded) => encoded,
             Err(e)  => return Err(e.to_string())
@@ -77,3 +136,61 @@ impl Ban {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ban(target: &str, expires: SystemTime) -> Ban {
+        Ban::new(Ban::parse_network(target, 8, 32).unwrap(), "test".to_string(), expires, "test".to_string())
+    }
+
+    fn in_an_hour() -> SystemTime {
+        SystemTime::now() + Duration::from_secs(3600)
+    }
+
+    #[test]
+    fn parses_addresses_and_ranges() {
+        assert_eq!(Ban::parse_network("10.0.0.7", 8, 32).unwrap().to_string(), "10.0.0.7/32");
+        assert_eq!(Ban::parse_network(" 10.0.0.7/24 ", 8, 32).unwrap().to_string(), "10.0.0.0/24");
+        assert_eq!(Ban::parse_network("2001:db8::1/48", 8, 32).unwrap().to_string(), "2001:db8::/48");
+        assert_eq!(Ban::parse_network("::ffff:10.0.0.7", 8, 32).unwrap().to_string(), "10.0.0.7/32");
+    }
+
+    #[test]
+    fn refuses_invalid_and_too_wide_ranges() {
+        assert!(Ban::parse_network("10.0.0", 8, 32).is_err());
+        assert!(Ban::parse_network("10.0.0.0/33", 8, 32).is_err());
+        assert!(Ban::parse_network("0.0.0.0/0", 8, 32).is_err());
+        assert!(Ban::parse_network("10.0.0.0/7", 8, 32).is_err());
+        assert!(Ban::parse_network("2001:db8::/31", 8, 32).is_err());
+        assert!(Ban::parse_network("10.0.0.0/8", 8, 32).is_ok());
+        assert!(Ban::parse_network("0.0.0.0/0", 0, 0).is_ok());
+    }
+
+    #[test]
+    fn matches_addresses_in_the_range() {
+        let range = ban("192.168.1.0/24", in_an_hour());
+        assert!(range.matches("192.168.1.200".parse().unwrap()));
+        assert!(range.matches("::ffff:192.168.1.200".parse().unwrap()));
+        assert!(!range.matches("192.168.2.1".parse().unwrap()));
+
+        let host = ban("2001:db8::1", in_an_hour());
+        assert_eq!(host.prefix, None);
+        assert!(host.matches("2001:db8::1".parse().unwrap()));
+        assert!(!host.matches("2001:db8::2".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn expired_bans_no_longer_match() {
+        let expired = ban("10.1.0.0/16", SystemTime::now() - Duration::from_secs(1));
+        assert!(expired.is_expired());
+        assert!(!ban("10.2.0.0/16", in_an_hour()).is_expired());
+
+        let bans = Arc::new(RwLock::new(vec![expired, ban("10.2.0.0/16", in_an_hour())]));
+        assert!(!Ban::exists(&bans, "10.1.2.3".parse().unwrap()).await);
+        assert!(Ban::exists(&bans, "10.2.3.4".parse().unwrap()).await);
+        assert!(!Ban::listed(&bans, "10.1.0.0/16".parse().unwrap()).await);
+        assert!(Ban::listed(&bans, "10.2.0.0/16".parse().unwrap()).await);
+    }
+}
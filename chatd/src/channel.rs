@@ -9,6 +9,8 @@ use crate::Mutex;
 use crate::Arc;
 use crate::{Write, OpenOptions};
 use std::collections::HashMap;
+use std::time::SystemTime;
+use tracing::error;
 
 
 pub struct Channel {
@@ -17,36 +19,45 @@ pub struct Channel {
     owner: Option<Uuid>,
     clientid: Option<u64>,
     members: HashMap<Uuid, Arc<Mutex<Client>>>,
-    zombies: HashMap<String, Client>, // zombies are stored by username
+    zombies: HashMap<Uuid, Client>, // zombies are stored by client id, names are not unique
+    invites: HashMap<Uuid, SystemTime>, // invited client and when the invite expires
+    queue: Vec<CMessage>, // messages sent while waiting for an agent, oldest first
+    waiting_since: Option<SystemTime>, // when the first queued message was sent
+    last_spoke: HashMap<Uuid, SystemTime>, // member and their last message, for slow mode
+    member_limit: u64, // most members allowed, 0 is unlimited
+    raised_limit: Option<u64>, // limit kept by the Limit mode, raised every limit_interval
     private: bool, // usually agent to agent
     topic: Option<String>,
     options: ChannelOptions
 }
 
-#[repr(u64)]
-#[derive(Clone, Copy)]
-pub enum ChannelOptions {
-    None            = 0 << 0,
-    ClientInvites   = 1 << 0, // allow client to invite others 
-    AgentOnly       = 1 << 1, // only agents can join the channel
-    InviteOnly      = 1 << 2, // only creator or admins+ can join
-    SaveHistory     = 1 << 3, // save channel history when channel members = 0
-    Persist         = 1 << 4, // persist channel even if members = 0
-    WaitForAgent    = 1 << 5, // client can send messages but must wait for agent otherwise
-    RejoinClients   = 1 << 6, // disconnected clients are automatically rejoined on connect
-    CanNotLeave     = 1 << 7, // members may not leave the channel
-    HiddenMemberList= 1 << 8, // member list hidden from non agents
-    HiddenMessages  = 1 << 9, // messages are hidden from non agents
-    Invisible       = 1 << 10, //channel will not show up as active to non agents
-    Secret          = 1 << 11, // channel will not show up in channel listings to non agents
-
-    // AntiFlood    = 1 << 11, // flooding will get the user banned
-    // Throttle     = 1 << 12, // slow down user messages to once every 30 seconds
-    // Limit        = 1 << 13, // increases channel user limits by 5 every 30 seconds
+/// Channel modes as a bit set, combine them with `or`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ChannelOptions(u64);
+
+#[allow(non_upper_case_globals)]
+impl ChannelOptions {
+    pub const None:            ChannelOptions = ChannelOptions(0);
+    pub const ClientInvites:   ChannelOptions = ChannelOptions(1 << 0); // allow client to invite others 
+    pub const AgentOnly:       ChannelOptions = ChannelOptions(1 << 1); // only agents can join the channel
+    pub const InviteOnly:      ChannelOptions = ChannelOptions(1 << 2); // only creator or admins+ can join
+    pub const SaveHistory:     ChannelOptions = ChannelOptions(1 << 3); // save channel history when channel members = 0
+    pub const Persist:         ChannelOptions = ChannelOptions(1 << 4); // persist channel even if members = 0
+    pub const WaitForAgent:    ChannelOptions = ChannelOptions(1 << 5); // client can send messages but must wait for agent otherwise
+    pub const RejoinClients:   ChannelOptions = ChannelOptions(1 << 6); // disconnected clients are automatically rejoined on connect
+    pub const CanNotLeave:     ChannelOptions = ChannelOptions(1 << 7); // members may not leave the channel
+    pub const HiddenMemberList: ChannelOptions = ChannelOptions(1 << 8); // member list hidden from non agents
+    pub const HiddenMessages:  ChannelOptions = ChannelOptions(1 << 9); // messages are hidden from non agents
+    pub const Invisible:       ChannelOptions = ChannelOptions(1 << 10); //channel will not show up as active to non agents
+    pub const Secret:          ChannelOptions = ChannelOptions(1 << 11); // channel will not show up in channel listings to non agents
+    pub const Throttle:        ChannelOptions = ChannelOptions(1 << 12); // non agents may send one message every slow_mode_interval seconds
+    pub const AntiFlood:       ChannelOptions = ChannelOptions(1 << 13); // sustained flooding will get the user banned
+    pub const Limit:           ChannelOptions = ChannelOptions(1 << 14); // member limit rises by limit_step every limit_interval seconds
 }
+
 impl ChannelOptions {
     pub fn to_string(&self) -> String {
-        match self {
+        match *self {
             Self::None              => "",
             Self::ClientInvites     => "Allow Invites",
             Self::AgentOnly         => "Agent Only",
@@ -59,8 +70,11 @@ impl ChannelOptions {
             Self::HiddenMemberList  => "Hidden Members",
             Self::HiddenMessages    => "Hidden Messages",
             Self::Invisible         => "Invisible",
-            Self::Secret            => "Not Listed"
-            // _                       => ""
+            Self::Secret            => "Not Listed",
+            Self::Throttle          => "Slow Mode",
+            Self::AntiFlood         => "Anti Flood",
+            Self::Limit             => "Rising Limit",
+            _                       => ""
         }.to_string()
     }
 
@@ -84,24 +98,27 @@ impl ChannelOptions {
             Self::HiddenMemberList,
             Self::HiddenMessages,
             Self::Invisible,
-            Self::Secret
+            Self::Secret,
+            Self::Throttle,
+            Self::AntiFlood,
+            Self::Limit
         ]
     }
 
     pub fn or(self, rhs: ChannelOptions) -> ChannelOptions {
-        unsafe { std::mem::transmute::<u64, ChannelOptions>((self as u64) | (rhs as u64)) }
+        ChannelOptions(self.0 | rhs.0)
     }
 
     pub fn and(self, rhs: ChannelOptions) -> ChannelOptions {
-        unsafe { std::mem::transmute::<u64, ChannelOptions>((self as u64) & (rhs as u64)) }
+        ChannelOptions(self.0 & rhs.0)
     }
 
     pub fn xor(self, rhs: ChannelOptions) -> ChannelOptions {
-        unsafe { std::mem::transmute::<u64, ChannelOptions>((self as u64) ^ (rhs as u64)) }
+        ChannelOptions(self.0 ^ rhs.0)
     }
 
     pub fn bits(self) -> u64 {
-        self as u64
+        self.0
     }
     
 }
@@ -127,16 +144,32 @@ impl Channel {
             members: HashMap::new(),
             // members: Arc::new(Mutex::new(HashMap::new())),
             zombies: HashMap::new(),
+            invites: HashMap::new(),
+            queue: Vec::new(),
+            waiting_since: None,
+            last_spoke: HashMap::new(),
+            member_limit: 0,
+            raised_limit: None,
             topic: None,
             options
         }
         
     }
 
+    /// Rebuild a persisted channel with its original id. It starts without members.
+    pub fn restore(id: Uuid, name: String, owner: Option<Uuid>, topic: Option<String>, options: u64, member_limit: u64) -> Self {
+        let mut channel = Channel::new(name, owner, false);
+        channel.id = id;
+        channel.topic = topic;
+        channel.member_limit = member_limit;
+        channel.options = channel.get_options_from_u64(options);
+        channel
+    }
+
     pub fn get_options_from_u64(&mut self, modes: u64) -> ChannelOptions {
        let mut options: ChannelOptions = ChannelOptions::None;
         for option in ChannelOptions::available().iter() {
-            if modes & *option as u64 == *option as u64 {
+            if modes & option.bits() == option.bits() {
                 options = options.or(*option);
             }
         }
@@ -152,7 +185,7 @@ impl Channel {
     }
 
     pub fn has_option(&self, chanopt: ChannelOptions) -> bool {
-        self.options.and(chanopt) as u64 == chanopt as u64
+        self.options.and(chanopt) == chanopt
     }
 
     pub fn options(&self) -> ChannelOptions {
@@ -166,17 +199,134 @@ impl Channel {
     pub fn owner_id(&self) -> Option<Uuid> {
         self.owner
     }
+
+    /// Checks if a client holds an invite to this channel that has not expired.
     pub fn has_invite(&self, user: Uuid) -> bool {
+        match self.invites.get(&user) {
+            Some(expires)   => *expires > SystemTime::now(),
+            None            => false
+        }
+    }
+
+    /// Invite a client to the channel until the expiry time, replacing any older invite.
+    pub fn add_invite(&mut self, user: Uuid, expires: SystemTime) {
+        let now = SystemTime::now();
+        self.invites.retain(|_, e| *e > now);
+        self.invites.insert(user, expires);
+    }
+
+    pub fn remove_invite(&mut self, user: Uuid) {
+        self.invites.remove(&user);
+    }
+
+    /// Checks if any agent is a member of the channel.
+    pub async fn has_agent(&self) -> bool {
+        for member in self.members.values() {
+            if member.lock().await.gettype() as u8 == ClientType::Agent as u8 {
+                return true;
+            }
+        }
         false
     }
 
+    /// Queue a message for the first agent to join, keeping at most `max` messages.
+    ///
+    /// Returns true if this is the first message of a new wait.
+    pub fn queue_message(&mut self, message: CMessage, max: usize) -> bool {
+        let first = self.waiting_since.is_none();
+
+        if first {
+            self.waiting_since = Some(SystemTime::now());
+        }
+
+        self.queue.push(message);
+
+        if self.queue.len() > max {
+            self.queue.drain(..self.queue.len() - max);
+        }
+
+        first
+    }
+
+    /// Start waiting for an agent before anything was said, keeps an earlier start.
+    pub fn start_waiting(&mut self) {
+        if self.waiting_since.is_none() {
+            self.waiting_since = Some(SystemTime::now());
+        }
+    }
+
+    /// Take every queued message and stop waiting.
+    pub fn take_queue(&mut self) -> Vec<CMessage> {
+        self.waiting_since = None;
+        std::mem::take(&mut self.queue)
+    }
+
+    pub fn queue_len(&self) -> u64 {
+        self.queue.len() as u64
+    }
+
+    pub fn waiting_since(&self) -> Option<SystemTime> {
+        self.waiting_since
+    }
+
+    pub fn member_limit(&self) -> u64 {
+        self.member_limit
+    }
+
+    pub fn set_member_limit(&mut self, limit: u64) {
+        self.member_limit = limit;
+    }
+
+    /// Let `step` more members join than are in the channel now, used by the Limit mode.
+    pub async fn raise_limit(&mut self, step: u64) {
+        self.raised_limit = Some(self.count_members().await + step);
+    }
+
+    pub fn clear_raised_limit(&mut self) {
+        self.raised_limit = None;
+    }
+
+    /// True when no more members may join, by the member limit or the Limit mode.
+    pub async fn is_full(&self) -> bool {
+        let members = self.count_members().await;
+
+        if self.member_limit > 0 && members >= self.member_limit {
+            return true;
+        }
+
+        match self.raised_limit {
+            Some(limit) if self.has_option(ChannelOptions::Limit) => members >= limit,
+            _                                                     => false
+        }
+    }
+
+    /// Seconds a member has left to wait in slow mode, 0 when they may speak.
+    ///
+    /// A member allowed to speak is marked as having just spoken, non members are never recorded.
+    pub fn slow_mode_wait(&mut self, id: Uuid, interval: u64) -> u64 {
+        if !self.members.contains_key(&id) {
+            return 0;
+        }
+
+        let elapsed = self.last_spoke.get(&id)
+            .map(|last| last.elapsed().unwrap_or_default().as_secs())
+            .unwrap_or(interval);
+
+        if elapsed < interval {
+            return interval - elapsed;
+        }
+
+        self.last_spoke.insert(id, SystemTime::now());
+        0
+    }
+
     /// Add a member to the current channel
     ///
     /// Function will broadcast the join to others members as required, as well as
     /// sending the current channel modes, but not the userlist.
     pub async fn add_member(&mut self, mut client: Client) {
         // we never broadcast invisible people. use this power wisely.
-        if client.options().and(ClientOptions::Invisible) as u64 == 0 {
+        if !client.has_options(ClientOptions::Invisible) {
             self.broadcast_join(client.id(), client.name(), client.ip()).await;
             
             let _ = client.sender().lock().await.send(CMessage::new(MessageType::ChannelModes(Some(self.options_vec_string())), Uuid::nil(), self.id(), None).into()).await;
@@ -191,7 +341,7 @@ impl Channel {
     async fn broadcast_join(&mut self, client: Uuid, name: String, ip: IpAddr) {
 
         let agent_only = ChannelOptions::HiddenMemberList.or(ChannelOptions::Invisible);
-        if self.options.and(agent_only) as u64 != 0 {
+        if self.options.and(agent_only) != ChannelOptions::None {
             let cmessage = CMessage::new(MessageType::Join(name, ip), client, self.id(), None);
             let _ = self.sendto_agents(&cmessage).await;
         } else {
@@ -206,7 +356,7 @@ impl Channel {
     pub async fn broadcast_part(&mut self, client: Uuid, name: String, ip: IpAddr) {
         let agent_only = ChannelOptions::HiddenMemberList.or(ChannelOptions::Invisible);
 
-        if self.options.and(agent_only) as u64 != 0 {
+        if self.options.and(agent_only) != ChannelOptions::None {
             let cmessage = CMessage::new(MessageType::Part(client, self.id, ip), client, self.id(), Some(name));
             let _ = self.sendto_agents(&cmessage);
         } else {
@@ -222,20 +372,62 @@ impl Channel {
     /// This function does not broadcast to others
     pub async fn remove_member(&mut self, id: Uuid) {
         self.members.remove(&id);
+        self.last_spoke.remove(&id);
+    }
+
+    /// Move a member into the zombie list while its connection is gone.
+    ///
+    /// Zombies keep their place in the channel but are not sent any messages.
+    pub async fn zombify_member(&mut self, id: Uuid) {
+        if let Some(member) = self.members.remove(&id) {
+            let zombie = member.lock().await.clone();
+            self.zombies.insert(zombie.id(), zombie);
+        }
+    }
+
+    /// Move a zombie back into the member list using its new connection.
+    ///
+    /// Returns false if the client was not a zombie of this channel. Nothing is broadcast.
+    pub fn resume_member(&mut self, client: Client) -> bool {
+        if self.zombies.remove(&client.id()).is_none() {
+            return false;
+        }
+
+        self.members.insert(client.id(), Arc::new(Mutex::new(client)));
+        true
+    }
+
+    /// Remove a zombie from the channel. This function does not broadcast to others
+    pub fn remove_zombie(&mut self, id: Uuid) -> bool {
+        self.last_spoke.remove(&id);
+        self.zombies.remove(&id).is_some()
+    }
+
+    /// Members as sent in a UserList, presence is only filled in for agents.
+    pub async fn member_list(&self) -> Vec<(Uuid, String, Option<Presence>)> {
+        let mut members_list: Vec<(Uuid, String, Option<Presence>)> = Vec::new();
+
+        for (id, cl) in self.members.iter() {
+            let member = cl.lock().await;
+            let presence = if member.gettype() as u8 == ClientType::Agent as u8 { Some(member.presence().await) } else { None };
+            members_list.push((id.to_owned(), member.name(), presence));
+        }
+        members_list
     }
 
     pub async fn is_member(&self, id: Uuid) -> bool {
         self.members.get(&id).is_some() 
     }
 
-    pub async fn get_members(&self) -> HashMap<Uuid, Arc<Mutex<Client>>> {
-        self.members.clone()
-    }
-    
+    /// Counts members, including zombies that may still resume.
     pub async fn count_members(&self) -> u64 {
-        self.members.len() as u64
+        (self.members.len() + self.zombies.len()) as u64
     }
 
+    /// Append a line to the channel log when SaveHistory is set.
+    ///
+    /// The log records channel events such as joins, parts and mode changes. Messages are kept
+    /// in the channel store history, so replay and search see them.
     pub async fn to_log(&self, message: String) {
         if self.has_option(ChannelOptions::SaveHistory) {
             let logfile = OpenOptions::new()
@@ -325,7 +517,7 @@ impl MessageSendable for Channel {
     async fn sendto_one(&mut self, to: Uuid, message: &CMessage) -> Result<u64, String> {
         let json = match serde_json::to_string(message) {
             Ok(text)    => text,
-            Err(e)      => { println!("error: sendto_one() failed on serialization: {}", e); return Err(e.to_string()); }
+            Err(e)      => { error!(channel = %self.id, error = %e, "unable to serialize message"); return Err(e.to_string()); }
         };
 
         for member in self.members.values() { 
@@ -334,7 +526,7 @@ impl MessageSendable for Channel {
             if mut_mem.id() == to {
                 match mut_mem.sender().lock().await.send(Message::text(json.clone())).await {
                     Ok(_)   => { },
-                    Err(_)  => { }
+                    Err(_)  => { crate::metrics::count_send_error(); }
                 }
             }
         }
@@ -347,7 +539,7 @@ impl MessageSendable for Channel {
     async fn sendto_all_butone(&mut self, not: Uuid, message: &CMessage) -> Result<u64, String> {
         let json = match serde_json::to_string(message) {
             Ok(text)    => text,
-            Err(e)      => { println!("error: sendto_one() failed on serialization: {}", e); return Err(e.to_string()); }
+            Err(e)      => { error!(channel = %self.id, error = %e, "unable to serialize message"); return Err(e.to_string()); }
         };
 
         for member in self.members.values() { 
@@ -356,7 +548,7 @@ impl MessageSendable for Channel {
             if mut_mem.id() != not {
                 match mut_mem.sender().lock().await.send(Message::text(json.clone())).await {
                     Ok(_)   => {  },
-                    Err(_)  => {  }
+                    Err(_)  => { crate::metrics::count_send_error(); }
                 }
             }
         }
@@ -370,14 +562,14 @@ impl MessageSendable for Channel {
     async fn sendto_nonagents(&mut self, message: &CMessage) -> Result<u64, String> {
      let json = match serde_json::to_string(message) {
             Ok(text)    => text,
-            Err(e)      => { println!("error: sendto_one() failed on serialization: {}", e); return Err(e.to_string()); }
+            Err(e)      => { error!(channel = %self.id, error = %e, "unable to serialize message"); return Err(e.to_string()); }
         };
 
         for member in self.members.clone().values() { 
             let mut mut_mem = member.lock().await;
 
-            if mut_mem.gettype() as u64 != ClientType::Agent as u64 {
-                let _ = mut_mem.sender().lock().await.send(Message::text(json.clone())).await;
+            if mut_mem.gettype() as u64 != ClientType::Agent as u64 && mut_mem.sender().lock().await.send(Message::text(json.clone())).await.is_err() {
+                crate::metrics::count_send_error();
             }
         }
 
@@ -391,14 +583,14 @@ impl MessageSendable for Channel {
     async fn sendto_agents(&mut self, message: &CMessage) -> Result<u64, String> {
      let json = match serde_json::to_string(message) {
             Ok(text)    => text,
-            Err(e)      => { println!("error: sendto_one() failed on serialization: {}", e); return Err(e.to_string()); }
+            Err(e)      => { error!(channel = %self.id, error = %e, "unable to serialize message"); return Err(e.to_string()); }
         };
 
         for member in self.members.values() { 
             let mut mut_mem = member.lock().await;
 
-            if mut_mem.gettype() as u64 == ClientType::Agent as u64 {
-               let _ = mut_mem.sender().lock().await.send(Message::text(json.clone())).await;
+            if mut_mem.gettype() as u64 == ClientType::Agent as u64 && mut_mem.sender().lock().await.send(Message::text(json.clone())).await.is_err() {
+                crate::metrics::count_send_error();
             }
         }
 
@@ -412,13 +604,15 @@ impl MessageSendable for Channel {
     async fn sendto_all(&mut self, message: &CMessage) -> Result<u64, String> {
         let json = match serde_json::to_string(message) {
             Ok(text)    => text,
-            Err(e)      => { println!("error: sendto_one() failed on serialization: {}", e); return Err(e.to_string()); }
+            Err(e)      => { error!(channel = %self.id, error = %e, "unable to serialize message"); return Err(e.to_string()); }
         };
 
         for member in self.members.values() { 
             let mut mut_mem = member.lock().await;
             let mut sender_lock = mut_mem.sender().lock().await;
-            let _ = sender_lock.send(Message::text(json.clone())).await;
+            if sender_lock.send(Message::text(json.clone())).await.is_err() {
+                crate::metrics::count_send_error();
+            }
         } 
 
         Ok(1)
@@ -430,3 +624,122 @@ impl MessageSendable for Channel {
     //     Ok(count)
     // }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+    use crate::client::tests::connected_client;
+
+    #[tokio::test]
+    async fn slow_mode_waits_between_member_messages() {
+        let (server_tx, _server_rx) = mpsc::unbounded_channel();
+        let (member, _far_end) = connected_client(server_tx).await;
+        let member_id = member.id();
+        let mut channel = Channel::new("Support".to_string(), None, false);
+        channel.add_member(member).await;
+
+        assert_eq!(channel.slow_mode_wait(member_id, 30), 0);
+        assert!(channel.slow_mode_wait(member_id, 30) > 0);
+        assert_eq!(channel.slow_mode_wait(member_id, 0), 0);
+    }
+
+    #[tokio::test]
+    async fn slow_mode_forgets_members_that_leave() {
+        let (server_tx, _server_rx) = mpsc::unbounded_channel();
+        let (member, _far_end) = connected_client(server_tx).await;
+        let member_id = member.id();
+        let mut channel = Channel::new("Support".to_string(), None, false);
+        channel.add_member(member).await;
+        channel.slow_mode_wait(member_id, 30);
+
+        channel.remove_member(member_id).await;
+        assert!(channel.last_spoke.is_empty());
+    }
+
+    #[tokio::test]
+    async fn zombies_with_the_same_name_resume_separately() {
+        let (server_tx, _server_rx) = mpsc::unbounded_channel();
+        let mut channel = Channel::new("Support".to_string(), None, false);
+        let mut sessions = Vec::new();
+
+        for _ in 0..2 {
+            let (mut member, far_end) = connected_client(server_tx.clone()).await;
+            member.set_name(&"Guest-1".to_string()).await;
+            sessions.push((member.clone(), far_end));
+            channel.add_member(member).await;
+        }
+
+        let (first, second) = (sessions[0].0.clone(), sessions[1].0.clone());
+        channel.zombify_member(first.id()).await;
+        channel.zombify_member(second.id()).await;
+        assert_eq!(channel.zombies.len(), 2);
+
+        assert!(channel.resume_member(second.clone()));
+        assert!(channel.is_member(second.id()).await);
+        assert!(!channel.is_member(first.id()).await);
+        assert!(channel.remove_zombie(first.id()));
+        assert!(!channel.resume_member(first));
+    }
+
+    #[test]
+    fn slow_mode_ignores_non_members() {
+        let mut channel = Channel::new("Support".to_string(), None, false);
+
+        for _ in 0..3 {
+            assert_eq!(channel.slow_mode_wait(Uuid::new_v4(), 30), 0);
+        }
+        assert!(channel.last_spoke.is_empty());
+    }
+
+    #[test]
+    fn waiting_queue_keeps_the_newest_messages() {
+        let mut channel = Channel::new("Support".to_string(), None, false);
+        let said: Vec<CMessage> = (0..5).map(|n| CMessage::new(MessageType::Message(format!("message {}", n)), Uuid::new_v4(), channel.id(), None)).collect();
+
+        assert!(channel.queue_message(said[0].clone(), 3));
+        let since = channel.waiting_since();
+        assert!(since.is_some());
+
+        for message in said[1..].iter() {
+            assert!(!channel.queue_message(message.clone(), 3));
+        }
+        assert_eq!(channel.queue_len(), 3);
+        assert_eq!(channel.waiting_since(), since);
+
+        let backlog: Vec<u64> = channel.take_queue().iter().map(|m| m.id).collect();
+        assert_eq!(backlog, said[2..].iter().map(|m| m.id).collect::<Vec<u64>>());
+        assert_eq!((channel.queue_len(), channel.waiting_since()), (0, None));
+    }
+
+    #[tokio::test]
+    async fn member_limits_and_the_rising_limit() {
+        let (server_tx, _server_rx) = mpsc::unbounded_channel();
+        let mut far_ends = Vec::new();
+        let mut channel = Channel::new("Support".to_string(), None, false);
+        channel.set_member_limit(2);
+
+        for full in [false, true] {
+            let (member, far_end) = connected_client(server_tx.clone()).await;
+            far_ends.push(far_end);
+            channel.add_member(member).await;
+            assert_eq!(channel.is_full().await, full);
+        }
+
+        channel.set_member_limit(0);
+        channel.add_option(ChannelOptions::Limit);
+        channel.raise_limit(1).await;
+        assert!(!channel.is_full().await);
+
+        let (member, _far_end) = connected_client(server_tx).await;
+        channel.add_member(member).await;
+        assert!(channel.is_full().await);
+
+        // the raised limit only counts while the mode is set
+        channel.set_options(channel.options().xor(ChannelOptions::Limit));
+        assert!(!channel.is_full().await);
+        channel.add_option(ChannelOptions::Limit);
+        channel.clear_raised_limit();
+        assert!(!channel.is_full().await);
+    }
+}
@@ -6,19 +6,20 @@ use crate::{config, channel::*, message::Message as CMessage};
 use crate::Uuid;
 use futures_util::stream::SplitSink;
 use rand::Rng;
-use tokio::net::TcpStream;
+use crate::listener::Connection;
 use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::Mutex;
 // use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::tungstenite::protocol::Message as Message;
 use tokio_tungstenite::WebSocketStream;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 
 
 #[derive(Clone)]
 pub struct Client {
     id: Uuid,                   // internal id
-    pub sender: Arc<Mutex<SplitSink<WebSocketStream<tokio_rustls::server::TlsStream<TcpStream>>, Message>>>, // physical sender
+    pub sender: Arc<Mutex<SplitSink<WebSocketStream<Connection>, Message>>>, // physical sender
     pub main_sender: UnboundedSender<CMessage>,
     name: String,               // display name of the channel
     r#type: ClientType,         // agent / guest / contact
@@ -28,20 +29,42 @@ pub struct Client {
     last_ping: SystemTime,
     last_action: SystemTime,
     status: ClientStatus,
+    account: Option<String>,    // username the client authenticated with, if any
+    resume_token: String,       // secret the client can use to resume the session after a reload
+    zombie_since: Option<SystemTime>, // when the connection was lost, if it is a zombie
+    last_active: SystemTime,    // last message sent by the client itself, pongs excluded
+    presence: Arc<Mutex<Presence>>, // shared with the channel member copies
+    auto_away: bool,            // presence was set to Away by the idle check
+    flood_tokens: f64,          // messages the client may still send before being limited
+    flood_refilled: SystemTime, // last time flood_tokens were topped up
+    authenticating: bool,       // a login is being checked off the command loop
+    flood_strikes: u64,         // messages dropped since the bucket was last full
     channels: Arc<Mutex<HashMap<Uuid, String>>> // uuid and channel name
 }
 
-#[repr(u64)]
-#[derive(Clone, Copy)]
-pub enum ClientOptions {
-    None            = 0 << 0,
-    Admin           = 1 << 1,
-    JoinChannels    = 1 << 2, // client can join channels
-    PartChannels    = 1 << 3, // client can leave channels
-    CreateChannels  = 1 << 4,
-    CanInvite       = 1 << 5, // applies only to invitable channels.
-    FilesAllowed    = 1 << 6, // can upload files in general.
-    Invisible       = 1 << 7, // can join/participate in chats but wont show in users list.
+/// Client permissions as a bit set.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ClientOptions(u64);
+
+#[allow(non_upper_case_globals)]
+impl ClientOptions {
+    pub const None:           ClientOptions = ClientOptions(0);
+    pub const Admin:          ClientOptions = ClientOptions(1 << 1);
+    pub const JoinChannels:   ClientOptions = ClientOptions(1 << 2); // client can join channels
+    pub const PartChannels:   ClientOptions = ClientOptions(1 << 3); // client can leave channels
+    pub const CreateChannels: ClientOptions = ClientOptions(1 << 4);
+    pub const CanInvite:      ClientOptions = ClientOptions(1 << 5); // applies only to invitable channels.
+    pub const FilesAllowed:   ClientOptions = ClientOptions(1 << 6); // can upload files in general.
+    pub const Invisible:      ClientOptions = ClientOptions(1 << 7); // can join/participate in chats but wont show in users list.
+}
+
+/// Whether an agent is taking chats. Only agents change it, everyone else stays Available.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Presence {
+    Available,
+    Away,
+    Busy,
+    Offline, // connected but appearing offline
 }
 
 #[derive(Clone)]
@@ -54,7 +77,7 @@ pub enum ClientStatus {
 }
 
 #[repr(u8)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Serialize, Deserialize)]
 pub enum ClientType {
     None,
     Agent,
@@ -63,30 +86,32 @@ pub enum ClientType {
 }
 
 impl ClientOptions {
-    pub fn or(self, rhs: ClientOptions) -> ClientOptions {
-        unsafe { std::mem::transmute::<u64, ClientOptions>((self as u64) | (rhs as u64)) }
-    }
-
-    pub fn and(self, rhs: ClientOptions) -> ClientOptions {
-        unsafe { std::mem::transmute::<u64, ClientOptions>((self as u64) & (rhs as u64)) }
-    }
-
-    pub fn xor(self, rhs: ClientOptions) -> ClientOptions {
-        unsafe { std::mem::transmute::<u64, ClientOptions>((self as u64) ^ (rhs as u64)) }
+    pub fn bits(self) -> u64 {
+        self.0
     }
 
-    pub fn bits(self) -> u64 {
-        self as u64
+    /// Every option bit that is defined, for masking untrusted input.
+    pub fn all_bits() -> u64 {
+        [
+            ClientOptions::Admin,
+            ClientOptions::JoinChannels,
+            ClientOptions::PartChannels,
+            ClientOptions::CreateChannels,
+            ClientOptions::CanInvite,
+            ClientOptions::FilesAllowed,
+            ClientOptions::Invisible,
+        ].iter().fold(0u64, |bits, opt| bits | opt.bits())
     }
 
-    pub fn from_u64(bits: u64) -> ClientOptions {
-        unsafe { std::mem::transmute::<u64, ClientOptions>(bits)}
+    /// Options from stored or received bits, bits that are not defined are dropped.
+    pub fn from_bits(bits: u64) -> ClientOptions {
+        ClientOptions(bits & ClientOptions::all_bits())
     }
 }
 
 impl Client {
     pub fn has_options(&self, opt: ClientOptions) -> bool {
-        self.options.and(opt) as u64 == opt as u64
+        self.options.0 & opt.0 == opt.0
     }
     pub fn generate_id() -> Uuid {
         Uuid::new_v4()
@@ -100,7 +125,7 @@ impl Client {
         }
     }
 
-    pub fn new(sender: SplitSink<WebSocketStream<tokio_rustls::server::TlsStream<TcpStream>>, Message>, main_sender: UnboundedSender<CMessage>, ip: IpAddr, guestid: Option<u64>) -> Self {
+    pub fn new(sender: SplitSink<WebSocketStream<Connection>, Message>, main_sender: UnboundedSender<CMessage>, ip: IpAddr, guestid: Option<u64>) -> Self {
         Client {
             id: Client::generate_id(),
             sender: Arc::new(Mutex::new(sender)),
@@ -113,6 +138,16 @@ impl Client {
             last_ping: SystemTime::now(),
             last_action: SystemTime::now(),
             status: ClientStatus::PendingAuth,
+            account: None,
+            resume_token: Uuid::new_v4().to_string(),
+            zombie_since: None,
+            last_active: SystemTime::now(),
+            presence: Arc::new(Mutex::new(Presence::Available)),
+            auto_away: false,
+            flood_tokens: f64::MAX,
+            flood_refilled: SystemTime::now(),
+            authenticating: false,
+            flood_strikes: 0,
             channels: Arc::new(Mutex::new(HashMap::new()))
         }
     }
@@ -185,7 +220,7 @@ impl Client {
     }
 
     pub fn set_options_u64(&mut self, options: u64) {
-        self.options = ClientOptions::from_u64(options);
+        self.options = ClientOptions::from_bits(options);
     }
 
     pub fn gettype(&self) -> ClientType {
@@ -204,6 +239,108 @@ impl Client {
         self.status.clone()
     }
 
+    pub fn account(&self) -> Option<String> {
+        self.account.clone()
+    }
+
+    pub fn set_account(&mut self, account: Option<String>) {
+        self.account = account;
+    }
+
+    pub fn resume_token(&self) -> String {
+        self.resume_token.clone()
+    }
+
+    /// Replace the resume token, invalidating the old one.
+    pub fn new_resume_token(&mut self) -> String {
+        self.resume_token = Uuid::new_v4().to_string();
+        self.resume_token.clone()
+    }
+
+    pub fn zombie_since(&self) -> Option<SystemTime> {
+        self.zombie_since
+    }
+
+    pub fn set_zombie_since(&mut self, since: Option<SystemTime>) {
+        self.zombie_since = since;
+    }
+
+    /// Take over the identity of a zombie client, keeping this clients connection.
+    ///
+    /// The id, name, type, options, account and channel list are copied, and a new
+    /// resume token is issued. The caller must re-key the server client list.
+    pub fn resume_from(&mut self, zombie: &Client) {
+        self.id = zombie.id;
+        self.name = zombie.name.clone();
+        self.r#type = zombie.r#type;
+        self.options = zombie.options;
+        self.account = zombie.account.clone();
+        self.connected = zombie.connected;
+        self.channels = zombie.channels.clone();
+        self.presence = zombie.presence.clone();
+        self.flood_tokens = zombie.flood_tokens;
+        self.flood_refilled = zombie.flood_refilled;
+        self.flood_strikes = zombie.flood_strikes;
+        self.zombie_since = None;
+        self.new_resume_token();
+    }
+
+    pub fn authenticating(&self) -> bool {
+        self.authenticating
+    }
+
+    pub fn set_authenticating(&mut self, authenticating: bool) {
+        self.authenticating = authenticating;
+    }
+
+    pub fn last_active(&self) -> SystemTime {
+        self.last_active
+    }
+
+    pub fn update_last_active_time(&mut self) {
+        self.last_active = SystemTime::now();
+    }
+
+    pub async fn presence(&self) -> Presence {
+        *self.presence.lock().await
+    }
+
+    /// Set the presence, `auto` marks an Away set by the idle check.
+    pub async fn set_presence(&mut self, presence: Presence, auto: bool) {
+        *self.presence.lock().await = presence;
+        self.auto_away = auto;
+    }
+
+    pub fn is_auto_away(&self) -> bool {
+        self.auto_away
+    }
+
+    /// Take a message from the client's flood bucket, false when it is empty.
+    ///
+    /// The bucket holds `burst` messages and refills at `rate` messages a second. Strikes
+    /// count the messages refused since the bucket was last full.
+    pub fn take_flood_token(&mut self, rate: f64, burst: u64) -> bool {
+        let elapsed = self.flood_refilled.elapsed().unwrap_or_default().as_secs_f64();
+        self.flood_tokens = (self.flood_tokens + elapsed * rate).min(burst as f64);
+        self.flood_refilled = SystemTime::now();
+
+        if self.flood_tokens >= burst as f64 {
+            self.flood_strikes = 0;
+        }
+
+        if self.flood_tokens < 1.0 {
+            self.flood_strikes += 1;
+            return false;
+        }
+
+        self.flood_tokens -= 1.0;
+        true
+    }
+
+    pub fn flood_strikes(&self) -> u64 {
+        self.flood_strikes
+    }
+
     pub fn name(&self) -> String {
         self.name.to_string()
     }
@@ -219,7 +356,70 @@ impl Client {
         true
     }
 
-    pub fn sender(&mut self) -> &mut Arc<Mutex<SplitSink<WebSocketStream<tokio_rustls::server::TlsStream<TcpStream>>, Message>>> {
+    pub fn sender(&mut self) -> &mut Arc<Mutex<SplitSink<WebSocketStream<Connection>, Message>>> {
         self.sender.borrow_mut()
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+    use tokio_tungstenite::{client_async, accept_async};
+
+    /// A client on a real plaintext websocket, with the far end to read what it is sent.
+    pub(crate) async fn connected_client(server_tx: mpsc::UnboundedSender<CMessage>) -> (Client, WebSocketStream<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let connecting = tokio::spawn(async move {
+            let stream = TcpStream::connect(address).await.unwrap();
+            client_async(format!("ws://{}/", address), stream).await.unwrap().0
+        });
+
+        let (stream, peer) = listener.accept().await.unwrap();
+        let (ws_sender, _) = accept_async(Connection::Plain(stream)).await.unwrap().split();
+        (Client::new(ws_sender, server_tx, peer.ip(), None), connecting.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn options_keep_only_defined_bits() {
+        let (server_tx, _server_rx) = mpsc::unbounded_channel();
+        let (mut client, _far_end) = connected_client(server_tx).await;
+        let admin_and_invite = ClientOptions::Admin.bits() | ClientOptions::CanInvite.bits();
+
+        assert_eq!(ClientOptions::from_bits(admin_and_invite | 1 | 1 << 40).bits(), admin_and_invite);
+        client.set_options_u64(admin_and_invite | 1 << 40);
+        assert!(client.has_options(ClientOptions::Admin));
+        assert!(client.has_options(ClientOptions::CanInvite));
+        assert!(!client.has_options(ClientOptions::Invisible));
+        assert_eq!(client.options().bits(), admin_and_invite);
+    }
+
+    #[tokio::test]
+    async fn flood_bucket_empties_and_counts_strikes() {
+        let (server_tx, _server_rx) = mpsc::unbounded_channel();
+        let (mut client, _far_end) = connected_client(server_tx).await;
+
+        for _ in 0..3 {
+            assert!(client.take_flood_token(0.0, 3));
+        }
+        assert!(!client.take_flood_token(0.0, 3));
+        assert!(!client.take_flood_token(0.0, 3));
+        assert_eq!(client.flood_strikes(), 2);
+    }
+
+    #[tokio::test]
+    async fn flood_bucket_refills_and_clears_strikes() {
+        let (server_tx, _server_rx) = mpsc::unbounded_channel();
+        let (mut client, _far_end) = connected_client(server_tx).await;
+
+        while client.take_flood_token(0.0, 2) {}
+        assert_eq!(client.flood_strikes(), 1);
+
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert!(client.take_flood_token(1000.0, 2));
+        assert_eq!(client.flood_strikes(), 0);
+    }
+}
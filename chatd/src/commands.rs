@@ -2,26 +2,48 @@ use std::{str::FromStr, time::SystemTime};
 use crate::{handle_client_error, AuthFinder, ClientStatus, Config, OpenOptions, Write};
 // use regex::Regex;
 use futures_util::SinkExt;
+use tracing::{debug, error, info};
 use crate::{
     IpAddr, Ipv4Addr, message::*,
-    channel::*, client::Client, server::Server, Arc, CMessage, ClientOptions, ClientType, Mutex, ProblemCode, RwLock, Uuid
+    channel::*, client::{Client, Presence}, server::Server, Arc, CMessage, ClientOptions, ClientType, Mutex, ProblemCode, RwLock, Uuid
 };
 
 pub struct CommandHandler;
 impl CommandHandler {
-    pub async fn kline(server: &Mutex<Server>, client: &RwLock<Client>, target: IpAddr, reason: String, expires_sec: u64) {
+    /// Ban an ip address or CIDR range, IPv4 or IPv6, and kill every non agent client in it.
+    pub async fn kline(server: &Mutex<Server>, client: &RwLock<Client>, target: String, reason: String, expires_sec: u64) {
         if client.read().await.gettype() as u8 != ClientType::Agent as u8 {
             _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "No permission to perform this action.".to_string()).into()).await;
             return;
         }
 
-        if server.lock().await.ban_exists(target).await {
+        let (min_v4, min_v6) = {
+            let slock = server.lock().await;
+            (slock.conf.kline_min_prefix_v4, slock.conf.kline_min_prefix_v6)
+        };
+
+        let target = match crate::Ban::parse_network(&target, min_v4, min_v6) {
+            Ok(network) => network,
+            Err(e)      => {
+                _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::InvalidArgument, None, e).into()).await;
+                return;
+            }
+        };
+
+        let expires = match SystemTime::now().checked_add(std::time::Duration::new(expires_sec, 0)) {
+            Some(expires)   => expires,
+            None            => {
+                _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::InvalidArgument, None, "The ban duration is too long.".to_string()).into()).await;
+                return;
+            }
+        };
+
+        if server.lock().await.ban_listed(target).await {
             _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::AlreadyMember, None, "The ip is already in the ban list.".to_string()).into()).await;
             return;
         }
 
         let mut slock = server.lock().await;
-        let expires = SystemTime::now().checked_add(std::time::Duration::new(expires_sec,0)).unwrap();
         match slock.add_ban(target, &reason, expires, &client.read().await.name()).await {
             Ok(()) => {},
             Err(_e) => {
@@ -37,12 +59,12 @@ impl CommandHandler {
 
         for (id, check_client) in hunt_lock.iter() {
             let clock = check_client.write().await;
-            if clock.ip() != target {
+            if !target.contains(&clock.ip().to_canonical()) {
                 continue;
             }
 
             if clock.gettype() as u8 == ClientType::Agent as u8 {
-                println!("skip kline matching kill of agent: {}@{} [{}]", clock.name(), clock.ip(), reason.to_owned());
+                debug!(client = %clock.id(), ip = %clock.ip(), reason = %reason, "kline: skipped kill of agent");
                 continue;
             }
 
@@ -67,6 +89,62 @@ impl CommandHandler {
         _ = crate::Ban::save_to_disk(&ban_file, &bans.read().await.clone());
     }
 
+    /// Remove an ip address or CIDR range from the ban list. It must match the K-line exactly.
+    pub async fn unkline(server: &Mutex<Server>, client: &RwLock<Client>, target: String) {
+        if client.read().await.gettype() as u8 != ClientType::Agent as u8 {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "No permission to perform this action.".to_string()).into()).await;
+            return;
+        }
+
+        // no prefix floor, bans wider than the current one must still be removable
+        let target = match crate::Ban::parse_network(&target, 0, 0) {
+            Ok(network) => network,
+            Err(e)      => {
+                _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::InvalidArgument, None, e).into()).await;
+                return;
+            }
+        };
+
+        let mut slock = server.lock().await;
+
+        if slock.remove_ban(target).await.is_err() {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::NotAvailable, None, "The ip is not in the ban list.".to_string()).into()).await;
+            return;
+        }
+
+        let ban_file = slock.conf.ban_db.to_owned();
+
+        if let Err(e) = slock.save_ban_db(&ban_file).await {
+            error!(file = %ban_file, error = %e, "unable to save bans");
+        }
+
+        let name = client.read().await.name();
+        slock.sendto_wallops(CMessage::new(MessageType::Wallop(format!("<i>{}</i> removed the server ban on <i>{}</i>", name, target)), Uuid::nil(), Uuid::nil(), None)).await;
+    }
+
+    /// Send the bans that have not expired to an agent.
+    pub async fn ban_list(server: &Mutex<Server>, client: &RwLock<Client>) {
+        if client.read().await.gettype() as u8 != ClientType::Agent as u8 {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "No permission to perform this action.".to_string()).into()).await;
+            return;
+        }
+
+        let bans = server.lock().await.bans();
+        let unix = |time: SystemTime| time.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs();
+        let list: Vec<BanEntry> = bans.read().await.iter()
+            .filter(|ban| !ban.is_expired())
+            .map(|ban| BanEntry {
+                network: ban.network().to_string(),
+                reason: ban.reason.to_owned(),
+                added_by: ban.added_by.to_owned(),
+                added_on: unix(ban.added_on),
+                expires: unix(ban.expires),
+            })
+            .collect();
+
+        _ = client.write().await.sender().lock().await.send(CMessage::new(MessageType::BanList(list), Uuid::nil(), Uuid::nil(), None).into()).await;
+    }
+
     pub async fn kill(server: &Mutex<Server>, client: &RwLock<Client>, target: Uuid, mut reason: String) {
         if client.read().await.gettype() as u8 != ClientType::Agent as u8 {
             _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "No permission to perform this action.".to_string()).into()).await;
@@ -83,7 +161,6 @@ impl CommandHandler {
         let target_ref = target_ref.unwrap();
 
         if target_ref.read().await.has_options(ClientOptions::Admin) {
-            println!("options: {}", target_ref.read().await.options().bits());
             _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "You may not kill ADMIN flag users.".to_string()).into()).await;
             return;
         }
@@ -124,7 +201,6 @@ impl CommandHandler {
                             .open(format!("logs/{}.log",id));
 
                         if logfile.is_ok() {
-                            println!("logfile is ok");
                             let log_entry = format!("{:?} / DESTROY: {} ({}@{}) [Client Killed]\n", std::time::SystemTime::now(), chan_ref.read().await.name(), kicked_name , target_ref.read().await.ip());
 
                             let _ = logfile.unwrap().write(log_entry.as_bytes());
@@ -139,7 +215,6 @@ impl CommandHandler {
                             .open(format!("logs/{}.log", id));
 
                         if logfile.is_ok() {
-                            println!("logfile is ok");
                             let log_entry = format!("{:?} / Quit(Killed): {} ({}@{})\n", std::time::SystemTime::now(), chan_ref.read().await.name(), kicked_name , target_ref.read().await.ip());
 
                             let _ = logfile.unwrap().write(log_entry.as_bytes());
@@ -197,6 +272,8 @@ impl CommandHandler {
         }
 
         let chan_ref = chan_ref.unwrap();
+        let store = server.lock().await.channel_store();
+        let limit_step = server.lock().await.conf.limit_step;
         let mut client_lock = client.write().await;
         let chan_lock = chan_ref.read().await;
         let chan_owner = chan_lock.owner_id().unwrap_or(Uuid::nil());
@@ -209,9 +286,9 @@ impl CommandHandler {
         let mut channel_options = 0u64;
 
         let real_options: Vec<u64> = ChannelOptions::available().iter().filter_map(|&option| { 
-            if modes & (option as u64) == (option as u64) { 
-                channel_options += option as u64;
-                Some(option as u64); 
+            if modes & option.bits() == option.bits() { 
+                channel_options += option.bits();
+                Some(option.bits()); 
             } 
             None
         }).collect();
@@ -234,37 +311,97 @@ impl CommandHandler {
         if client_lock.gettype() as u8 != ClientType::Agent as u8 {
 
             for mode in ChannelOptions::public_modes().iter() {
-                if options as u64 & *mode as u64 == *mode as u64 && !chan_lock.has_option(*mode) {
+                if options.and(*mode) == *mode && !chan_lock.has_option(*mode) {
                     chan_lock.add_option(*mode);
                 }
-                else if options as u64 & *mode as u64 != *mode as u64 && chan_lock.has_option(*mode) {
+                else if options.and(*mode) != *mode && chan_lock.has_option(*mode) {
                     chan_lock.remove_option(*mode);
                 }
             }
 
-            if original_options as u64 == chan_lock.options() as u64 {
+            if original_options == chan_lock.options() {
                 return;
             }
             std::mem::drop(client_lock);
+
+            if let Some(store) = &store {
+                store.persist(&chan_lock).await;
+            }
+
             let chanopt = chan_lock.options_vec_string();
             let _ = chan_lock.sendto_all(&CMessage::new(MessageType::Message("<i class=\"fa fa-gear\"> </i><i class=\"ichat-modechange\"> has changed the channel modes.</i>".to_string()), Uuid::nil(), chan_id, Some(client_name))).await;
             let _ = chan_lock.sendto_all(&CMessage::new(MessageType::ChannelModes(Some(chanopt)), Uuid::nil(), chan_id, None).into()).await;
             return; // end of non agents setting modes
         }
 
-        if original_options as u64 == options as u64 {
+        if original_options == options {
             return;
         }
 
-        println!("modes: {} and new is {} -- {}", original_options as u64, options as u64, channel_options as u64);
+        debug!(channel = %chan_lock.id(), old = original_options.bits(), new = options.bits(), requested = channel_options, "modes changed");
         chan_lock.set_options(options);
+
+        // the Limit mode starts by letting limit_step more members in than there are now
+        if chan_lock.has_option(ChannelOptions::Limit) {
+            if original_options.and(ChannelOptions::Limit) == ChannelOptions::None {
+                chan_lock.raise_limit(limit_step).await;
+            }
+        } else {
+            chan_lock.clear_raised_limit();
+        }
         std::mem::drop(client_lock);
+
+        if let Some(store) = &store {
+            store.persist(&chan_lock).await;
+        }
+
         let chanopt = chan_lock.options_vec_string();
         let _ = chan_lock.sendto_all(&CMessage::new(MessageType::Message("<i class=\"fa fa-gear\"> </i><i class=\"ichat-modechange\"> has changed the channel modes.</i>".to_string()), Uuid::nil(), chan_id, Some(client_name))).await;
         let _ = chan_lock.sendto_all(&CMessage::new(MessageType::ChannelModes(Some(chanopt)), Uuid::nil(), chan_id, None).into()).await;
         return; 
     }
 
+    /// Set the most members a channel may have, 0 removes the limit. Agents ignore it.
+    pub async fn set_channel_limit(server: &Mutex<Server>, client: &RwLock<Client>, channel: Uuid, limit: u64) {
+        let chan_ref = server.lock().await.get_channel_ref(channel).await;
+
+        if chan_ref.is_none() {
+            let _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::ChannelInvalid, Some(channel), "Cannot change the limit of non-existent channels.".to_string()).into()).await;
+            return;
+        }
+
+        let chan_ref = chan_ref.unwrap();
+        let store = server.lock().await.channel_store();
+        let mut client_lock = client.write().await;
+        let mut chan_lock = chan_ref.write().await;
+        let chan_owner = chan_lock.owner_id().unwrap_or(Uuid::nil());
+
+        if chan_owner != client_lock.id() && client_lock.gettype() as u8 != ClientType::Agent as u8 {
+            let _ = client_lock.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, Some(channel), "You have no permission to change this channels limit.".to_string()).into()).await;
+            return;
+        }
+
+        if chan_lock.member_limit() == limit {
+            return;
+        }
+
+        let client_name = client_lock.name();
+        std::mem::drop(client_lock);
+        chan_lock.set_member_limit(limit);
+
+        if let Some(store) = &store {
+            store.persist(&chan_lock).await;
+        }
+
+        let text = match limit {
+            0   => "<i class=\"fa fa-gear\"> </i><i class=\"ichat-modechange\"> has removed the channel member limit.</i>".to_string(),
+            _   => format!("<i class=\"fa fa-gear\"> </i><i class=\"ichat-modechange\"> has limited the channel to {} members.</i>", limit)
+        };
+        let _ = chan_lock.sendto_all(&CMessage::new(MessageType::Message(text), Uuid::nil(), channel, Some(client_name))).await;
+        let _ = chan_lock.sendto_all(&CMessage::new(MessageType::ChannelLimit(limit), Uuid::nil(), channel, None)).await;
+        chan_lock.to_log(format!("{:?} / LIMIT: {} now {}", SystemTime::now(), chan_lock.name(), limit)).await;
+    }
+
     pub async fn set_channel_topic(server: &Mutex<Server>, client: &RwLock<Client>, channel: Uuid, topic: String, max_len: u16) {
         let chan_ref = server.lock().await.get_channel_ref(channel).await;
 
@@ -274,6 +411,7 @@ impl CommandHandler {
         }
 
         let chan_ref = chan_ref.unwrap();
+        let store = server.lock().await.channel_store();
         let mut client_lock = client.write().await;
         let chan_lock = chan_ref.read().await;
         let chan_owner = chan_lock.owner_id().unwrap_or(Uuid::nil());
@@ -301,6 +439,18 @@ impl CommandHandler {
         chan_lock.set_topic(Some(clean_topic.clone())).await;
         }
 
+        if let Some(store) = &store {
+            store.persist(&chan_lock).await;
+
+            if chan_lock.has_option(ChannelOptions::SaveHistory) {
+                let history = CMessage::new(MessageType::Topic(clean_topic.clone()), client_lock.id(), chan_id, Some(client_name.clone()));
+
+                if let Err(e) = store.add_history(&chan_lock, &history, client_lock.ip()).await {
+                    error!(channel = %chan_id, error = %e, "channel store: unable to save history");
+                }
+            }
+        }
+
         let _ = chan_lock.sendto_all(&CMessage::new(MessageType::Message("<i class=\"ichat-modechange\"> has changed the channel topic.</i>".to_string()), Uuid::nil(), chan_id, Some(client_name))).await;
         let _ = chan_lock.sendto_all(&CMessage::new(MessageType::Topic(clean_topic), Uuid::nil(), chan_id, None)).await;
     }
@@ -332,13 +482,31 @@ impl CommandHandler {
         return;
     }
 
+    /// Send live server counters to an agent.
+    pub async fn stats(server: &Mutex<Server>, client: &RwLock<Client>) {
+        if client.read().await.gettype() as u8 != ClientType::Agent as u8 {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "No permission to perform this action.".to_string()).into()).await;
+            return;
+        }
+
+        let stats = server.lock().await.stats().await;
+        _ = client.write().await.sender().lock().await.send(CMessage::new(MessageType::StatsReply(stats), Uuid::nil(), Uuid::nil(), None).into()).await;
+    }
+
     /// Handles login attempts using a username and password 
-    pub async fn auth_agent(server: &Mutex<Server>, client: &RwLock<Client>, authfinder: Arc<Mutex<Box<dyn AuthFinder + Send + Sync>>>, config: Config, nickname: String, username: String, password: String) {
-        let userauth = authfinder.lock().await.by_username_password(&username, &password).await;
+    pub async fn auth_agent(server: &Mutex<Server>, client: &RwLock<Client>, authfinder: Arc<RwLock<Box<dyn AuthFinder + Send + Sync>>>, config: Config, nickname: String, username: String, password: String) {
+        if CommandHandler::auth_throttled(server, client).await {
+            return;
+        }
+
+        let userauth = authfinder.read().await.by_username_password(&username, &password).await;
 
         if userauth.is_none() {
             let _= client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::InvalidAuth, None, "The username and password are not valid.".to_string()).into()).await;
             client.write().await.set_status(crate::ClientStatus::Closing);
+            server.lock().await.invalid_connects += 1;
+            let ip = client.read().await.ip();
+            server.lock().await.count_auth_failure(ip);
             server.lock().await.remove_client(client.read().await.id()).await;
             let _ = client.write().await.sender.lock().await.close().await;
             return;
@@ -361,10 +529,11 @@ impl CommandHandler {
         c_lock.set_status(ClientStatus::Connected);
         c_lock.set_type(ClientType::Agent);
         c_lock.set_options_u64(userauth.permissions);
-        server.lock().await.guest_count += 1;
+        c_lock.set_account(Some(userauth.username.clone()));
         let id = c_lock.id();
         let name = c_lock.name();
-        let _ = c_lock.sender().lock().await.send(CMessage::new(MessageType::AuthOk(id.to_owned(), name.to_owned()), Uuid::nil(), Uuid::nil(), None ).into()).await;
+        let token = c_lock.resume_token();
+        let _ = c_lock.sender().lock().await.send(CMessage::new(MessageType::AuthOk(id.to_owned(), name.to_owned(), token), Uuid::nil(), Uuid::nil(), None ).into()).await;
         let motd = server.lock().await.motd_agents.clone();
                 
         if motd.len() > 0 {
@@ -374,7 +543,7 @@ impl CommandHandler {
         if config.use_global_lobby {
             if let Some(global_channel) = server.lock().await.get_channel_by_name("Global Lobby".to_string()).await {
                 let mut lock = global_channel.write().await;
-                println!("*** adding client {} to Global Lobby", 1+lock.count_members().await);
+                debug!(client = %c_lock.id(), channel = %lock.id(), "adding client to Global Lobby");
                 lock.add_member(c_lock.clone()).await;
                 c_lock.add_channel(lock.id(), lock.name()).await;
 
@@ -382,10 +551,7 @@ impl CommandHandler {
                     let _ = c_lock.sender().lock().await.send(CMessage::new(MessageType::Join(name.to_owned(), IpAddr::V4(Ipv4Addr::new(0,0,0,0))), id.to_owned(), lock.id(), Some(lock.name())).into()).await;
                     
                     if !lock.has_option(ChannelOptions::HiddenMemberList) {
-                        let mut members_list: Vec<(Uuid, String)> = Vec::new();
-                        for (id,cl) in lock.get_members().await.iter() {
-                            members_list.push((id.to_owned(), cl.lock().await.name()));
-                        }
+                        let members_list = lock.member_list().await;
                         
                         if members_list.is_empty() {
                             let _ = c_lock.sender().lock().await.send(CMessage::new(MessageType::UserList(None), Uuid::nil(), lock.id(), None).into()).await;
@@ -412,10 +578,7 @@ impl CommandHandler {
                 let _ = c_lock.sender().lock().await.send(CMessage::new(MessageType::Join(name.to_owned(), IpAddr::V4(Ipv4Addr::new(0,0,0,0))), id.to_owned(), lock.id(), Some(lock.name())).into()).await;
                 
                 if !lock.has_option(ChannelOptions::HiddenMemberList) {
-                    let mut members_list: Vec<(Uuid, String)> = Vec::new();
-                    for (id,cl) in lock.get_members().await.iter() {
-                        members_list.push((id.to_owned(), cl.lock().await.name()));
-                    }
+                    let members_list = lock.member_list().await;
                     
                     if members_list.is_empty() {
                         let _ = c_lock.sender().lock().await.send(CMessage::new(MessageType::UserList(None), Uuid::nil(), lock.id(), None).into()).await;
@@ -433,165 +596,1041 @@ impl CommandHandler {
         }
     }
 
-    pub async fn typing(server: &Mutex<Server>, client: &RwLock<Client>, target: Uuid) {
-        let slock = server.lock().await;
-        let channel = match slock.get_channel_ref(target).await {
-            Some(rchan)   => rchan,
-            None        => return
-        };
-        let hidden_msg = channel.read().await.has_option(ChannelOptions::HiddenMessages);
-        let hidden_members = channel.read().await.has_option(ChannelOptions::HiddenMemberList);
-        let clientid = client.read().await.id();
+    /// Refuses and closes a connection from an ip with too many recent failed logins.
+    async fn auth_throttled(server: &Mutex<Server>, client: &RwLock<Client>) -> bool {
+        let ip = client.read().await.ip();
 
-        if hidden_msg || hidden_members {
-            let _ = channel.write().await.sendto_agents(&CMessage::new(MessageType::Typing(clientid, target), clientid, target, None).into()).await;
-            return;
-        } else {
-            let _ = channel.write().await.sendto_all_butone(clientid, &CMessage::new(MessageType::Typing(clientid, target), clientid, target, None).into()).await;
-            return;
+        if !server.lock().await.auth_throttled(ip) {
+            return false;
         }
-        // if !let Some(channel) = match slock.
+
+        let _= client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::Flooding, None, "Too many failed logins, try again later.".to_string()).into()).await;
+        client.write().await.set_status(crate::ClientStatus::Closing);
+        server.lock().await.remove_client(client.read().await.id()).await;
+        let _ = client.write().await.sender.lock().await.close().await;
+        true
     }
 
-    pub async fn part(server: &Mutex<Server>, client: &RwLock<Client>, channel: Uuid) {
-        if !client.read().await.has_options(ClientOptions::Admin) && !client.read().await.has_options(ClientOptions::PartChannels) {
-            let _= client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "You may not leave channels".to_string()).into()).await;
+    /// Handles login attempts by returning contacts using a registered device id
+    pub async fn auth_device(server: &Mutex<Server>, client: &RwLock<Client>, authfinder: Arc<RwLock<Box<dyn AuthFinder + Send + Sync>>>, config: Config, device: String, username: String) {
+        if !config.allow_clients {
+            let _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::NotAvailable, None, "Not accepting client logins at this time.".to_string()).into()).await;
+            let _ = client.write().await.sender().lock().await.close().await;
             return;
-        } 
-        
-        let slock = server.lock().await;
+        }
 
-        let channel_ref = match slock.get_channel_ref(channel).await {
-            Some(r)   => r,
-            None        => {
-                let _= client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::ChannelInvalid, None, "Invalid channel.".to_string()).into()).await;
-                return;
-            }
-        };
+        if CommandHandler::auth_throttled(server, client).await {
+            return;
+        }
 
-        if !channel_ref.read().await.is_member(client.read().await.id()).await {
-            let _= client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::NotMember, None, "Cannot leave channels you are not a member of.".to_string()).into()).await;
+        let userauth = authfinder.read().await.by_device(&device, &username).await;
+
+        if userauth.is_none() {
+            let _= client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::InvalidAuth, None, "The device is not registered.".to_string()).into()).await;
+            client.write().await.set_status(crate::ClientStatus::Closing);
+            server.lock().await.invalid_connects += 1;
+            let ip = client.read().await.ip();
+            server.lock().await.count_auth_failure(ip);
+            server.lock().await.remove_client(client.read().await.id()).await;
+            let _ = client.write().await.sender.lock().await.close().await;
             return;
         }
-        
-        let clock = client.read().await;
-        let client_type = clock.gettype();
-        let client_id = clock.id();
-        let client_name = clock.name();
-        let client_ip = clock.ip();
-        let is_admin = clock.has_options(ClientOptions::Admin);
-        std::mem::drop(clock);
 
-        if channel_ref.read().await.has_option(ChannelOptions::CanNotLeave) && (client_type as u64 != ClientType::Agent as u64 || !is_admin) {
-            let _= client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "You may not leave this channel.".to_string()).into()).await;
+        if server.lock().await.client_name_to_uuid(&username).await.is_some() {
+            let _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::NameInUse, None, username).into()).await;
             return;
         }
 
-        // we are good to let them leave the channel
-        let cmessage = CMessage::new(MessageType::Part(client.read().await.id(), channel, IpAddr::V4(Ipv4Addr::new(0,0,0,0))), client_id, channel, None);
-        let _ = client.write().await.sender().lock().await.send(cmessage.into()).await;
-        channel_ref.write().await.remove_member(client_id).await;
-        channel_ref.write().await.broadcast_part(client_id, client_name.clone(), client_ip).await;
+        let mut c_lock = client.write().await;
 
-        let is_persist = channel_ref.read().await.has_option(ChannelOptions::Persist);
+        if !c_lock.set_name(&username).await {
+            let _ = c_lock.sender().lock().await.send(CMessage::new_problem(ProblemCode::NameInvalid, None, username).into()).await;
+            return;
+        }
 
-        if channel_ref.read().await.count_members().await == 0 && !is_persist {
-            std::mem::drop(slock); // not mut
-            let mut slock = server.lock().await;
-            let uuid = channel_ref.read().await.id();
+        c_lock.set_status(ClientStatus::Connected);
+        c_lock.set_type(ClientType::Contact);
+        c_lock.set_options_u64(config.default_client_options);
+        c_lock.set_account(Some(username.clone()));
+        let id = c_lock.id();
+        let name = c_lock.name();
+        let token = c_lock.resume_token();
+        let _ = c_lock.sender().lock().await.send(CMessage::new(MessageType::AuthOk(id.to_owned(), name.to_owned(), token), Uuid::nil(), Uuid::nil(), None ).into()).await;
+        let motd = server.lock().await.motd_clients.clone();
 
-            if channel_ref.read().await.has_option(ChannelOptions::SaveHistory) {
-                let logfile = OpenOptions::new()
-                    .append(true)
-                    .create(true)
-                    .open(format!("logs/{}.log",channel_ref.read().await.id()));
+        if !motd.is_empty() {
+            let _ = c_lock.sender().lock().await.send(CMessage::new(MessageType::Motd(motd), Uuid::nil(), id, Some("Client Message of the Day".to_string())).into()).await;
+        }
 
-                if logfile.is_ok() {
-                    println!("logfile is ok");
-                    let log_entry = format!("{:?} / DESTROY: {} ({}@{})\n", std::time::SystemTime::now(), channel_ref.read().await.name(), client_name , client_ip);
+        if config.use_global_lobby {
+            CommandHandler::join_lobby(server, &mut c_lock, "Global Lobby").await;
+        }
+    }
 
-                    let _ = logfile.unwrap().write(log_entry.as_bytes());
-                }
-            }
-            slock.remove_channel(uuid).await;
-        } else {
-            if channel_ref.read().await.has_option(ChannelOptions::SaveHistory) {
-                let logfile = OpenOptions::new()
-                    .append(true)
-                    .create(true)
-                    .open(format!("logs/{}.log",channel_ref.read().await.id()));
+    /// Registers a device for a connected client, so they can log back in as a contact.
+    ///
+    /// The device id is only ever sent to the registered client; the database keeps a hash.
+    pub async fn register_device(server: &Mutex<Server>, client: &RwLock<Client>, authfinder: Arc<RwLock<Box<dyn AuthFinder + Send + Sync>>>, target: Uuid, username: String) {
+        if client.read().await.gettype() as u8 != ClientType::Agent as u8 {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "No permission to perform this action.".to_string()).into()).await;
+            return;
+        }
 
-                if logfile.is_ok() {
-                    println!("logfile is ok");
-                    let log_entry = format!("{:?} / PART: {} ({}@{})\n", std::time::SystemTime::now(), channel_ref.read().await.name(), client_name , client_ip);
+        let re = regex::Regex::new(r"^[a-zA-Z0-9 \-]{3,30}$").expect("unable to create regex");
 
-                    let _ = logfile.unwrap().write(log_entry.as_bytes());
-                }
-            }
+        if !re.is_match(&username) || username.starts_with("Guest-") {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::NameInvalid, None, username).into()).await;
+            return;
         }
-    }
 
-    /// process a join channel command. channel can be a uuid or name
-    pub async fn join(server: &Mutex<Server>, client: &RwLock<Client>, channel: String, is_uuid: bool) {
-        let mut channel_ref: Option<Arc<RwLock<Channel>>> = None;
-        let mut slock = server.lock().await;
+        if server.lock().await.get_client_ref(target).await.is_none() {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::NameInvalid, None, "No matching client was found.".to_string()).into()).await;
+            return;
+        }
 
-        if !client.read().await.has_options(ClientOptions::Admin) && !client.read().await.has_options(ClientOptions::JoinChannels) {
-            let _= client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "You may not create new channels".to_string()).into()).await;
+        let device = Uuid::new_v4().to_string();
+        let agent_name = client.read().await.name();
+
+        if let Err(e) = authfinder.write().await.add_device(&device, &username, &agent_name).await {
+            error!(username = %username, error = %e, "unable to register device");
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::NotAvailable, None, "Unable to register device.".to_string()).into()).await;
             return;
         }
-        if is_uuid {
-            if let Some(chan) = slock.get_channel_ref(Uuid::from_str(&channel).unwrap()).await {
-            channel_ref = Some(chan);
-            }
-        } else {
-            if let Some(chan_uuid) = slock.channel_name_to_uuid(channel.clone()).await {
-                if let Some(chan) = slock.get_channel_ref(chan_uuid).await {
-                    channel_ref = Some(chan);
-                }
 
-            }
+        server.lock().await.sendto_one(target, CMessage::new(MessageType::DeviceRegistered(device, username.clone()), Uuid::nil(), target, None)).await;
+        server.lock().await.sendto_wallops(CMessage::new(MessageType::Wallop(format!("<i>{}</i> registered a device for <i>{}</i>", agent_name, username)), Uuid::nil(), Uuid::nil(), None)).await;
+    }
+
+    /// Revokes all devices registered to a username.
+    pub async fn revoke_device(server: &Mutex<Server>, client: &RwLock<Client>, authfinder: Arc<RwLock<Box<dyn AuthFinder + Send + Sync>>>, username: String) {
+        if client.read().await.gettype() as u8 != ClientType::Agent as u8 {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "No permission to perform this action.".to_string()).into()).await;
+            return;
         }
-        // channel exists, treat it based on its settings
-        if channel_ref.is_some() {
-            let channel_ref = channel_ref.unwrap();
-            let cref_read = channel_ref.read().await;
 
-            if cref_read.is_member(client.read().await.id()).await {
-                let _= client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::AlreadyMember, None, "You are already a member of that channel.".to_string()).into()).await;
-                return;
+        let agent_name = client.read().await.name();
+
+        match authfinder.write().await.delete_devices(&username).await {
+            Ok(0)   => {
+                _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::NameInvalid, None, "No devices are registered to that username.".to_string()).into()).await;
+            },
+            Ok(count)   => {
+                server.lock().await.sendto_wallops(CMessage::new(MessageType::Wallop(format!("<i>{}</i> revoked {} devices for <i>{}</i>", agent_name, count, username)), Uuid::nil(), Uuid::nil(), None)).await;
+            },
+            Err(e)  => {
+                error!(username = %username, error = %e, "unable to revoke devices");
+                _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::NotAvailable, None, "Unable to revoke devices.".to_string()).into()).await;
             }
-            if cref_read.has_option(ChannelOptions::AgentOnly) && client.read().await.gettype() as u64 != ClientType::Agent as u64 {
-                let _= client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "Channel restricted to Agents.".to_string()).into()).await;
+        }
+    }
+
+    /// Replies with every agent account. Requires the Admin flag.
+    pub async fn agent_list(client: &RwLock<Client>, authfinder: Arc<RwLock<Box<dyn AuthFinder + Send + Sync>>>) {
+        if !client.read().await.has_options(ClientOptions::Admin) {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "No permission to perform this action.".to_string()).into()).await;
+            return;
+        }
+
+        let agents = match authfinder.read().await.all().await {
+            Some(list)  => list.into_iter().map(|a| (a.username, a.permissions, a.disabled)).collect(),
+            None        => {
+                _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::NotAvailable, None, "Unable to list agents.".to_string()).into()).await;
                 return;
             }
+        };
 
-            if cref_read.has_option(ChannelOptions::InviteOnly) && 
-                !client.read().await.has_options(ClientOptions::Admin) &&
-                !cref_read.has_invite(client.read().await.id()) {
-                    let _= client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "Channel is invite only.".to_string()).into()).await;
-                    return;
-            } 
+        _ = client.write().await.sender().lock().await.send(CMessage::new(MessageType::AgentList(agents), Uuid::nil(), Uuid::nil(), None).into()).await;
+    }
 
-            // client is allowed to join the channel at this point
-            std::mem::drop(cref_read);
-            let mut cref_write = channel_ref.write().await;
+    /// Creates a new agent account. Requires the Admin flag.
+    pub async fn agent_add(server: &Mutex<Server>, client: &RwLock<Client>, authfinder: Arc<RwLock<Box<dyn AuthFinder + Send + Sync>>>, username: String, password: String, permissions: u64) {
+        if !client.read().await.has_options(ClientOptions::Admin) {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "No permission to perform this action.".to_string()).into()).await;
+            return;
+        }
 
-            let mut clock = client.write().await;
-            cref_write.add_member(clock.clone()).await;
-            clock.add_channel(cref_write.id(), cref_write.name()).await;
-            // cref_write.add_member(client.write().await.clone()).await;
-            let username = clock.name();
-            let userid = clock.id();
+        let re = regex::Regex::new(r"^[a-zA-Z0-9_\-\.]{3,30}$").expect("unable to create regex");
 
-            if cref_write.has_option(ChannelOptions::SaveHistory) {
-                let logfile = OpenOptions::new()
-                    .append(true)
-                    .create(true)
-                    .open(format!("logs/{}.log",cref_write.id()));
+        if !re.is_match(&username) {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::NameInvalid, None, username).into()).await;
+            return;
+        }
+
+        if password.chars().count() < 8 {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::InvalidArgument, None, "Passwords must be at least 8 characters.".to_string()).into()).await;
+            return;
+        }
+
+        let permissions = ClientOptions::from_bits(permissions);
+        let result = authfinder.write().await.add(&username, &password, permissions).await.map(|_| ());
+        CommandHandler::agent_change_result(server, client, authfinder, result, format!("created agent <i>{}</i>", username)).await;
+    }
+
+    /// Removes an agent account. Requires the Admin flag, and admins cannot remove themselves.
+    pub async fn agent_delete(server: &Arc<Mutex<Server>>, client: &RwLock<Client>, authfinder: Arc<RwLock<Box<dyn AuthFinder + Send + Sync>>>, username: String) {
+        if !client.read().await.has_options(ClientOptions::Admin) {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "No permission to perform this action.".to_string()).into()).await;
+            return;
+        }
+
+        if client.read().await.account() == Some(username.clone()) {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::InvalidArgument, None, "You may not delete your own account.".to_string()).into()).await;
+            return;
+        }
+
+        let result = authfinder.write().await.delete(&username).await;
+        let deleted = result.is_ok();
+        CommandHandler::agent_change_result(server, client, authfinder, result, format!("deleted agent <i>{}</i>", username)).await;
+
+        if deleted {
+            crate::end_account_sessions(server, &username, "Account deleted".to_string()).await;
+        }
+    }
+
+    /// Disables or re-enables an agent account. Requires the Admin flag.
+    pub async fn agent_disable(server: &Arc<Mutex<Server>>, client: &RwLock<Client>, authfinder: Arc<RwLock<Box<dyn AuthFinder + Send + Sync>>>, username: String, disabled: bool) {
+        if !client.read().await.has_options(ClientOptions::Admin) {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "No permission to perform this action.".to_string()).into()).await;
+            return;
+        }
+
+        if client.read().await.account() == Some(username.clone()) {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::InvalidArgument, None, "You may not disable your own account.".to_string()).into()).await;
+            return;
+        }
+
+        let result = authfinder.write().await.set_disabled(&username, disabled).await;
+        let action = if disabled { "disabled" } else { "enabled" };
+        let ended = disabled && result.is_ok();
+        CommandHandler::agent_change_result(server, client, authfinder, result, format!("{} agent <i>{}</i>", action, username)).await;
+
+        if ended {
+            crate::end_account_sessions(server, &username, "Account disabled".to_string()).await;
+        }
+    }
+
+    /// Resets an agents password. Requires the Admin flag.
+    pub async fn agent_password(server: &Mutex<Server>, client: &RwLock<Client>, authfinder: Arc<RwLock<Box<dyn AuthFinder + Send + Sync>>>, username: String, password: String) {
+        if !client.read().await.has_options(ClientOptions::Admin) {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "No permission to perform this action.".to_string()).into()).await;
+            return;
+        }
+
+        if password.chars().count() < 8 {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::InvalidArgument, None, "Passwords must be at least 8 characters.".to_string()).into()).await;
+            return;
+        }
+
+        let result = authfinder.write().await.set_password(&username, &password).await;
+        CommandHandler::agent_change_result(server, client, authfinder, result, format!("reset the password of agent <i>{}</i>", username)).await;
+    }
+
+    /// Replaces an agents permission bits. Requires the Admin flag.
+    pub async fn agent_permissions(server: &Arc<Mutex<Server>>, client: &RwLock<Client>, authfinder: Arc<RwLock<Box<dyn AuthFinder + Send + Sync>>>, username: String, permissions: u64) {
+        if !client.read().await.has_options(ClientOptions::Admin) {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "No permission to perform this action.".to_string()).into()).await;
+            return;
+        }
+
+        let permissions = ClientOptions::from_bits(permissions).bits();
+
+        if client.read().await.account() == Some(username.clone()) && permissions & ClientOptions::Admin.bits() == 0 {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::InvalidArgument, None, "You may not remove your own Admin flag.".to_string()).into()).await;
+            return;
+        }
+
+        let result = authfinder.write().await.set_permissions(&username, permissions).await;
+
+        // live sessions and zombies waiting to resume get the new flags right away
+        if result.is_ok() {
+            for session in crate::clients_with_account(server, &username).await {
+                session.write().await.set_options_u64(permissions);
+            }
+        }
+
+        CommandHandler::agent_change_result(server, client, authfinder, result, format!("set the permissions of agent <i>{}</i> to {}", username, permissions)).await;
+    }
+
+    /// Reports the outcome of an agent account change and sends the updated list.
+    async fn agent_change_result(server: &Mutex<Server>, client: &RwLock<Client>, authfinder: Arc<RwLock<Box<dyn AuthFinder + Send + Sync>>>, result: Result<(), String>, action: String) {
+        if let Err(e) = result {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::NotAvailable, None, format!("Unable to change agent: {}", e)).into()).await;
+            return;
+        }
+
+        let admin_name = client.read().await.name();
+        info!(admin = %admin_name, action = %action, "agents changed");
+        server.lock().await.sendto_walladmins(CMessage::new(MessageType::Walladmin(format!("<i>{}</i> {}", admin_name, action)), Uuid::nil(), Uuid::nil(), None)).await;
+        CommandHandler::agent_list(client, authfinder).await;
+    }
+
+    /// Adds a freshly authenticated client to one of the default lobbies by name.
+    ///
+    /// Sends the join, member list and topic to the client as the other auth paths do. A full
+    /// lobby is refused like any other channel, agents are let in anyway.
+    pub async fn join_lobby(server: &Mutex<Server>, c_lock: &mut Client, lobby: &str) {
+        let channel = match server.lock().await.get_channel_by_name(lobby.to_string()).await {
+            Some(c) => c,
+            None    => return
+        };
+
+        let mut lock = channel.write().await;
+
+        if lock.is_full().await && c_lock.gettype() as u8 != ClientType::Agent as u8 {
+            let _ = c_lock.sender().lock().await.send(CMessage::new_problem(ProblemCode::ChannelFull, None, "Channel is full, try again later.".to_string()).into()).await;
+            return;
+        }
+
+        debug!(client = %c_lock.id(), channel = %lock.id(), lobby = %lobby, "adding client to lobby");
+        lock.add_member(c_lock.clone()).await;
+        c_lock.add_channel(lock.id(), lock.name()).await;
+        Self::send_channel_state(c_lock, &lock).await;
+        lock.to_log(format!("{:?} / JOIN CHANNEL: {} ({}@{})", std::time::SystemTime::now(), lock.name(), c_lock.name(), c_lock.ip())).await;
+    }
+
+    /// Send a client its own Join, the member list and topic of a channel it is in.
+    pub async fn send_channel_state(c_lock: &mut Client, lock: &Channel) {
+        let id = c_lock.id();
+        let name = c_lock.name();
+
+        if !lock.has_option(ChannelOptions::Invisible) || c_lock.gettype() as u8 == ClientType::Agent as u8 {
+            let _ = c_lock.sender().lock().await.send(CMessage::new(MessageType::Join(name, IpAddr::V4(Ipv4Addr::new(0,0,0,0))), id, lock.id(), Some(lock.name())).into()).await;
+
+            if !lock.has_option(ChannelOptions::HiddenMemberList) || c_lock.gettype() as u8 == ClientType::Agent as u8 {
+                let members_list = lock.member_list().await;
+
+                if members_list.is_empty() {
+                    let _ = c_lock.sender().lock().await.send(CMessage::new(MessageType::UserList(None), Uuid::nil(), lock.id(), None).into()).await;
+                } else {
+                    let _ = c_lock.sender().lock().await.send(CMessage::new(MessageType::UserList(Some(members_list)), Uuid::nil(), lock.id(), None).into()).await;
+                }
+            }
+
+            if let Some(topic) = lock.topic() {
+                let _ = c_lock.sender().lock().await.send(CMessage::new(MessageType::Topic(topic), Uuid::nil(), lock.id(), None).into()).await;
+            }
+
+            if lock.member_limit() > 0 {
+                let _ = c_lock.sender().lock().await.send(CMessage::new(MessageType::ChannelLimit(lock.member_limit()), Uuid::nil(), lock.id(), None).into()).await;
+            }
+        }
+    }
+
+    /// Resume a zombie session on a new connection using the token from AuthOk.
+    ///
+    /// The new connection takes over the zombies id, name and channels. Nothing is
+    /// broadcast to other members, they never saw the client leave.
+    pub async fn resume(server: &Mutex<Server>, client: &Arc<RwLock<Client>>, config: Config, id: Uuid, token: String) {
+        if CommandHandler::auth_throttled(server, client).await {
+            return;
+        }
+
+        let zombie = match server.lock().await.get_client_ref(id).await {
+            Some(z) => z,
+            None    => {
+                let ip = client.read().await.ip();
+                server.lock().await.count_auth_failure(ip);
+                _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::InvalidAuth, None, "The session can not be resumed.".to_string()).into()).await;
+                return;
+            }
+        };
+
+        let mut zlock = zombie.write().await;
+        let expired = match zlock.zombie_since() {
+            Some(since) => since.elapsed().unwrap_or_default().as_secs() > config.resume_grace,
+            None        => true
+        };
+
+        if config.resume_grace == 0 || zlock.status() as u64 != ClientStatus::Zombie as u64 || expired || zlock.resume_token() != token {
+            std::mem::drop(zlock);
+            let ip = client.read().await.ip();
+            server.lock().await.count_auth_failure(ip);
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::InvalidAuth, None, "The session can not be resumed.".to_string()).into()).await;
+            return;
+        }
+
+        let mut c_lock = client.write().await;
+        let temp_id = c_lock.id();
+        c_lock.resume_from(&zlock);
+        c_lock.set_status(ClientStatus::Connected);
+        zlock.set_status(ClientStatus::Closing);
+        std::mem::drop(zlock);
+
+        let id = c_lock.id();
+        let name = c_lock.name();
+        let token = c_lock.resume_token();
+        let channels = c_lock.channels().await;
+        info!(client = %id, name = %name, ip = %c_lock.ip(), "resume: session resumed");
+        let _ = c_lock.sender().lock().await.send(CMessage::new(MessageType::AuthOk(id, name, token), Uuid::nil(), Uuid::nil(), None ).into()).await;
+
+        // the server is locked before any client, so let go of ours first
+        std::mem::drop(c_lock);
+        let mut resumed = Vec::new();
+        let slock = server.lock().await;
+        slock.rekey_client(temp_id, id, client.clone()).await;
+
+        for (uid, _) in channels {
+            resumed.push((uid, slock.get_channel_ref(uid).await));
+        }
+
+        std::mem::drop(slock);
+        let mut c_lock = client.write().await;
+
+        for (uid, channel) in resumed {
+            let channel = match channel {
+                Some(c) => c,
+                None    => { c_lock.remove_channel(uid).await; continue; }
+            };
+
+            let mut lock = channel.write().await;
+
+            if !lock.resume_member(c_lock.clone()) && !lock.is_member(id).await {
+                c_lock.remove_channel(uid).await;
+                continue;
+            }
+
+            Self::send_channel_state(&mut c_lock, &lock).await;
+            lock.to_log(format!("{:?} / RESUME CHANNEL: {} ({}@{})", std::time::SystemTime::now(), lock.name(), c_lock.name(), c_lock.ip())).await;
+        }
+    }
+
+    pub async fn typing(server: &Mutex<Server>, client: &RwLock<Client>, target: Uuid) {
+        let slock = server.lock().await;
+        let channel = match slock.get_channel_ref(target).await {
+            Some(rchan)   => rchan,
+            None        => return
+        };
+        let hidden_msg = channel.read().await.has_option(ChannelOptions::HiddenMessages);
+        let hidden_members = channel.read().await.has_option(ChannelOptions::HiddenMemberList);
+        let clientid = client.read().await.id();
+
+        if hidden_msg || hidden_members {
+            let _ = channel.write().await.sendto_agents(&CMessage::new(MessageType::Typing(clientid, target), clientid, target, None).into()).await;
+            return;
+        } else {
+            let _ = channel.write().await.sendto_all_butone(clientid, &CMessage::new(MessageType::Typing(clientid, target), clientid, target, None).into()).await;
+            return;
+        }
+        // if !let Some(channel) = match slock.
+    }
+
+    /// Broadcast a message to every connected client. Requires the Admin flag.
+    pub async fn wall(server: &Mutex<Server>, client: &RwLock<Client>, message: String) {
+        if !client.read().await.has_options(ClientOptions::Admin) {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "No permission to perform this action.".to_string()).into()).await;
+            return;
+        }
+
+        let message = match CommandHandler::clean_broadcast(client, message).await {
+            Some(m) => m,
+            None    => return
+        };
+
+        let clock = client.read().await;
+        let (client_id, client_name, client_ip) = (clock.id(), clock.name(), clock.ip());
+        std::mem::drop(clock);
+
+        // the gate is read under the broadcast lock, bodies stay out of the logs unless it is on
+        let (count, log_body) = {
+            let mut slock = server.lock().await;
+            (slock.sendto_wall(CMessage::new(MessageType::Wall(message.clone()), client_id, Uuid::nil(), Some(client_name.clone()))).await, slock.conf.log_message_bodies)
+        };
+        info!(client = %client_id, ip = %client_ip, reached = count, "wall");
+
+        if log_body {
+            debug!(client = %client_id, ip = %client_ip, body = %message, "wall");
+        }
+    }
+
+    /// Broadcast a message to every connected agent.
+    pub async fn wallop(server: &Mutex<Server>, client: &RwLock<Client>, message: String) {
+        if client.read().await.gettype() as u8 != ClientType::Agent as u8 {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "No permission to perform this action.".to_string()).into()).await;
+            return;
+        }
+
+        let message = match CommandHandler::clean_broadcast(client, message).await {
+            Some(m) => m,
+            None    => return
+        };
+
+        let clock = client.read().await;
+        let (client_id, client_name, client_ip) = (clock.id(), clock.name(), clock.ip());
+        std::mem::drop(clock);
+
+        let (count, log_body) = {
+            let mut slock = server.lock().await;
+            (slock.sendto_wallops(CMessage::new(MessageType::Wallop(format!("<i>{}</i>: {}", client_name, message)), client_id, Uuid::nil(), Some(client_name.clone()))).await, slock.conf.log_message_bodies)
+        };
+        info!(client = %client_id, ip = %client_ip, reached = count, "wallop");
+
+        if log_body {
+            debug!(client = %client_id, ip = %client_ip, body = %message, "wallop");
+        }
+    }
+
+    /// Broadcast a message to every connected client with the Admin flag.
+    pub async fn walladmin(server: &Mutex<Server>, client: &RwLock<Client>, message: String) {
+        let clock = client.read().await;
+        let is_admin = clock.gettype() as u8 == ClientType::Agent as u8 && clock.has_options(ClientOptions::Admin);
+        std::mem::drop(clock);
+
+        if !is_admin {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "No permission to perform this action.".to_string()).into()).await;
+            return;
+        }
+
+        let message = match CommandHandler::clean_broadcast(client, message).await {
+            Some(m) => m,
+            None    => return
+        };
+
+        let clock = client.read().await;
+        let (client_id, client_name, client_ip) = (clock.id(), clock.name(), clock.ip());
+        std::mem::drop(clock);
+
+        let (count, log_body) = {
+            let mut slock = server.lock().await;
+            (slock.sendto_walladmins(CMessage::new(MessageType::Walladmin(format!("<i>{}</i>: {}", client_name, message)), client_id, Uuid::nil(), Some(client_name.clone()))).await, slock.conf.log_message_bodies)
+        };
+        info!(client = %client_id, ip = %client_ip, reached = count, "walladmin");
+
+        if log_body {
+            debug!(client = %client_id, ip = %client_ip, body = %message, "walladmin");
+        }
+    }
+
+    /// Sanitize and length check a broadcast, telling the client when it is not valid.
+    async fn clean_broadcast(client: &RwLock<Client>, message: String) -> Option<String> {
+        let message = CMessage::sanitize_text_message(message);
+        let length = message.char_indices().count();
+
+        if length == 0 || length > 512 {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::InvalidArgument, None, "Broadcasts must be between 1 and 512 characters.".to_string()).into()).await;
+            return None;
+        }
+
+        Some(message)
+    }
+
+    /// Invite a client to a channel.
+    ///
+    /// The owner and agents may always invite. Other members need the CanInvite flag and
+    /// the channel must allow client invites. The invited client is notified and accepts
+    /// by joining the channel with its uuid.
+    pub async fn invite(server: &Mutex<Server>, client: &RwLock<Client>, channel: Uuid, target: Uuid, expires_sec: u64) {
+        let chan_ref = match server.lock().await.get_channel_ref(channel).await {
+            Some(r) => r,
+            None    => {
+                let _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::ChannelInvalid, Some(channel), "Cannot invite to non-existent channels.".to_string()).into()).await;
+                return;
+            }
+        };
+
+        let clock = client.read().await;
+        let client_id = clock.id();
+        let client_name = clock.name();
+        let is_agent = clock.gettype() as u8 == ClientType::Agent as u8;
+        let can_invite = clock.has_options(ClientOptions::CanInvite);
+        std::mem::drop(clock);
+
+        let chan_lock = chan_ref.read().await;
+        let is_owner = chan_lock.owner_id() == Some(client_id);
+        let is_member = chan_lock.is_member(client_id).await;
+        let client_invites = chan_lock.has_option(ChannelOptions::ClientInvites);
+        let agent_only = chan_lock.has_option(ChannelOptions::AgentOnly);
+        let channel_name = chan_lock.name();
+
+        if !is_agent && !is_member {
+            std::mem::drop(chan_lock);
+            let _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::NotMember, Some(channel), "Cannot invite to channels you are not a member of.".to_string()).into()).await;
+            return;
+        }
+
+        let may_invite = is_agent || is_owner || (client_invites && can_invite);
+
+        if !may_invite {
+            std::mem::drop(chan_lock);
+            let _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, Some(channel), "You have no permission to invite to this channel.".to_string()).into()).await;
+            return;
+        }
+
+        if chan_lock.is_member(target).await {
+            std::mem::drop(chan_lock);
+            let _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::AlreadyMember, Some(channel), "User is already a member of that channel.".to_string()).into()).await;
+            return;
+        }
+        std::mem::drop(chan_lock);
+
+        let target_ref = match server.lock().await.get_client_ref(target).await {
+            Some(r) => r,
+            None    => {
+                let _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::NameInvalid, Some(channel), "No matching client was found for INVITE.".to_string()).into()).await;
+                return;
+            }
+        };
+
+        if agent_only && target_ref.read().await.gettype() as u8 != ClientType::Agent as u8 {
+            let _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, Some(channel), "Channel restricted to Agents.".to_string()).into()).await;
+            return;
+        }
+
+        let expires = match SystemTime::now().checked_add(std::time::Duration::new(expires_sec, 0)) {
+            Some(expires)   => expires,
+            None            => {
+                let _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::InvalidArgument, Some(channel), "The invite expiry is too long.".to_string()).into()).await;
+                return;
+            }
+        };
+        chan_ref.write().await.add_invite(target, expires);
+
+        let cmessage = CMessage::new(MessageType::Invite(channel, target), client_id, target, Some(channel_name));
+        server.lock().await.sendto_one(target, cmessage).await;
+        chan_ref.read().await.to_log(format!("{:?} / INVITE: {} invited {}", std::time::SystemTime::now(), client_name, target_ref.read().await.name())).await;
+    }
+
+    /// Sets the presence of an agent and tells the other agents when it changes.
+    ///
+    /// `auto` is used by the idle check, so the next message from the agent sets them back.
+    pub async fn set_presence(server: &Mutex<Server>, client: &RwLock<Client>, presence: Presence, auto: bool) {
+        let mut c_lock = client.write().await;
+
+        if c_lock.gettype() as u8 != ClientType::Agent as u8 {
+            _ = c_lock.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "No permission to perform this action.".to_string()).into()).await;
+            return;
+        }
+
+        let changed = c_lock.presence().await != presence;
+        c_lock.set_presence(presence, auto).await;
+        let id = c_lock.id();
+        let name = c_lock.name();
+        std::mem::drop(c_lock);
+
+        if changed {
+            // sendto_wallops locks every agent, ourselves included, so no locks may be held here
+            server.lock().await.sendto_wallops(CMessage::new(MessageType::Presence(presence), id, Uuid::nil(), Some(name))).await;
+        }
+    }
+
+    /// Makes a private support channel for a guest and queues it for the next free agent.
+    ///
+    /// The channel waits for an agent like any WaitForAgent channel, the router picks the
+    /// agent using the configured strategy.
+    pub async fn request_help(server: &Mutex<Server>, client: &RwLock<Client>, config: &Config, question: Option<String>) {
+        if client.read().await.gettype() as u8 == ClientType::Agent as u8 {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "Agents can not request help.".to_string()).into()).await;
+            return;
+        }
+
+        let guest_id = client.read().await.id();
+
+        if let Some(channel) = server.lock().await.router.guest_channel(guest_id) {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::AlreadyMember, Some(channel), "You are already waiting for an agent.".to_string()).into()).await;
+            return;
+        }
+
+        let mut c_lock = client.write().await;
+        let mut support = Channel::new(format!("Support {}", c_lock.name()), Some(guest_id), false);
+        support.set_options(
+            ChannelOptions::WaitForAgent
+                .or(ChannelOptions::SaveHistory)
+                .or(ChannelOptions::RejoinClients)
+                .or(ChannelOptions::InviteOnly)
+                .or(ChannelOptions::Secret)
+        );
+
+        if let Some(question) = question {
+            let clean_question = CMessage::sanitize_text_message(question);
+
+            if !clean_question.is_empty() {
+                support.set_topic(Some(clean_question.chars().take(config.max_topic_length as usize).collect())).await;
+            }
+        }
+
+        support.start_waiting();
+        support.add_member(c_lock.clone()).await;
+        c_lock.add_channel(support.id(), support.name()).await;
+        Self::send_channel_state(&mut c_lock, &support).await;
+        support.to_log(format!("{:?} / CREATE SUPPORT CHANNEL: {} ({}@{})", std::time::SystemTime::now(), support.name(), c_lock.name(), c_lock.ip())).await;
+        std::mem::drop(c_lock);
+
+        let channel_id = support.id();
+        let wallop = CMessage::new(MessageType::Wallop(format!("<i>{}</i> asked for help in <i>{}</i> ({})", client.read().await.name(), support.name(), channel_id)), Uuid::nil(), Uuid::nil(), None);
+        let mut slock = server.lock().await;
+        slock.router.add_support_channel(channel_id, guest_id);
+        slock.add_channel(support).await;
+        slock.sendto_wallops(wallop).await;
+        std::mem::drop(slock);
+
+        crate::routing::route_pending(server, config).await;
+    }
+
+    /// Searches the saved history of all channels, one page at a time. Agent only.
+    pub async fn search(server: &Mutex<Server>, client: &RwLock<Client>, config: &Config, search: HistorySearch) {
+        if client.read().await.gettype() as u8 != ClientType::Agent as u8 {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "No permission to perform this action.".to_string()).into()).await;
+            return;
+        }
+
+        let store = match server.lock().await.channel_store() {
+            Some(s) => s,
+            None    => {
+                _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::NotAvailable, None, "History is not available.".to_string()).into()).await;
+                return;
+            }
+        };
+
+        let page_size = config.search_page_size.max(1);
+
+        if crate::ChannelStore::search_offset(search.page, page_size).is_none() {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::InvalidArgument, None, "Search page is out of range.".to_string()).into()).await;
+            return;
+        }
+
+        match store.search(&search, page_size).await {
+            Ok((results, more)) => {
+                _ = client.write().await.sender().lock().await.send(CMessage::new(MessageType::SearchReply(search.page, more, results), Uuid::nil(), Uuid::nil(), None).into()).await;
+            },
+            Err(e)              => {
+                error!(error = %e, "channel store: search failed");
+                _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::InvalidArgument, None, "Unable to search history.".to_string()).into()).await;
+            }
+        }
+    }
+
+    /// Sends the last `count` history entries of a channel, or those before a message id.
+    ///
+    /// Only members of the channel, or agents, may read its history. Entries of
+    /// HiddenMessages channels are only sent to agents.
+    pub async fn history(server: &Mutex<Server>, client: &RwLock<Client>, config: &Config, channel: Uuid, count: u64, before: Option<u64>) {
+        let (chan_ref, store) = {
+            let slock = server.lock().await;
+            (slock.get_channel_ref(channel).await, slock.channel_store())
+        };
+
+        let chan_ref = match chan_ref {
+            Some(c) => c,
+            None    => {
+                _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::ChannelInvalid, Some(channel), "Channel does not exist.".to_string()).into()).await;
+                return;
+            }
+        };
+
+        let store = match store {
+            Some(s) => s,
+            None    => {
+                _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::NotAvailable, Some(channel), "History is not available.".to_string()).into()).await;
+                return;
+            }
+        };
+
+        let client_id = client.read().await.id();
+        let is_agent = client.read().await.gettype() as u8 == ClientType::Agent as u8;
+
+        if !is_agent && !chan_ref.read().await.is_member(client_id).await {
+            _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::NotMember, Some(channel), "You are not a member of that channel.".to_string()).into()).await;
+            return;
+        }
+
+        if !chan_ref.read().await.has_option(ChannelOptions::SaveHistory) {
+            _ = client.write().await.sender().lock().await.send(CMessage::new(MessageType::HistoryReply(Vec::new()), Uuid::nil(), channel, None).into()).await;
+            return;
+        }
+
+        let count = count.clamp(1, config.history_max.max(1));
+
+        match store.history(channel, count, before, is_agent).await {
+            Ok(entries) => {
+                _ = client.write().await.sender().lock().await.send(CMessage::new(MessageType::HistoryReply(entries), Uuid::nil(), channel, None).into()).await;
+            },
+            Err(e)      => {
+                error!(channel = %channel, error = %e, "channel store: unable to read history");
+                _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::NotAvailable, Some(channel), "History is not available.".to_string()).into()).await;
+            }
+        }
+    }
+
+    /// Look up a client and reply with its details.
+    ///
+    /// Agents also get the ip and options of the client, and see every channel
+    /// it is in. Invisible clients can only be looked up by agents.
+    pub async fn whois(server: &Mutex<Server>, client: &RwLock<Client>, target: Uuid) {
+        let clock = client.read().await;
+        let client_id = clock.id();
+        let is_agent = clock.gettype() as u8 == ClientType::Agent as u8;
+        std::mem::drop(clock);
+
+        let target_ref = server.lock().await.get_client_ref(target).await;
+        let target_ref = match target_ref {
+            Some(r) if is_agent || !r.read().await.has_options(ClientOptions::Invisible) => r,
+            _   => {
+                let _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::NameInvalid, None, "No matching client was found for WHOIS.".to_string()).into()).await;
+                return;
+            }
+        };
+
+        let tlock = target_ref.read().await;
+        let now = SystemTime::now();
+        let connected = tlock.connected_time().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs();
+        let idle = now.duration_since(tlock.last_action()).unwrap_or_default().as_secs();
+        let mut entry = WhoisEntry {
+            id: tlock.id(),
+            name: tlock.name(),
+            r#type: tlock.gettype(),
+            connected,
+            idle,
+            channels: Vec::new(),
+            ip: None,
+            options: None,
+            presence: None
+        };
+
+        if tlock.gettype() as u8 == ClientType::Agent as u8 {
+            entry.presence = Some(tlock.presence().await);
+        }
+
+        if is_agent {
+            entry.ip = Some(tlock.ip());
+            entry.options = Some(tlock.options().bits());
+        }
+
+        let target_channels = tlock.channels().await;
+        std::mem::drop(tlock);
+
+        for (id, name) in target_channels {
+            if !is_agent {
+                let chan_ref = match server.lock().await.get_channel_ref(id).await {
+                    Some(r) => r,
+                    None    => continue
+                };
+                let chan_lock = chan_ref.read().await;
+                let hidden = chan_lock.has_option(ChannelOptions::Secret)
+                    || chan_lock.has_option(ChannelOptions::Invisible)
+                    || chan_lock.has_option(ChannelOptions::AgentOnly);
+
+                if hidden && !chan_lock.is_member(client_id).await {
+                    continue;
+                }
+            }
+
+            entry.channels.push((id, name));
+        }
+
+        let _ = client.write().await.sender().lock().await.send(CMessage::new(MessageType::WhoisReply(entry), Uuid::nil(), target, None).into()).await;
+    }
+
+    /// Send a private message from one client directly to another.
+    ///
+    /// Guests and contacts may only message agents, unless `allow_guest_private` is set.
+    pub async fn private_message(server: &Mutex<Server>, client: &RwLock<Client>, config: &Config, target: Uuid, message: String) {
+        let clock = client.read().await;
+        let client_id = clock.id();
+        let client_name = clock.name();
+        let client_ip = clock.ip();
+        let client_is_agent = clock.gettype() as u8 == ClientType::Agent as u8;
+        std::mem::drop(clock);
+
+        if target == client_id {
+            let _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::InvalidArgument, None, "You cannot private message yourself.".to_string()).into()).await;
+            return;
+        }
+
+        let target_ref = match server.lock().await.get_client_ref(target).await {
+            Some(r)     => r,
+            None        => {
+                let _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::NameInvalid, None, "No matching client was found.".to_string()).into()).await;
+                return;
+            }
+        };
+
+        let tlock = target_ref.read().await;
+        let target_name = tlock.name();
+        let target_ip = tlock.ip();
+        let target_is_agent = tlock.gettype() as u8 == ClientType::Agent as u8;
+        let target_connected = tlock.status() as u64 == ClientStatus::Connected as u64;
+        std::mem::drop(tlock);
+
+        if !target_connected {
+            let _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::NotAvailable, None, "That client is not available.".to_string()).into()).await;
+            return;
+        }
+
+        if !client_is_agent && !target_is_agent && !config.allow_guest_private {
+            let _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "You may only private message agents.".to_string()).into()).await;
+            return;
+        }
+
+        let clean_message = CMessage::sanitize_text_message(message);
+
+        if clean_message.is_empty() {
+            return;
+        }
+
+        let cmessage = CMessage::new(MessageType::Private(client_id, target, clean_message.clone()), client_id, target, Some(client_name.clone()));
+
+        if server.lock().await.sendto_one(target, cmessage).await == 0 {
+            let _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::NotAvailable, None, "Unable to deliver message.".to_string()).into()).await;
+            return;
+        }
+
+        server.lock().await.count_message();
+
+        if config.save_private_messages {
+            let logfile = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open("logs/private.log");
+
+            if let Ok(mut logfile) = logfile {
+                let log_entry = format!("{:?} / {} ({}) -> {} ({}): {}\n", std::time::SystemTime::now(), client_ip, client_name, target_ip, target_name, clean_message);
+                let _ = logfile.write(log_entry.as_bytes());
+            }
+        }
+    }
+
+    pub async fn part(server: &Mutex<Server>, client: &RwLock<Client>, channel: Uuid) {
+        if !client.read().await.has_options(ClientOptions::Admin) && !client.read().await.has_options(ClientOptions::PartChannels) {
+            let _= client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "You may not leave channels".to_string()).into()).await;
+            return;
+        } 
+        
+        let slock = server.lock().await;
+
+        let channel_ref = match slock.get_channel_ref(channel).await {
+            Some(r)   => r,
+            None        => {
+                let _= client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::ChannelInvalid, None, "Invalid channel.".to_string()).into()).await;
+                return;
+            }
+        };
+
+        if !channel_ref.read().await.is_member(client.read().await.id()).await {
+            let _= client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::NotMember, None, "Cannot leave channels you are not a member of.".to_string()).into()).await;
+            return;
+        }
+        
+        let clock = client.read().await;
+        let client_type = clock.gettype();
+        let client_id = clock.id();
+        let client_name = clock.name();
+        let client_ip = clock.ip();
+        let is_admin = clock.has_options(ClientOptions::Admin);
+        std::mem::drop(clock);
+
+        if channel_ref.read().await.has_option(ChannelOptions::CanNotLeave) && (client_type as u64 != ClientType::Agent as u64 || !is_admin) {
+            let _= client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "You may not leave this channel.".to_string()).into()).await;
+            return;
+        }
+
+        // we are good to let them leave the channel
+        let cmessage = CMessage::new(MessageType::Part(client.read().await.id(), channel, IpAddr::V4(Ipv4Addr::new(0,0,0,0))), client_id, channel, None);
+        let _ = client.write().await.sender().lock().await.send(cmessage.into()).await;
+        channel_ref.write().await.remove_member(client_id).await;
+        channel_ref.write().await.broadcast_part(client_id, client_name.clone(), client_ip).await;
+
+        let is_persist = channel_ref.read().await.has_option(ChannelOptions::Persist);
+
+        if channel_ref.read().await.count_members().await == 0 && !is_persist {
+            std::mem::drop(slock); // not mut
+            let mut slock = server.lock().await;
+            let uuid = channel_ref.read().await.id();
+
+            if channel_ref.read().await.has_option(ChannelOptions::SaveHistory) {
+                let logfile = OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(format!("logs/{}.log",channel_ref.read().await.id()));
+
+                if logfile.is_ok() {
+                    let log_entry = format!("{:?} / DESTROY: {} ({}@{})\n", std::time::SystemTime::now(), channel_ref.read().await.name(), client_name , client_ip);
+
+                    let _ = logfile.unwrap().write(log_entry.as_bytes());
+                }
+            }
+            slock.remove_channel(uuid).await;
+        } else {
+            if channel_ref.read().await.has_option(ChannelOptions::SaveHistory) {
+                let logfile = OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(format!("logs/{}.log",channel_ref.read().await.id()));
+
+                if logfile.is_ok() {
+                    let log_entry = format!("{:?} / PART: {} ({}@{})\n", std::time::SystemTime::now(), channel_ref.read().await.name(), client_name , client_ip);
+
+                    let _ = logfile.unwrap().write(log_entry.as_bytes());
+                }
+            }
+        }
+    }
+
+    /// process a join channel command. channel can be a uuid or name
+    pub async fn join(server: &Mutex<Server>, client: &RwLock<Client>, channel: String, is_uuid: bool) {
+        let mut channel_ref: Option<Arc<RwLock<Channel>>> = None;
+        let mut slock = server.lock().await;
+
+        if !client.read().await.has_options(ClientOptions::Admin) && !client.read().await.has_options(ClientOptions::JoinChannels) {
+            let _= client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "You may not create new channels".to_string()).into()).await;
+            return;
+        }
+        if is_uuid {
+            if let Some(chan) = slock.get_channel_ref(Uuid::from_str(&channel).unwrap()).await {
+            channel_ref = Some(chan);
+            }
+        } else {
+            if let Some(chan_uuid) = slock.channel_name_to_uuid(channel.clone()).await {
+                if let Some(chan) = slock.get_channel_ref(chan_uuid).await {
+                    channel_ref = Some(chan);
+                }
+
+            }
+        }
+        // channel exists, treat it based on its settings
+        if channel_ref.is_some() {
+            let channel_ref = channel_ref.unwrap();
+            let cref_read = channel_ref.read().await;
+
+            if cref_read.is_member(client.read().await.id()).await {
+                let _= client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::AlreadyMember, None, "You are already a member of that channel.".to_string()).into()).await;
+                return;
+            }
+            if cref_read.has_option(ChannelOptions::AgentOnly) && client.read().await.gettype() as u64 != ClientType::Agent as u64 {
+                let _= client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::PermissionDenied, None, "Channel restricted to Agents.".to_string()).into()).await;
+                return;
+            }
+
+            if cref_read.has_option(ChannelOptions::InviteOnly) && 
+                !client.read().await.has_options(ClientOptions::Admin) &&
+                cref_read.owner_id() != Some(client.read().await.id()) &&
+                !cref_read.has_invite(client.read().await.id()) {
+                    let _= client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::InviteOnly, None, "Channel is invite only.".to_string()).into()).await;
+                    return;
+            } 
+
+            if cref_read.is_full().await && client.read().await.gettype() as u8 != ClientType::Agent as u8 {
+                let _= client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::ChannelFull, None, "Channel is full, try again later.".to_string()).into()).await;
+                return;
+            }
+
+            // client is allowed to join the channel at this point
+            std::mem::drop(cref_read);
+            let mut cref_write = channel_ref.write().await;
+
+            let mut clock = client.write().await;
+            cref_write.remove_invite(clock.id());
+            cref_write.add_member(clock.clone()).await;
+            clock.add_channel(cref_write.id(), cref_write.name()).await;
+            // cref_write.add_member(client.write().await.clone()).await;
+            let username = clock.name();
+            let userid = clock.id();
+
+            if cref_write.has_option(ChannelOptions::SaveHistory) {
+                let logfile = OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(format!("logs/{}.log",cref_write.id()));
 
                 if logfile.is_ok() {
-                    println!("logfile is ok");
                     let log_entry = format!("{:?} / JOIN: {} ({}@{})\n", std::time::SystemTime::now(), cref_write.name(), clock.name(), clock.ip());
 
                     let _ = logfile.unwrap().write(log_entry.as_bytes());
@@ -605,11 +1644,7 @@ impl CommandHandler {
 
                 // send the user list if applicable
                 if !cref_write.has_option(ChannelOptions::HiddenMemberList) || clock.gettype() as u64 == ClientType::Agent as u64 {
-                    let mut members_list: Vec<(Uuid, String)> = Vec::new();
-
-                    for (id,cl) in cref_write.get_members().await.iter() {
-                        members_list.push((id.to_owned(), cl.lock().await.name()));
-                    }
+                    let members_list = cref_write.member_list().await;
                     
                     if members_list.is_empty() {
                         let _ = clock.sender().lock().await.send(CMessage::new(MessageType::UserList(None), Uuid::nil(), cref_write.id(), None).into()).await;
@@ -620,6 +1655,19 @@ impl CommandHandler {
                 }
             }
 
+            // first agent in a waiting channel gets everything said while nobody could answer
+            if clock.gettype() as u8 == ClientType::Agent as u8 && cref_write.waiting_since().is_some() {
+                let backlog = cref_write.take_queue();
+                info!(client = %clock.id(), channel = %cref_write.id(), queued = backlog.len(), "picked up queued messages");
+
+                for queued in backlog {
+                    let _ = clock.sender().lock().await.send(queued.into()).await;
+                }
+
+                let chan_id = cref_write.id();
+                let _ = cref_write.sendto_nonagents(&CMessage::new(MessageType::QueueState(0, 0), Uuid::nil(), chan_id, None)).await;
+            }
+
         } 
         else {
             let mut clock = client.write().await;
@@ -664,7 +1712,6 @@ impl CommandHandler {
                     .open(format!("logs/{}.log",chan_creator.id()));
 
                 if logfile.is_ok() {
-                    println!("logfile is ok");
                     let log_entry = format!("{:?} / CREATE CHANNEL: {} ({}@{})\n", std::time::SystemTime::now(), chan_creator.name(), clock.name(), clock.ip());
 
                     let _ = logfile.unwrap().write(log_entry.as_bytes());
@@ -691,3 +1738,345 @@ pub enum CommandError {
     InviteOnly,     // channel is invite only 
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use futures_util::StreamExt;
+    use tokio::net::TcpStream;
+    use tokio::sync::mpsc;
+    use tokio_tungstenite::WebSocketStream;
+    use crate::client::tests::connected_client;
+    use crate::server::tests::test_config;
+
+    async fn next_message(far_end: &mut WebSocketStream<TcpStream>) -> CMessage {
+        let message = tokio::time::timeout(Duration::from_secs(5), far_end.next()).await.unwrap().unwrap().unwrap();
+        serde_json::from_str(&message.to_string()).unwrap()
+    }
+
+    /// A connected client of `kind` added to the server, with the far end of its websocket.
+    async fn online(server: &Mutex<Server>, server_tx: mpsc::UnboundedSender<CMessage>, kind: ClientType, name: &str) -> (Arc<RwLock<Client>>, WebSocketStream<TcpStream>) {
+        let (mut client, far_end) = connected_client(server_tx).await;
+        client.set_name(&name.to_string()).await;
+        client.set_type(kind);
+        client.set_status(ClientStatus::Connected);
+        let id = client.id();
+        server.lock().await.add_client(client).await;
+        (server.lock().await.get_client_ref(id).await.unwrap(), far_end)
+    }
+
+    async fn zombie_and_new_connection(server: &Mutex<Server>, server_tx: mpsc::UnboundedSender<CMessage>) -> (Uuid, String, Arc<RwLock<Client>>, WebSocketStream<TcpStream>) {
+        let (mut zombie, _) = connected_client(server_tx.clone()).await;
+        zombie.set_name(&"Guest-1".to_string()).await;
+        zombie.set_type(ClientType::Guest);
+        zombie.set_status(ClientStatus::Zombie);
+        zombie.set_zombie_since(Some(SystemTime::now()));
+        let (zombie_id, token) = (zombie.id(), zombie.resume_token());
+        server.lock().await.add_client(zombie).await;
+
+        let (fresh, far_end) = connected_client(server_tx).await;
+        let fresh_id = fresh.id();
+        server.lock().await.add_client(fresh).await;
+        let fresh = server.lock().await.get_client_ref(fresh_id).await.unwrap();
+        (zombie_id, token, fresh, far_end)
+    }
+
+    #[tokio::test]
+    async fn resume_takes_over_the_zombie_session() {
+        let (server_tx, server_rx) = mpsc::unbounded_channel();
+        let config = test_config();
+        let server = Mutex::new(Server::new(server_rx, config.clone()));
+        let (zombie_id, token, fresh, mut far_end) = zombie_and_new_connection(&server, server_tx).await;
+        let fresh_id = fresh.read().await.id();
+
+        tokio::time::timeout(Duration::from_secs(5), CommandHandler::resume(&server, &fresh, config, zombie_id, token.clone()))
+            .await
+            .expect("resume did not finish");
+
+        match next_message(&mut far_end).await.r#type {
+            MessageType::AuthOk(id, name, new_token) => {
+                assert_eq!(id, zombie_id);
+                assert_eq!(name, "Guest-1");
+                assert_ne!(new_token, token);
+            },
+            _   => panic!("expected AuthOk")
+        }
+
+        let resumed = server.lock().await.get_client_ref(zombie_id).await.unwrap();
+        assert!(Arc::ptr_eq(&resumed, &fresh));
+        assert_eq!(resumed.read().await.status() as u64, ClientStatus::Connected as u64);
+        assert!(!server.lock().await.client_exists(fresh_id).await);
+    }
+
+    #[tokio::test]
+    async fn resume_refuses_a_wrong_token() {
+        let (server_tx, server_rx) = mpsc::unbounded_channel();
+        let config = test_config();
+        let server = Mutex::new(Server::new(server_rx, config.clone()));
+        let (zombie_id, _, fresh, mut far_end) = zombie_and_new_connection(&server, server_tx).await;
+
+        CommandHandler::resume(&server, &fresh, config, zombie_id, "not the token".to_string()).await;
+
+        match next_message(&mut far_end).await.r#type {
+            MessageType::Problem(ProblemCode::InvalidAuth) => (),
+            _   => panic!("expected InvalidAuth")
+        }
+
+        let zombie = server.lock().await.get_client_ref(zombie_id).await.unwrap();
+        assert_eq!(zombie.read().await.status() as u64, ClientStatus::Zombie as u64);
+    }
+
+    #[tokio::test]
+    async fn walladmin_needs_the_admin_flag() {
+        let (server_tx, server_rx) = mpsc::unbounded_channel();
+        let server = Mutex::new(Server::new(server_rx, test_config()));
+        let (mut agent, mut far_end) = connected_client(server_tx).await;
+        agent.set_type(ClientType::Agent);
+        let agent = RwLock::new(agent);
+
+        CommandHandler::walladmin(&server, &agent, "hello admins".to_string()).await;
+
+        match next_message(&mut far_end).await.r#type {
+            MessageType::Problem(ProblemCode::PermissionDenied) => (),
+            _   => panic!("expected PermissionDenied")
+        }
+    }
+
+    #[tokio::test]
+    async fn private_messages_reach_agents_only() {
+        let (server_tx, server_rx) = mpsc::unbounded_channel();
+        let config = Config { save_private_messages: false, ..test_config() };
+        let server = Mutex::new(Server::new(server_rx, config.clone()));
+        let (guest, mut guest_end) = online(&server, server_tx.clone(), ClientType::Guest, "Guest-1").await;
+        let (other, mut other_end) = online(&server, server_tx.clone(), ClientType::Guest, "Guest-2").await;
+        let (agent, mut agent_end) = online(&server, server_tx, ClientType::Agent, "Ray").await;
+        let (guest_id, other_id, agent_id) = (guest.read().await.id(), other.read().await.id(), agent.read().await.id());
+
+        CommandHandler::private_message(&server, &guest, &config, agent_id, "hello <b>there</b>".to_string()).await;
+        match next_message(&mut agent_end).await.r#type {
+            MessageType::Private(from, to, text) => {
+                assert_eq!((from, to), (guest_id, agent_id));
+                assert_eq!(text, CMessage::sanitize_text_message("hello <b>there</b>".to_string()));
+            },
+            _   => panic!("expected Private")
+        }
+
+        CommandHandler::private_message(&server, &guest, &config, other_id, "psst".to_string()).await;
+        match next_message(&mut guest_end).await.r#type {
+            MessageType::Problem(ProblemCode::PermissionDenied) => (),
+            _   => panic!("expected PermissionDenied")
+        }
+
+        CommandHandler::private_message(&server, &guest, &config, guest_id, "me".to_string()).await;
+        match next_message(&mut guest_end).await.r#type {
+            MessageType::Problem(ProblemCode::InvalidArgument) => (),
+            _   => panic!("expected InvalidArgument")
+        }
+
+        CommandHandler::private_message(&server, &guest, &config, Uuid::new_v4(), "anyone".to_string()).await;
+        match next_message(&mut guest_end).await.r#type {
+            MessageType::Problem(ProblemCode::NameInvalid) => (),
+            _   => panic!("expected NameInvalid")
+        }
+
+        let config = Config { allow_guest_private: true, ..config };
+        CommandHandler::private_message(&server, &guest, &config, other_id, "psst".to_string()).await;
+        match next_message(&mut other_end).await.r#type {
+            MessageType::Private(from, _, text) => assert_eq!((from, text.as_str()), (guest_id, "psst")),
+            _   => panic!("expected Private")
+        }
+    }
+
+    #[tokio::test]
+    async fn whois_shows_addresses_to_agents_only() {
+        let (server_tx, server_rx) = mpsc::unbounded_channel();
+        let server = Mutex::new(Server::new(server_rx, test_config()));
+        let (guest, mut guest_end) = online(&server, server_tx.clone(), ClientType::Guest, "Guest-1").await;
+        let (hidden, _) = online(&server, server_tx.clone(), ClientType::Guest, "Guest-2").await;
+        let (agent, mut agent_end) = online(&server, server_tx, ClientType::Agent, "Ray").await;
+        hidden.write().await.set_options_u64(ClientOptions::Invisible.bits());
+        let (guest_id, hidden_id, agent_id) = (guest.read().await.id(), hidden.read().await.id(), agent.read().await.id());
+
+        CommandHandler::whois(&server, &agent, guest_id).await;
+        match next_message(&mut agent_end).await.r#type {
+            MessageType::WhoisReply(entry) => {
+                assert_eq!((entry.id, entry.name.as_str()), (guest_id, "Guest-1"));
+                assert!(entry.ip.is_some() && entry.options.is_some());
+                assert!(entry.presence.is_none());
+            },
+            _   => panic!("expected WhoisReply")
+        }
+
+        CommandHandler::whois(&server, &guest, agent_id).await;
+        match next_message(&mut guest_end).await.r#type {
+            MessageType::WhoisReply(entry) => {
+                assert_eq!(entry.id, agent_id);
+                assert!(entry.ip.is_none() && entry.options.is_none());
+                assert!(entry.presence.is_some());
+            },
+            _   => panic!("expected WhoisReply")
+        }
+
+        CommandHandler::whois(&server, &guest, hidden_id).await;
+        match next_message(&mut guest_end).await.r#type {
+            MessageType::Problem(ProblemCode::NameInvalid) => (),
+            _   => panic!("expected NameInvalid")
+        }
+
+        CommandHandler::whois(&server, &agent, hidden_id).await;
+        assert!(matches!(next_message(&mut agent_end).await.r#type, MessageType::WhoisReply(_)));
+    }
+
+    #[tokio::test]
+    async fn invites_let_clients_into_invite_only_channels() {
+        let (server_tx, server_rx) = mpsc::unbounded_channel();
+        let server = Mutex::new(Server::new(server_rx, test_config()));
+        let (member, mut member_end) = online(&server, server_tx.clone(), ClientType::Guest, "Guest-1").await;
+        let (guest, mut guest_end) = online(&server, server_tx.clone(), ClientType::Guest, "Guest-2").await;
+        let (agent, _) = online(&server, server_tx, ClientType::Agent, "Ray").await;
+        guest.write().await.set_options_u64(ClientOptions::JoinChannels.bits());
+        let guest_id = guest.read().await.id();
+
+        let mut channel = Channel::new("Support".to_string(), None, false);
+        channel.set_options(ChannelOptions::InviteOnly.or(ChannelOptions::ClientInvites));
+        channel.add_member(member.read().await.clone()).await;
+        let channel_id = channel.id();
+        server.lock().await.add_channel(channel).await;
+        let channel = server.lock().await.get_channel_ref(channel_id).await.unwrap();
+        _ = next_message(&mut member_end).await; // the channel modes from joining
+
+        CommandHandler::join(&server, &guest, channel_id.to_string(), true).await;
+        match next_message(&mut guest_end).await.r#type {
+            MessageType::Problem(ProblemCode::InviteOnly) => (),
+            _   => panic!("expected InviteOnly")
+        }
+
+        // members need CanInvite for channels with ClientInvites
+        CommandHandler::invite(&server, &member, channel_id, guest_id, 60).await;
+        match next_message(&mut member_end).await.r#type {
+            MessageType::Problem(ProblemCode::PermissionDenied) => (),
+            _   => panic!("expected PermissionDenied")
+        }
+        assert!(!channel.read().await.has_invite(guest_id));
+
+        CommandHandler::invite(&server, &agent, channel_id, guest_id, u64::MAX).await;
+        assert!(!channel.read().await.has_invite(guest_id));
+
+        CommandHandler::invite(&server, &agent, channel_id, guest_id, 60).await;
+        match next_message(&mut guest_end).await.r#type {
+            MessageType::Invite(invited_to, invited) => assert_eq!((invited_to, invited), (channel_id, guest_id)),
+            _   => panic!("expected Invite")
+        }
+        assert!(channel.read().await.has_invite(guest_id));
+
+        CommandHandler::join(&server, &guest, channel_id.to_string(), true).await;
+        assert!(channel.read().await.is_member(guest_id).await);
+        assert!(!channel.read().await.has_invite(guest_id));
+    }
+
+    #[tokio::test]
+    async fn history_replays_to_members_without_hidden_messages() {
+        let (server_tx, server_rx) = mpsc::unbounded_channel();
+        let config = Config { history_max: 3, ..test_config() };
+        let server = Mutex::new(Server::new(server_rx, config.clone()));
+        let store = crate::store::tests::store().await;
+        let (member, mut member_end) = online(&server, server_tx.clone(), ClientType::Guest, "Guest-1").await;
+        let (outsider, mut outsider_end) = online(&server, server_tx.clone(), ClientType::Guest, "Guest-2").await;
+        let (agent, mut agent_end) = online(&server, server_tx, ClientType::Agent, "Ray").await;
+
+        let mut channel = Channel::new("Support".to_string(), None, false);
+        channel.set_options(ChannelOptions::SaveHistory);
+        let channel_id = channel.id();
+        let visible = crate::store::tests::say(&store, &channel, 4).await;
+        channel.add_option(ChannelOptions::HiddenMessages);
+        let hidden = crate::store::tests::say(&store, &channel, 1).await;
+        channel.add_member(member.read().await.clone()).await;
+        _ = next_message(&mut member_end).await; // the channel modes from joining
+        server.lock().await.set_channel_store(store);
+        server.lock().await.add_channel(channel).await;
+
+        let replayed = |message: CMessage| match message.r#type {
+            MessageType::HistoryReply(entries) => entries.iter().map(|e| e.id).collect::<Vec<u64>>(),
+            _   => panic!("expected HistoryReply")
+        };
+
+        // capped at history_max, oldest first
+        CommandHandler::history(&server, &member, &config, channel_id, 50, None).await;
+        assert_eq!(replayed(next_message(&mut member_end).await), visible[1..4].to_vec());
+
+        CommandHandler::history(&server, &member, &config, channel_id, 2, Some(visible[2])).await;
+        assert_eq!(replayed(next_message(&mut member_end).await), visible[0..2].to_vec());
+
+        CommandHandler::history(&server, &agent, &config, channel_id, 2, None).await;
+        assert_eq!(replayed(next_message(&mut agent_end).await), vec![visible[3], hidden[0]]);
+
+        CommandHandler::history(&server, &outsider, &config, channel_id, 2, None).await;
+        match next_message(&mut outsider_end).await.r#type {
+            MessageType::Problem(ProblemCode::NotMember) => (),
+            _   => panic!("expected NotMember")
+        }
+    }
+
+    #[tokio::test]
+    async fn presence_changes_are_told_to_agents() {
+        let (server_tx, server_rx) = mpsc::unbounded_channel();
+        let server = Mutex::new(Server::new(server_rx, test_config()));
+        let (guest, mut guest_end) = online(&server, server_tx.clone(), ClientType::Guest, "Guest-1").await;
+        let (agent, _) = online(&server, server_tx.clone(), ClientType::Agent, "Ray").await;
+        let (_, mut other_end) = online(&server, server_tx, ClientType::Agent, "Kim").await;
+        let agent_id = agent.read().await.id();
+
+        CommandHandler::set_presence(&server, &guest, Presence::Busy, false).await;
+        match next_message(&mut guest_end).await.r#type {
+            MessageType::Problem(ProblemCode::PermissionDenied) => (),
+            _   => panic!("expected PermissionDenied")
+        }
+
+        CommandHandler::set_presence(&server, &agent, Presence::Busy, false).await;
+        // unchanged, nobody is told
+        CommandHandler::set_presence(&server, &agent, Presence::Busy, false).await;
+        CommandHandler::set_presence(&server, &agent, Presence::Away, true).await;
+
+        for expected in [Presence::Busy, Presence::Away] {
+            let message = next_message(&mut other_end).await;
+            assert_eq!(message.source, agent_id);
+            match message.r#type {
+                MessageType::Presence(presence) => assert!(presence == expected),
+                _   => panic!("expected Presence")
+            }
+        }
+        assert!(agent.read().await.is_auto_away());
+        assert!(agent.read().await.presence().await == Presence::Away);
+    }
+
+    #[tokio::test]
+    async fn full_channels_turn_away_all_but_agents() {
+        let (server_tx, server_rx) = mpsc::unbounded_channel();
+        let server = Mutex::new(Server::new(server_rx, test_config()));
+        let (member, _) = online(&server, server_tx.clone(), ClientType::Guest, "Guest-1").await;
+        let (guest, mut guest_end) = online(&server, server_tx.clone(), ClientType::Guest, "Guest-2").await;
+        let (agent, _) = online(&server, server_tx, ClientType::Agent, "Ray").await;
+        guest.write().await.set_options_u64(ClientOptions::JoinChannels.bits());
+        agent.write().await.set_options_u64(ClientOptions::JoinChannels.bits());
+        let (guest_id, agent_id) = (guest.read().await.id(), agent.read().await.id());
+
+        let mut channel = Channel::new("Support".to_string(), None, false);
+        channel.set_options(ChannelOptions::None);
+        channel.set_member_limit(1);
+        channel.add_member(member.read().await.clone()).await;
+        let channel_id = channel.id();
+        server.lock().await.add_channel(channel).await;
+        let channel = server.lock().await.get_channel_ref(channel_id).await.unwrap();
+
+        CommandHandler::join(&server, &guest, channel_id.to_string(), true).await;
+        match next_message(&mut guest_end).await.r#type {
+            MessageType::Problem(ProblemCode::ChannelFull) => (),
+            _   => panic!("expected ChannelFull")
+        }
+        assert!(!channel.read().await.is_member(guest_id).await);
+
+        CommandHandler::join(&server, &agent, channel_id.to_string(), true).await;
+        assert!(channel.read().await.is_member(agent_id).await);
+    }
+}
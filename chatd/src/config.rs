@@ -1,7 +1,12 @@
 use std::{fs, net::{IpAddr, Ipv4Addr}};
+use std::collections::HashMap;
 use std::error::Error;
 
 use serde::{Serialize, Deserialize};
+use crate::routing::RoutingStrategy;
+
+/// Salt every install used before auth_salt was generated, older hashes may still need it.
+pub const LEGACY_AUTH_SALT: &str = "5805fde87d8cbf0de22c396419f10bfa652f3e95225b50f1d446e1b225db4745";
 
 #[derive(Clone, Serialize, Deserialize)]
 pub enum AuthType {
@@ -9,19 +14,75 @@ pub enum AuthType {
     SqLite(String)    // String is a path to a .auth file that will be created.
 }
 
+/// An address to accept websocket connections on.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ListenerConfig {
+    pub address: IpAddr,
+    pub port: u16,
+    pub tls: bool, // false serves plain ws, only for a tls terminating proxy on localhost
+    pub dual_stack: bool, // an IPv6 address also accepts IPv4 connections
+    pub forwarded_for: bool, // take the client ip from the proxy's X-Forwarded-For header
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        ListenerConfig { address: IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port: 1300, tls: true, dual_stack: false, forwarded_for: false }
+    }
+}
+
+/// Format of the server log written by tracing.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// How long and how large channel log files may grow. 0 disables a limit.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogRetention {
+    pub max_age_days: u64, // delete logs not written to in this many days
+    pub max_size: u64, // rotate a log once it is larger than this many bytes
+}
+
+impl Default for LogRetention {
+    fn default() -> Self {
+        LogRetention { max_age_days: 90, max_size: 10 * 1024 * 1024 }
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub bind_address: IpAddr,
     pub ssl_certificate: String, // path to ssl certificate
     pub ssl_privatekey: String, // path to ssl private key
     pub bind_port: u16,
+    pub listeners: Vec<ListenerConfig>, // replaces the tls listener on bind_address:bind_port when not empty
+    pub metrics_enabled: bool, // serve prometheus metrics over plain http
+    pub metrics_address: IpAddr, // keep this private, metrics have no auth
+    pub metrics_port: u16,
+    pub admin_enabled: bool, // serve the admin json api over plain http
+    pub admin_address: IpAddr, // keep this private
+    pub admin_port: u16,
+    pub admin_api_key: String, // bearer key for the admin api, empty allows only agent credentials
     pub auth_type: AuthType,
-    pub auth_salt: String,
+    pub auth_salt: String, // legacy password and device hash salt, random for configs made on first run
+    pub auth_max_failures: u64, // failed logins from one ip before it is refused for a while, 0 disables
+    pub auth_failure_window: u64, // seconds failed logins are counted over
+    pub invent_timeout: u64, // seconds before an Invent directory request is abandoned
+    pub invent_cache_ttl: u64, // seconds Invent directory replies are cached, 0 disables
+    pub invent_roles: HashMap<String, u64>, // Invent directory role to ClientOptions bits
     pub allow_clients: bool, // allow customer access
     pub allow_guests: bool,  // allow guests (ie pub website)
     pub default_guest_options: u64,
     pub default_agent_options: u64,
+    pub default_client_options: u64, // options for contacts logged in with a registered device
     pub ban_db: String, // file to store bans
+    pub kline_min_prefix_v4: u8, // shortest IPv4 prefix a K-line may cover
+    pub kline_min_prefix_v6: u8, // shortest IPv6 prefix a K-line may cover
+    pub channel_db: String, // sqlite file to store persistent channels
     pub motd_file_guests: String,
     pub motd_file_clients: String,
     pub motd_file_agents: String,
@@ -29,11 +90,36 @@ pub struct Config {
     pub use_staff_lobby: bool,  // use global lobby for all agents
     pub use_guest_lobby: bool,  // use global lobby for all guests 
     pub max_topic_length: u16,
-    
+    pub allow_guest_private: bool, // allow guests/contacts to private message each other
+    pub save_private_messages: bool, // log private messages to logs/private.log
+    pub invite_expiry: u64, // seconds a channel invite stays valid
+    pub resume_grace: u64, // seconds a lost connection can be resumed, 0 disables
+    pub wait_queue_max: u64, // messages kept per WaitForAgent channel until an agent joins
+    pub routing_strategy: RoutingStrategy, // how guests asking for help are given to agents
+    pub agent_capacity: u64, // most support channels routed to one agent, 0 is unlimited
+    pub auto_away: u64, // seconds of agent inactivity before presence turns Away, 0 disables
+    pub flood_rate: f64, // messages a second a client may keep sending, 0 disables flood protection
+    pub flood_burst: u64, // messages a client may send at once before flood_rate applies
+    pub flood_kline_strikes: u64, // refused messages in an AntiFlood channel before a kline
+    pub flood_kline_duration: u64, // seconds a flood kline lasts
+    pub slow_mode_interval: u64, // seconds between messages from non agents in Throttle channels
+    pub limit_step: u64, // members the Limit mode lets in per limit_interval
+    pub limit_interval: u64, // seconds between Limit mode raises
+    pub history_max: u64, // most history entries sent for one History request
+    pub search_page_size: u64, // results per page of an agent history Search
+    pub log_retention: LogRetention, // applies to every file in logs/
+    pub log_retention_overrides: HashMap<String, LogRetention>, // by channel uuid, or "private"
+    pub log_compress: bool, // gzip rotated logs
+    pub log_check_interval: u64, // seconds between log retention checks
+    pub log_level: String, // error, warn, info, debug or trace, RUST_LOG overrides it
+    pub log_format: LogFormat,
+    pub log_file: Option<String>, // server log file, written daily as <log_file>.YYYY-MM-DD, None logs to stdout
+    pub log_message_bodies: bool, // include message text in debug logs
 }
 impl Config {
+    /// A config for a fresh install, with its own random auth_salt.
     pub fn new() -> Self {
-        Config { ..Default::default()}
+        Config { auth_salt: (0..32).map(|_| format!("{:02x}", rand::random::<u8>())).collect(), ..Default::default()}
     }
 
     pub fn from_disk(filename: &str) -> Result<Config, Box<dyn Error>> {
@@ -52,15 +138,35 @@ impl Default for Config {
         Config {
             bind_address: IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
             bind_port: 1300,
+            listeners: Vec::new(),
+            metrics_enabled: false,
+            metrics_address: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            metrics_port: 9300,
+            admin_enabled: false,
+            admin_address: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            admin_port: 9301,
+            admin_api_key: String::new(),
             ssl_certificate: "/path/to/ssl_certs/cert.pem".to_string(),
             ssl_privatekey: "/path/to/ssl_certs/privkey.pem".to_string(),
             auth_type: AuthType::SqLite("chatd.auth".to_string()),
-            auth_salt: "5805fde87d8cbf0de22c396419f10bfa652f3e95225b50f1d446e1b225db4745".to_string(),
+            auth_salt: LEGACY_AUTH_SALT.to_string(),
+            auth_max_failures: 10,
+            auth_failure_window: 300,
+            invent_timeout: 5,
+            invent_cache_ttl: 300,
+            invent_roles: HashMap::from([
+                ("agent".to_string(), 60u64),
+                ("admin".to_string(), 62u64),
+            ]),
             allow_clients: true,
             allow_guests: true,
             default_guest_options: 12u64, // 12u64
             default_agent_options: 62u64,
+            default_client_options: 12u64,
             ban_db: "bans.db".to_string(),
+            kline_min_prefix_v4: 8,
+            kline_min_prefix_v6: 32,
+            channel_db: "channels.db".to_string(),
             motd_file_guests: "guest.motd".to_string(),
             motd_file_clients: "client.motd".to_string(),
             motd_file_agents: "agent.motd".to_string(),
@@ -68,6 +174,31 @@ impl Default for Config {
             use_guest_lobby: true,
             use_global_lobby: true,
             max_topic_length: 128,
+            allow_guest_private: false,
+            save_private_messages: true,
+            invite_expiry: 300,
+            resume_grace: 60,
+            wait_queue_max: 200,
+            routing_strategy: RoutingStrategy::LeastLoaded,
+            agent_capacity: 3,
+            auto_away: 600,
+            flood_rate: 1.0,
+            flood_burst: 5,
+            flood_kline_strikes: 20,
+            flood_kline_duration: 3600,
+            slow_mode_interval: 30,
+            limit_step: 5,
+            limit_interval: 30,
+            history_max: 100,
+            search_page_size: 50,
+            log_retention: LogRetention::default(),
+            log_retention_overrides: HashMap::new(),
+            log_compress: true,
+            log_check_interval: 3600,
+            log_level: "info".to_string(),
+            log_format: LogFormat::Text,
+            log_file: None,
+            log_message_bodies: false,
         }
     }
 }
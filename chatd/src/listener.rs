@@ -0,0 +1,100 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use socket2::{Domain, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::server::TlsStream;
+use crate::config::ListenerConfig;
+
+/// A client socket, tls or plaintext from a listener behind a tls terminating proxy.
+pub enum Connection {
+    Tls(Box<TlsStream<TcpStream>>),
+    Plain(TcpStream),
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tls(stream)     => Pin::new(stream).poll_read(cx, buf),
+            Connection::Plain(stream)   => Pin::new(stream).poll_read(cx, buf)
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Tls(stream)     => Pin::new(stream).poll_write(cx, buf),
+            Connection::Plain(stream)   => Pin::new(stream).poll_write(cx, buf)
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tls(stream)     => Pin::new(stream).poll_flush(cx),
+            Connection::Plain(stream)   => Pin::new(stream).poll_flush(cx)
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tls(stream)     => Pin::new(stream).poll_shutdown(cx),
+            Connection::Plain(stream)   => Pin::new(stream).poll_shutdown(cx)
+        }
+    }
+}
+
+/// Bind a listener, IPv6 addresses only take IPv4 connections as well when `dual_stack` is set.
+///
+/// IPv6 listeners are v6 only by default so `0.0.0.0` and `::` can listen on the same port.
+pub fn bind(settings: &ListenerConfig) -> io::Result<TcpListener> {
+    let address = SocketAddr::new(settings.address, settings.port);
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+
+    if address.is_ipv6() {
+        socket.set_only_v6(!settings.dual_stack)?;
+    }
+
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    fn settings(address: IpAddr, port: u16, dual_stack: bool) -> ListenerConfig {
+        ListenerConfig { address, port, dual_stack, ..ListenerConfig::default() }
+    }
+
+    #[tokio::test]
+    async fn v4_and_v6_listeners_share_a_port() {
+        let v6 = bind(&settings(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0, false)).unwrap();
+        let port = v6.local_addr().unwrap().port();
+        let v4 = bind(&settings(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port, false)).unwrap();
+
+        let (connected, accepted) = tokio::join!(TcpStream::connect((Ipv4Addr::LOCALHOST, port)), v4.accept());
+        connected.unwrap();
+        assert!(accepted.unwrap().1.is_ipv4());
+    }
+
+    #[tokio::test]
+    async fn dual_stack_listeners_take_v4_connections() {
+        let listener = bind(&settings(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0, true)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // the v4 port is taken by the dual stack listener
+        assert!(bind(&settings(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port, false)).is_err());
+
+        let (connected, accepted) = tokio::join!(TcpStream::connect((Ipv4Addr::LOCALHOST, port)), listener.accept());
+        connected.unwrap();
+        let (_, peer) = accepted.unwrap();
+        assert_eq!(peer.ip().to_canonical(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+    }
+}
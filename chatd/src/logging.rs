@@ -0,0 +1,89 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::EnvFilter;
+use crate::config::{Config, LogFormat};
+
+/// Start the server log described by `config`.
+///
+/// RUST_LOG takes precedence over `log_level` when it is set. The returned guard flushes the log
+/// file writer when it is dropped, keep it alive for as long as the server runs.
+pub fn init(config: &Config) -> WorkerGuard {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| level_filter(&config.log_level));
+
+    let (writer, guard) = match &config.log_file {
+        Some(file) => {
+            let (directory, prefix) = log_file_parts(file);
+            tracing_appender::non_blocking(tracing_appender::rolling::daily(directory, prefix))
+        },
+        None => tracing_appender::non_blocking(std::io::stdout())
+    };
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(config.log_file.is_none());
+
+    match config.log_format {
+        LogFormat::Text     => builder.init(),
+        LogFormat::Json     => builder.json().init()
+    }
+
+    guard
+}
+
+/// The filter for a `log_level`, info when it does not parse.
+fn level_filter(level: &str) -> EnvFilter {
+    EnvFilter::try_new(level).unwrap_or_else(|_| EnvFilter::new("info"))
+}
+
+/// Directory and file name prefix of the daily log files for `log_file`.
+fn log_file_parts(file: &str) -> (PathBuf, OsString) {
+    let path = Path::new(file);
+    let directory = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let prefix = path.file_name().map(|f| f.to_os_string()).unwrap_or_else(|| "chatd.log".into());
+    (directory.to_path_buf(), prefix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::tests::test_config;
+    use crate::Uuid;
+
+    #[test]
+    fn log_levels_fall_back_to_info() {
+        assert_eq!(level_filter("debug").to_string(), "debug");
+        assert_eq!(level_filter("chatd=trace,warn").to_string(), "chatd=trace,warn");
+        assert_eq!(level_filter("chatd=loud").to_string(), "info");
+    }
+
+    #[test]
+    fn log_files_split_into_directory_and_prefix() {
+        assert_eq!(log_file_parts("/var/log/chatd/server.log"), (PathBuf::from("/var/log/chatd"), OsString::from("server.log")));
+        assert_eq!(log_file_parts("server.log"), (PathBuf::from("."), OsString::from("server.log")));
+        assert_eq!(log_file_parts("/"), (PathBuf::from("."), OsString::from("chatd.log")));
+    }
+
+    // the only test that installs the global subscriber, it can be set once per process
+    #[test]
+    fn init_writes_json_lines_to_the_log_file() {
+        let directory = std::env::temp_dir().join(format!("chatd-test-log-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let config = Config { log_file: Some(directory.join("server.log").to_string_lossy().to_string()), log_format: LogFormat::Json, ..test_config() };
+
+        let guard = init(&config);
+        tracing::error!(marker = "logging test", "written to the log file");
+        drop(guard);
+
+        let written: Vec<std::fs::DirEntry> = std::fs::read_dir(&directory).unwrap().map(|e| e.unwrap()).collect();
+        assert_eq!(written.len(), 1);
+        assert!(written[0].file_name().to_string_lossy().starts_with("server.log."));
+
+        // other tests running at the same time log here too
+        let lines = std::fs::read_to_string(written[0].path()).unwrap();
+        let events: Vec<serde_json::Value> = lines.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert!(events.iter().any(|event| event["fields"]["marker"] == "logging test"));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,211 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use flate2::{write::GzEncoder, Compression};
+use tracing::error;
+use crate::config::{Config, LogRetention};
+use crate::store::ChannelStore;
+use crate::Uuid;
+
+pub const LOG_DIR: &str = "logs";
+
+/// Create the log directory if it is missing, channel logs fail silently without it.
+pub fn create_log_dir() -> io::Result<()> {
+    fs::create_dir_all(LOG_DIR)
+}
+
+/// Rotate, compress and expire the files in the log directory.
+///
+/// Active logs (`{name}.log`) larger than the max size are renamed to `{name}.{unix time}.log`
+/// and gzipped when enabled. Any log, active or rotated, not written to within the max age
+/// is deleted. Returns how many files were rotated and deleted.
+pub fn check_logs(config: &Config) -> io::Result<(u64, u64)> {
+    check_log_dir(config, Path::new(LOG_DIR))
+}
+
+fn check_log_dir(config: &Config, directory: &Path) -> io::Result<(u64, u64)> {
+    let mut rotated = 0u64;
+    let mut deleted = 0u64;
+
+    for entry in fs::read_dir(directory)? {
+        let path = match entry {
+            Ok(entry)   => entry.path(),
+            Err(e)      => {
+                error!(error = %e, "logs: unable to read directory entry");
+                continue;
+            }
+        };
+        let file_name = match path.file_name().and_then(|f| f.to_str()) {
+            Some(f) => f.to_string(),
+            None    => continue
+        };
+
+        if !path.is_file() || !(file_name.ends_with(".log") || file_name.ends_with(".log.gz")) {
+            continue;
+        }
+
+        // channel logs are {uuid}.log, rotated ones {uuid}.{time}.log[.gz]
+        let log_name = file_name.split('.').next().unwrap_or_default();
+        let retention = retention_for(config, log_name);
+        // the file can vanish or be unreadable, that should not stop the sweep
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata)    => metadata,
+            Err(e)          => {
+                error!(file = %file_name, error = %e, "logs: unable to read metadata");
+                continue;
+            }
+        };
+        let modified = metadata.modified().unwrap_or(SystemTime::now());
+
+        if retention.max_age_days > 0 && modified.elapsed().unwrap_or_default() > Duration::from_secs(retention.max_age_days * 86400) {
+            match fs::remove_file(&path) {
+                Ok(_)   => deleted += 1,
+                Err(e)  => error!(file = %file_name, error = %e, "logs: unable to delete")
+            }
+            continue;
+        }
+
+        let is_active = file_name == format!("{}.log", log_name);
+
+        if is_active && retention.max_size > 0 && metadata.len() > retention.max_size {
+            match rotate(directory, &path, log_name, config.log_compress) {
+                Ok(_)   => rotated += 1,
+                Err(e)  => error!(file = %file_name, error = %e, "logs: unable to rotate")
+            }
+        }
+    }
+
+    Ok((rotated, deleted))
+}
+
+/// Delete channel history in the store older than the max age of its channel's retention.
+///
+/// Channels with an override use its max age, every other channel the default one. A max age
+/// of 0 keeps history forever. Returns how many entries were deleted.
+pub async fn prune_history(store: &ChannelStore, config: &Config, now: SystemTime) -> Result<u64, String> {
+    let cutoff = |retention: &LogRetention| now.checked_sub(Duration::from_secs(retention.max_age_days.saturating_mul(86400)))
+        .map(|time| time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs())
+        .unwrap_or(0);
+
+    let overrides: Vec<(Uuid, &LogRetention)> = config.log_retention_overrides.iter()
+        .filter_map(|(name, retention)| Uuid::parse_str(name).ok().map(|id| (id, retention)))
+        .collect();
+    let mut deleted = 0u64;
+
+    for (id, retention) in overrides.iter() {
+        if retention.max_age_days > 0 {
+            deleted += store.delete_history_before(cutoff(retention), Some(*id), &[]).await?;
+        }
+    }
+
+    if config.log_retention.max_age_days > 0 {
+        let except: Vec<Uuid> = overrides.iter().map(|(id, _)| *id).collect();
+        deleted += store.delete_history_before(cutoff(&config.log_retention), None, &except).await?;
+    }
+
+    Ok(deleted)
+}
+
+fn retention_for<'a>(config: &'a Config, log_name: &str) -> &'a LogRetention {
+    config.log_retention_overrides.get(log_name).unwrap_or(&config.log_retention)
+}
+
+/// Move an active log out of the way, writers reopen the file for every line so
+/// the next line starts a fresh log.
+fn rotate(directory: &Path, path: &Path, log_name: &str, compress: bool) -> io::Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let rotated = directory.join(format!("{}.{}.log", log_name, now));
+    fs::rename(path, &rotated)?;
+
+    if !compress {
+        return Ok(());
+    }
+
+    let gz_path = rotated.with_extension("log.gz");
+    let mut encoder = GzEncoder::new(File::create(&gz_path)?, Compression::default());
+    io::copy(&mut File::open(&rotated)?, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(&rotated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::Channel;
+    use crate::server::tests::test_config;
+    use crate::store::tests::{say, store};
+
+    /// A fresh log directory with `{name}` files last written `age_days` ago.
+    fn log_dir(files: &[(&str, u64)]) -> std::path::PathBuf {
+        let directory = std::env::temp_dir().join(format!("chatd-test-logs-{}", Uuid::new_v4()));
+        fs::create_dir_all(&directory).unwrap();
+
+        for (name, age_days) in files {
+            let file = File::create(directory.join(name)).unwrap();
+            file.set_modified(SystemTime::now() - Duration::from_secs(age_days * 86400)).unwrap();
+        }
+        directory
+    }
+
+    #[test]
+    fn old_logs_are_deleted_by_their_retention() {
+        let kept = Uuid::new_v4();
+        let directory = log_dir(&[
+            ("private.log", 91),
+            ("private.1700000000.log.gz", 120),
+            ("recent.log", 89),
+            (&format!("{}.log", kept), 400),
+            ("notes.txt", 400),
+        ]);
+        let mut config = test_config();
+        config.log_retention_overrides.insert(kept.to_string(), LogRetention { max_age_days: 0, ..LogRetention::default() });
+
+        assert_eq!(check_log_dir(&config, &directory).unwrap(), (0, 2));
+
+        let mut left: Vec<String> = fs::read_dir(&directory).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().to_string()).collect();
+        left.sort();
+        let mut expected = vec![format!("{}.log", kept), "notes.txt".to_string(), "recent.log".to_string()];
+        expected.sort();
+        assert_eq!(left, expected);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn large_logs_are_rotated_and_compressed() {
+        let directory = log_dir(&[]);
+        fs::write(directory.join("private.log"), vec![b'a'; 2048]).unwrap();
+        let config = Config { log_retention: LogRetention { max_size: 1024, ..LogRetention::default() }, log_compress: true, ..test_config() };
+
+        assert_eq!(check_log_dir(&config, &directory).unwrap(), (1, 0));
+
+        let left: Vec<String> = fs::read_dir(&directory).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().to_string()).collect();
+        assert_eq!(left.len(), 1);
+        assert!(left[0].starts_with("private.") && left[0].ends_with(".log.gz"));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn prune_history_follows_retention() {
+        let store = store().await;
+        let support = Channel::new("Support".to_string(), None, false);
+        let sales = Channel::new("Sales".to_string(), None, false);
+        say(&store, &support, 3).await;
+        say(&store, &sales, 3).await;
+
+        let mut config = test_config();
+        config.log_retention_overrides.insert(sales.id().to_string(), LogRetention { max_age_days: 0, ..LogRetention::default() });
+
+        // nothing is older than the default 90 days yet
+        assert_eq!(prune_history(&store, &config, SystemTime::now()).await, Ok(0));
+
+        let later = SystemTime::now() + Duration::from_secs(91 * 86400);
+        assert_eq!(prune_history(&store, &config, later).await, Ok(3));
+        assert!(store.history(support.id(), 10, None, true).await.unwrap().is_empty());
+        assert_eq!(store.history(sales.id(), 10, None, true).await.unwrap().len(), 3);
+
+        config.log_retention_overrides.insert(sales.id().to_string(), LogRetention { max_age_days: 30, ..LogRetention::default() });
+        assert_eq!(prune_history(&store, &config, later).await, Ok(3));
+        assert!(store.history(sales.id(), 10, None, true).await.unwrap().is_empty());
+    }
+}
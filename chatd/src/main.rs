@@ -6,6 +6,13 @@ mod message;
 mod commands;
 mod auth;
 mod ban;
+mod store;
+mod logs;
+mod routing;
+mod metrics;
+mod admin;
+mod listener;
+mod logging;
 use commands::CommandHandler;
 use config::*;
 use auth::*;
@@ -17,10 +24,14 @@ use channel::*;
 use message::{Message as CMessage,*};
 use uuid::Uuid;
 use ban::Ban;
+use store::ChannelStore;
+use ipnet::IpNet;
+use listener::Connection;
 
-// use tracing::{info, Level};
+use tracing::{debug, error, info, warn};
 use tokio::net::TcpListener;
-use tokio_tungstenite::accept_async;
+use tokio_tungstenite::accept_hdr_async;
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
 use tokio_tungstenite::tungstenite::protocol::Message;
 use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
 use rustls::pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
@@ -45,88 +56,86 @@ const DEFAULT_CONFIG_FILE: &str = "chatd.conf";
 #[tokio::main]
 async fn main() {
     // console_subscriber::init();
-    println!("**************************\n* InvenT chatd {} by Ray Lynk\n**************************", VERSION);
-    let config = match Config::from_disk(DEFAULT_CONFIG_FILE) {
-        Ok(config)  => { println!("ok: loaded config from {}", DEFAULT_CONFIG_FILE); config },
-        Err(e)  => { 
-            println!("unable to load config: {}  [{}]\ncreating new config", DEFAULT_CONFIG_FILE, e.to_string()); 
+    // reqwest pulls in ring next to the default aws-lc-rs, so rustls can not pick one itself
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let (config, created) = match Config::from_disk(DEFAULT_CONFIG_FILE) {
+        Ok(config)  => (config, None),
+        Err(e) if std::path::Path::new(DEFAULT_CONFIG_FILE).exists() => {
+            // never replace a config we could not read, its auth_salt checks the stored hashes
+            eprintln!("fatal: unable to load config {} [{}]", DEFAULT_CONFIG_FILE, e);
+            std::process::exit(1);
+        },
+        Err(_)      => {
             let config = Config::new();
-            if config.to_disk(DEFAULT_CONFIG_FILE).is_ok() {
-                println!("created and saved new config to {}", DEFAULT_CONFIG_FILE);
-                config
-            } else {
-                println!("unable save config to {}", DEFAULT_CONFIG_FILE);
-                config
-            }
+            let saved = config.to_disk(DEFAULT_CONFIG_FILE).map_err(|e| e.to_string());
+            (config, Some(saved))
         }
     };
+    let _log_guard = logging::init(&config);
+
+    info!(version = VERSION, "InvenT chatd by Ray Lynk");
+    match created {
+        None            => info!(file = DEFAULT_CONFIG_FILE, "loaded config"),
+        Some(Ok(()))    => info!(file = DEFAULT_CONFIG_FILE, "created and saved a new config"),
+        Some(Err(e))    => error!(file = DEFAULT_CONFIG_FILE, error = %e, "unable to save the new config")
+    }
    
-    let certificates: Vec<_> = match CertificateDer::pem_file_iter(config.ssl_certificate.to_owned()) {
-        Ok(certs)   => certs.map(|cert| cert.unwrap()).collect(),
-        Err(e)      => {
-            println!("fatal: unable to parse certificate file: {} [{}]", config.ssl_certificate.to_owned(), e);
-            std::process::exit(0);
-        }
+    // an empty listeners list keeps the single tls listener on bind_address:bind_port
+    let listeners = match config.listeners.is_empty() {
+        true    => vec![ListenerConfig { address: config.bind_address, port: config.bind_port, ..Default::default() }],
+        false   => config.listeners.clone()
     };
 
-    let privatekey = match PrivateKeyDer::from_pem_file(config.ssl_privatekey.to_owned()) {
-        Ok(key) => key,
-        Err(e)  => {
-            println!("fatal: unable to parse private key file: {} [{}]", config.ssl_privatekey.to_owned(), e);
-            std::process::exit(0);
-        }
+    let tls_acceptor = match listeners.iter().any(|settings| settings.tls) {
+        true    => Some(load_tls(&config)),
+        false   => None
     };
 
-    let tlsconfig = match ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(certificates, privatekey) {
-        Ok(conf)    => conf,
-        Err(e)      => {
-            println!("fatal: unable to build serverconfig with ssl certificates [{}]", e);
-            std::process::exit(0)
-        }
-    };
+    let mut bound = Vec::new();
 
-    let listener = match TcpListener::bind("0.0.0.0:1300").await {
-        Ok(bind)    => bind,
-        Err(e)      => {
-            println!("fatal: unable to bind to {}:{} [{}]", config.bind_address, config.bind_port, e);
-            std::process::exit(0);
+    for settings in listeners {
+        match listener::bind(&settings) {
+            Ok(bind)    => {
+                info!(address = %settings.address, port = settings.port, tls = settings.tls, dual_stack = settings.dual_stack, "listening");
+                bound.push((bind, settings));
+            },
+            Err(e)      => {
+                error!(address = %settings.address, port = settings.port, error = %e, "unable to bind");
+                std::process::exit(0);
+            }
         }
-    };
-
-    let tls_acceptor = TlsAcceptor::from(Arc::new(tlsconfig));
+    }
 
-    println!("ok: bind - {}:{} ", config.bind_address.to_owned(), config.bind_port);
     // load our auth module
     // let mut authfinder: Arc<dyn AuthFinder + 'static + Send>;
-    let authfinder: Arc<Mutex<Box<dyn AuthFinder + Send + Sync>>>;
+    let authfinder: Arc<RwLock<Box<dyn AuthFinder + Send + Sync>>>;
 
     match config.to_owned().auth_type {
         AuthType::SqLite(path)  => {
-            println!("auth-type: SQLite {}", path.clone());
+            info!(path = %path, "auth-type: SQLite");
             match AuthSqLite::new(path.clone(), config.auth_salt.to_owned()).await {
-                Ok(auth)    => { authfinder = Arc::new(Mutex::new(Box::new(auth))) },
-                Err(e)      => { println!("fatal: unable to open agent database at {}\nerror: {}", path, e); std::process::exit(0);}
+                Ok(auth)    => { authfinder = Arc::new(RwLock::new(Box::new(auth))) },
+                Err(e)      => { error!(path = %path, error = %e, "unable to open agent database"); std::process::exit(0);}
             }
         },
 
-        AuthType::Invent(_url, _apikey)   => {
-            println!("fatal: AuthType::Invent is not available to this version. Please use AuthType::SqLite");
-            std::process::exit(0);
-            // println!("auth-type: Invent: {}", url.clone());
-            // auth_sql = Box::new(AuthInvent { url, session: apikey });
+        AuthType::Invent(url, apikey)   => {
+            info!(url = %url, "auth-type: Invent");
+            match AuthInvent::new(url.clone(), apikey, config.invent_timeout, config.invent_cache_ttl, config.invent_roles.clone()) {
+                Ok(auth)    => { authfinder = Arc::new(RwLock::new(Box::new(auth))) },
+                Err(e)      => { error!(url = %url, error = %e, "unable to create Invent client"); std::process::exit(0);}
+            }
         } 
     }
 
-    authfinder.lock().await.create_tables().await;
-    let has_any = authfinder.lock().await.has_any().await;
+    authfinder.write().await.create_tables().await;
+    let has_any = authfinder.read().await.has_any().await;
 
     match has_any { 
-        Err(_)      => { println!("database: error checking for agents."); },
-        Ok(true)    => { println!("database: found agents in the database."); () },
+        Err(_)      => { error!("database: error checking for agents"); },
+        Ok(true)    => { info!("database: found agents in the database"); },
         Ok(false)   => {
-            println!("database: no agents found in database, generating...");
+            warn!("database: no agents found in database, generating...");
             let random_username = "admin";
             let random_password = rand::thread_rng().gen_range(999999..99999999).to_string();
     
@@ -136,9 +145,9 @@ async fn main() {
             //     std::process::exit(1);
             // }
 
-            match authfinder.lock().await.add(random_username, &random_password, ClientOptions::Admin).await {
-                Ok(_) => { println!("database: created admin account:\nusername: {}\npassword: {}\n\n---------------------\n", random_username, random_password); },
-                Err(e) => { println!("database: unable to create admin account: {}", e.to_string()); }
+            match authfinder.write().await.add(random_username, &random_password, ClientOptions::Admin).await {
+                Ok(_) => { warn!(username = random_username, password = %random_password, "database: created admin account"); },
+                Err(e) => { error!(error = %e, "database: unable to create admin account"); }
             }
         }
     } 
@@ -157,9 +166,13 @@ async fn main() {
     //     println!("ban exists for 192");
     //     
     // }
-    ///
     //
-    println!("ok: mpsc - rx/tx unbounded");
+    //
+
+    if config.admin_enabled {
+        tokio::spawn(admin::serve(server.clone(), authfinder.clone(), config.clone()));
+    }
+
     let receiver_handle = Arc::clone(&server);
     let config_mpsc = config.clone();
 
@@ -185,7 +198,7 @@ async fn main() {
                 let my_client = receiver_handle.lock().await.get_client_ref(cmessage.source).await;
 
                 if my_client.is_none() {
-                    println!(">>>>> serious error: command from a client that doesnt exist: {}.", cmessage.source);
+                    error!(client = %cmessage.source, "command from a client that doesnt exist");
                     continue;
                 }
 
@@ -195,7 +208,6 @@ async fn main() {
         }
     });
 
-    println!("ok: inter-channel communication reader");
 
     let health_server = server.clone();
 
@@ -207,63 +219,256 @@ async fn main() {
         }
     });
 
-    println!("create default channels:\n\t-> Global Lobby: {}\n\t-> Staff Lobby: {}\n\t-> Guest Lobby: {}", config.use_global_lobby, config.use_staff_lobby, config.use_guest_lobby);
+    let away_server = server.clone();
+    let auto_away = config.auto_away;
+
+    if auto_away > 0 {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(tokio::time::Duration::from_secs(30)).await;
+                check_idle_agents(&away_server, auto_away).await;
+            }
+        });
+    }
+
+    if config.metrics_enabled {
+        tokio::spawn(metrics::serve(server.clone(), config.metrics_address, config.metrics_port));
+    }
+
+    let ban_server = server.clone();
+
+    // expired K-lines are dropped from the list and the ban database
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
+            let mut slock = ban_server.lock().await;
+            let expired = slock.expire_bans().await;
+
+            if expired > 0 {
+                let ban_file = slock.conf.ban_db.to_owned();
+                info!(expired, "bans: expired");
+
+                if let Err(e) = slock.save_ban_db(&ban_file).await {
+                    error!(file = %ban_file, error = %e, "unable to save bans");
+                }
+            }
+        }
+    });
+
+    let limit_server = server.clone();
+    let limit_step = config.limit_step;
+    let limit_interval = config.limit_interval.max(1);
+
+    // Limit mode channels let a few more members in every interval
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(tokio::time::Duration::from_secs(limit_interval)).await;
+            raise_channel_limits(&limit_server, limit_step).await;
+        }
+    });
+
+    let routing_server = server.clone();
+    let routing_config = config.clone();
+
+    // keeps waiting guests moving to agents as they free up, and their queue place current
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+            routing::route_pending(&routing_server, &routing_config).await;
+        }
+    });
+
+    match logs::create_log_dir() {
+        Ok(_)   => debug!(directory = logs::LOG_DIR, "channel logs"),
+        Err(e)  => error!(directory = logs::LOG_DIR, error = %e, "unable to create log directory")
+    }
+
+    let log_config = config.clone();
+    let log_server = server.clone();
+
+    // log rotation touches the disk, keep it off the runtime threads
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(tokio::time::Duration::from_secs(log_config.log_check_interval.max(60))).await;
+            let check_config = log_config.clone();
+
+            match tokio::task::spawn_blocking(move || logs::check_logs(&check_config)).await {
+                Ok(Ok((0, 0)))                  => (),
+                Ok(Ok((rotated, deleted)))      => info!(rotated, deleted, "logs: retention checked"),
+                Ok(Err(e))                      => error!(directory = logs::LOG_DIR, error = %e, "logs: unable to check retention"),
+                Err(e)                          => error!(error = %e, "logs: retention task failed")
+            }
+
+            let store = log_server.lock().await.channel_store();
+
+            if let Some(store) = store {
+                match logs::prune_history(&store, &log_config, SystemTime::now()).await {
+                    Ok(0)           => (),
+                    Ok(deleted)     => info!(deleted, "logs: channel history pruned"),
+                    Err(e)          => error!(error = %e, "logs: unable to prune channel history")
+                }
+            }
+        }
+    });
+
+    let zombie_server = server.clone();
+    let resume_grace = config.resume_grace;
+    let log_message_bodies = config.log_message_bodies;
+
+    // zombies need to be reaped close to their grace period, so they get their own task
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
+            check_zombies(&zombie_server, resume_grace).await;
+        }
+    });
+
+    match ChannelStore::new(&config.channel_db).await {
+        Ok(store)   => {
+            match store.create_tables().await {
+                Ok(_)   => {
+                    server.lock().await.set_channel_store(store);
+                    info!(restored = server.lock().await.restore_channels().await, file = %config.channel_db, "channels: restored");
+                },
+                Err(e)  => error!(file = %config.channel_db, error = %e, "unable to create channel tables")
+            }
+        },
+        Err(e)      => error!(file = %config.channel_db, error = %e, "unable to open channel database, channels will not persist")
+    }
+
+    info!(global_lobby = config.use_global_lobby, staff_lobby = config.use_staff_lobby, guest_lobby = config.use_guest_lobby, "create default channels");
     { server.lock().await.create_default_channels(&config).await; }
 
-    println!("ok: channels - created default lobbys");
-    println!("ok: ready for connections");
-    println!("channels:  {}", server.lock().await.channel_count().await);
-    println!("bans loaded: {}", server.lock().await.count_bans().await);
+    let slock = server.lock().await;
+    info!(channels = slock.channel_count().await, bans = slock.count_bans().await, "ready for connections");
+    std::mem::drop(slock);
 
     // start accepting client connections
-    while let Ok((stream, ip)) = listener.accept().await {
+    let mut accepting = Vec::new();
+
+    for (bind, settings) in bound {
+        let tls_acceptor = if settings.tls { tls_acceptor.clone() } else { None };
+        accepting.push(tokio::spawn(accept_clients(bind, settings, tls_acceptor, server.clone(), server_tx.clone(), resume_grace, log_message_bodies)));
+    }
+
+    futures_util::future::join_all(accepting).await;
+}
+
+/// Build the tls acceptor from the certificate and key in the config, exits when they can not be used.
+fn load_tls(config: &Config) -> TlsAcceptor {
+    let certificates: Vec<_> = match CertificateDer::pem_file_iter(&config.ssl_certificate) {
+        Ok(certs)   => certs.map(|cert| cert.unwrap()).collect(),
+        Err(e)      => {
+            error!(file = %config.ssl_certificate, error = %e, "unable to parse certificate file");
+            std::process::exit(0);
+        }
+    };
+
+    let privatekey = match PrivateKeyDer::from_pem_file(&config.ssl_privatekey) {
+        Ok(key) => key,
+        Err(e)  => {
+            error!(file = %config.ssl_privatekey, error = %e, "unable to parse private key file");
+            std::process::exit(0);
+        }
+    };
+
+    let tlsconfig = match ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certificates, privatekey) {
+        Ok(conf)    => conf,
+        Err(e)      => {
+            error!(error = %e, "unable to build serverconfig with ssl certificates");
+            std::process::exit(0)
+        }
+    };
+
+    TlsAcceptor::from(Arc::new(tlsconfig))
+}
+
+/// Accept websocket clients on one listener, over tls when `tls_acceptor` is set.
+///
+/// Listeners with `forwarded_for` use the last X-Forwarded-For address as the client ip, which is
+/// the one added by the proxy in front of chatd, and check the ban list again once it is known.
+#[allow(clippy::result_large_err)] // the handshake callback signature is set by tungstenite
+async fn accept_clients(listener: TcpListener, settings: ListenerConfig, tls_acceptor: Option<TlsAcceptor>, server: Arc<Mutex<Server>>, server_tx: mpsc::UnboundedSender<CMessage>, resume_grace: u64, log_message_bodies: bool) {
+    loop {
+        // errors like running out of file descriptors pass, so wait a moment and keep listening
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted)    => accepted,
+            Err(e)          => {
+                warn!(port = settings.port, error = %e, "accept failed");
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
         // check the ban list
         let mut serv_lock = server.lock().await;
 
-        if serv_lock.ban_exists(ip.ip()).await {
+        if serv_lock.ban_exists(peer.ip()).await {
             serv_lock.banned_connects += 1;
-            println!("Banned({}): connection from {} refused.", serv_lock.banned_connects, ip.ip());
+            info!(ip = %peer.ip(), banned_connects = serv_lock.banned_connects, "banned connection refused");
             continue;
         }
-        
+
+        serv_lock.connections_since_start += 1;
         std::mem::drop(serv_lock);
 
         let tls_acceptor = tls_acceptor.clone();
         let server = server.clone();
         let server_tx = server_tx.clone();
+        let forwarded_for = settings.forwarded_for;
         let invalid = server.lock().await.invalid_connects;
-        let mydate = SystemTime::now();
-        println!("ts:{} -- connection attempt - {}", mydate.duration_since(UNIX_EPOCH).unwrap().as_secs(), ip);
-        let server_lock = match server.try_lock() {
-            Ok(server_lock) => server_lock,
-            Err(_)  => { println!("main thread block"); continue; }
-        };
-        let (test_channelcount, test_clientcount) = tokio::join!(server_lock.channel_count(), server_lock.client_count());
-
-        drop(server_lock);
-        println!("we have {} channels and {} clients", test_channelcount, test_clientcount);
-        // server.lock().await.invalid_connects += 1;
+        debug!(ip = %peer, port = settings.port, "connection attempt");
 
         tokio::task::spawn(async move {
-            let tls_stream = match tls_acceptor.accept(stream).await {
-                Ok(tls) => { println!("tls ok: {}", ip); tls },
-                Err(e)  => { 
-                    println!("rejected[{}]: tls failed - {}", invalid+1, e); 
-                    // server.lock().await.invalid_connects += 1;
-                    return;
-                }
+            let connection = match tls_acceptor {
+                Some(tls_acceptor)  => match tls_acceptor.accept(stream).await {
+                    Ok(tls) => Connection::Tls(Box::new(tls)),
+                    Err(e)  => {
+                        info!(ip = %peer, invalid_connects = invalid+1, error = %e, "rejected: tls failed");
+                        server.lock().await.invalid_connects += 1;
+                        return;
+                    }
+                },
+                None                => Connection::Plain(stream)
             };
-            let sock_stream = match accept_async(tls_stream).await {
-                Ok(ss)  => { println!("accepted: {} - websocket proto", ip); ss }
+
+            let mut forwarded: Option<IpAddr> = None;
+            let handshake = accept_hdr_async(connection, |request: &Request, response: Response| {
+                if forwarded_for {
+                    forwarded = request.headers().get("X-Forwarded-For")
+                        .and_then(|header| header.to_str().ok())
+                        .and_then(|header| header.rsplit(',').next())
+                        .and_then(|last| IpAddr::from_str(last.trim()).ok());
+                }
+                Ok(response)
+            }).await;
+
+            let sock_stream = match handshake {
+                Ok(ss)  => ss,
                 Err(e)  => {
-                    println!("rejected[{}]: {} - {}", invalid+1, ip, e);
-                    // server.lock().await.invalid_connects += 1;
+                    info!(ip = %peer, invalid_connects = invalid+1, error = %e, "rejected: websocket failed");
+                    server.lock().await.invalid_connects += 1;
                     return;
                 }
             };
 
+            let ip = forwarded.unwrap_or(peer.ip());
+            debug!(ip = %ip, peer = %peer, "accepted websocket");
+
+            if ip != peer.ip() {
+                let mut serv_lock = server.lock().await;
+
+                if serv_lock.ban_exists(ip).await {
+                    serv_lock.banned_connects += 1;
+                    info!(ip = %ip, peer = %peer, banned_connects = serv_lock.banned_connects, "banned connection refused");
+                    return;
+                }
+            }
+
             let (ws_sender, mut ws_receiver) = sock_stream.split();
-            let my_client = Client::new(ws_sender, server_tx, ip.ip(), None);
+            let my_client = Client::new(ws_sender, server_tx, ip, None);
             let my_uuid = my_client.id();
             server.lock().await.add_client(my_client).await;
 
@@ -271,28 +476,27 @@ async fn main() {
             let my_client = match server.lock().await.get_client_ref(my_uuid).await {
                 Some(mc)    => mc,
                 None        => {
-                    println!("error: unable to refind {}", ip.ip());
+                    error!(client = %my_uuid, ip = %ip, "unable to refind client");
                     return;
                 }
             };
 
-              while let Some(message) = ws_receiver.next().await {
+            while let Some(message) = ws_receiver.next().await {
                 let main_sender = my_client.read().await.main_sender.clone();
                 // we can't trust the source field from the sender, so we will remake it (if needed)
                 if message.is_err() {
-                    my_client.write().await.set_status(ClientStatus::Closing);
-                    handle_client_error(my_client.as_ref(), &server, "read error: connection reset by peer".to_string()).await;
+                    handle_client_disconnect(my_client.as_ref(), &server, resume_grace, "read error: connection reset by peer".to_string()).await;
                     return;
                 }
-                
-                // for debug
+
                 let unwrap_msg = message.unwrap();
-                println!("> {}", unwrap_msg.clone());
+                if log_message_bodies {
+                    debug!(client = %my_uuid, ip = %ip, body = %unwrap_msg, "received");
+                }
                 let cmessage = serde_json::from_str::<CMessage>(&unwrap_msg.to_string());
 
                 if cmessage.is_err() {
-                    my_client.write().await.set_status(ClientStatus::Closing);
-                    handle_client_error(my_client.as_ref(), server.borrow(), "read error: connection closed".to_string()).await;
+                    handle_client_disconnect(my_client.as_ref(), server.borrow(), resume_grace, "read error: connection closed".to_string()).await;
                     return;
                 }
 
@@ -300,10 +504,273 @@ async fn main() {
                 cmessage.source = my_client.read().await.id();
                 let _ = main_sender.send(cmessage);
             }
-           
+
+            handle_client_disconnect(my_client.as_ref(), &server, resume_grace, "connection closed".to_string()).await;
         });
     }
+}
+
+/// Handles a lost connection, keeping the client as a zombie if it may resume.
+///
+/// Channels with RejoinClients keep the zombie as a silent member, all other channels
+/// see a normal quit. Clients that never finished auth are cleaned up right away.
+async fn handle_client_disconnect(client: &RwLock<Client>, server: &Arc<Mutex<Server>>, resume_grace: u64, reason: String) {
+    let mut client_lock = client.write().await;
+
+    if resume_grace == 0 || client_lock.status() as u64 != ClientStatus::Connected as u64 {
+        client_lock.set_status(ClientStatus::Closing);
+        std::mem::drop(client_lock);
+        handle_client_error(client, server, reason).await;
+        return;
+    }
+
+    client_lock.set_status(ClientStatus::Zombie);
+    client_lock.set_zombie_since(Some(SystemTime::now()));
+    let channels = client_lock.channels().await;
+    let client_id = client_lock.id();
+    let client_name = client_lock.name();
+    let client_ip = client_lock.ip();
+    std::mem::drop(client_lock);
+    info!(client = %client_id, name = %client_name, ip = %client_ip, resume_grace, reason = %reason, "zombie: waiting for resume");
+
+    for (uid, channel_name) in channels {
+        let channel_ref = server.lock().await.get_channel_ref(uid).await;
+
+        if let Some(channel_ref) = channel_ref {
+            if channel_ref.read().await.has_option(ChannelOptions::RejoinClients) {
+                channel_ref.write().await.zombify_member(client_id).await;
+                continue;
+            }
+
+            client.write().await.remove_channel(uid).await;
+            quit_channel(server, &channel_ref, client_id, &client_name, client_ip, uid, &channel_name, &reason).await;
+        }
+    }
+}
 
+/// Applies flood protection and channel slow mode to a chat message, false when it is dropped.
+///
+/// Clients that keep flooding an AntiFlood channel are banned for `flood_kline_duration`.
+async fn check_flood(server: &Arc<Mutex<Server>>, config: &Config, client: &RwLock<Client>, cmessage: &CMessage) -> bool {
+    // the server lock is released before the client lock is taken, never the other way around
+    let channel = match cmessage.r#type {
+        MessageType::Message(_) => server.lock().await.get_channel_ref(cmessage.target).await,
+        _                       => None
+    };
+    let mut c_lock = client.write().await;
+    let is_agent = c_lock.gettype() as u8 == ClientType::Agent as u8;
+
+    if config.flood_rate > 0.0 && !c_lock.take_flood_token(config.flood_rate, config.flood_burst) {
+        let strikes = c_lock.flood_strikes();
+        let anti_flood = match &channel {
+            Some(chan)  => chan.read().await.has_option(ChannelOptions::AntiFlood),
+            None        => false
+        };
+
+        if anti_flood && !is_agent && config.flood_kline_strikes > 0 && strikes >= config.flood_kline_strikes {
+            let name = c_lock.name();
+            let ip = c_lock.ip();
+            std::mem::drop(c_lock);
+            flood_kline(server, &name, ip, config.flood_kline_duration).await;
+            return false;
+        }
+
+        // only warn once, flooders would otherwise get a problem for every message
+        if strikes == 1 {
+            _ = c_lock.sender().lock().await.send(CMessage::new_problem(ProblemCode::Flooding, Some(cmessage.target), "You are sending messages too quickly.".to_string()).into()).await;
+        }
+        return false;
+    }
+
+    if let Some(chan) = channel {
+        if !is_agent && chan.read().await.has_option(ChannelOptions::Throttle) {
+            let wait = chan.write().await.slow_mode_wait(c_lock.id(), config.slow_mode_interval);
+
+            if wait > 0 {
+                _ = c_lock.sender().lock().await.send(CMessage::new_problem(ProblemCode::Flooding, Some(cmessage.target), format!("Slow mode is on, you may send another message in {} seconds.", wait)).into()).await;
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Bans a flooding ip and disconnects every non agent client using it.
+async fn flood_kline(server: &Arc<Mutex<Server>>, name: &str, ip: IpAddr, duration: u64) {
+    let reason = "Banned (Flooding)".to_string();
+    let expires = match SystemTime::now().checked_add(std::time::Duration::from_secs(duration)) {
+        Some(expires)   => expires,
+        None            => {
+            error!(duration, "flood: flood_kline_duration is too long, no ban was added");
+            return;
+        }
+    };
+    let mut slock = server.lock().await;
+
+    if slock.add_ban(ip.to_canonical().into(), "Flooding", expires, "server").await.is_err() {
+        return; // already banned by an earlier message
+    }
+
+    let ban_file = slock.conf.ban_db.to_owned();
+    let bans = slock.bans();
+    slock.sendto_wallops(CMessage::new(MessageType::Wallop(format!("<i>{}@{}</i> was automatically banned for flooding ({} seconds)", name, ip, duration)), Uuid::nil(), Uuid::nil(), None)).await;
+    std::mem::drop(slock);
+    warn!(name = %name, ip = %ip, duration, "flood: banned");
+
+    _ = Ban::save_to_disk(&ban_file, &bans.read().await.clone());
+
+    for found in clients_in_network(server, ip.to_canonical().into()).await {
+        disconnect_client(server, &found, reason.clone()).await;
+    }
+}
+
+/// Every non agent client connected from an address in `network`.
+async fn clients_in_network(server: &Arc<Mutex<Server>>, network: IpNet) -> Vec<Arc<RwLock<Client>>> {
+    let mut found_clients: Vec<Arc<RwLock<Client>>> = Vec::new();
+    let clients = server.lock().await.get_clients().await;
+
+    if let Some(clients) = clients {
+        for check_client in clients.lock().await.values() {
+            let clock = check_client.read().await;
+
+            if network.contains(&clock.ip().to_canonical()) && clock.gettype() as u8 != ClientType::Agent as u8 {
+                found_clients.push(check_client.clone());
+            }
+        }
+    }
+    found_clients
+}
+
+/// Remove a client from the server, its channels see a Quit with `reason`.
+async fn disconnect_client(server: &Arc<Mutex<Server>>, client: &RwLock<Client>, reason: String) {
+    client.write().await.set_status(ClientStatus::Closing);
+    handle_client_error(client, server, reason.clone()).await;
+    client.write().await.clear_channel_list().await;
+    _ = client.write().await.sender().lock().await.send(CMessage::new_problem(ProblemCode::KickedFromServer, None, format!("You were kicked from the server: {}", reason)).into()).await;
+    _ = client.write().await.sender().lock().await.close().await;
+}
+
+/// Every client, connected or zombie, logged in to the agent account `username`.
+async fn clients_with_account(server: &Arc<Mutex<Server>>, username: &str) -> Vec<Arc<RwLock<Client>>> {
+    let mut found_clients: Vec<Arc<RwLock<Client>>> = Vec::new();
+    let clients = server.lock().await.get_clients().await;
+
+    if let Some(clients) = clients {
+        for check_client in clients.lock().await.values() {
+            if check_client.read().await.account().as_deref() == Some(username) {
+                found_clients.push(check_client.clone());
+            }
+        }
+    }
+    found_clients
+}
+
+/// Disconnects every session of an agent account and voids their resume tokens.
+async fn end_account_sessions(server: &Arc<Mutex<Server>>, username: &str, reason: String) {
+    for client in clients_with_account(server, username).await {
+        client.write().await.new_resume_token();
+        disconnect_client(server, &client, reason.clone()).await;
+    }
+}
+
+/// Raises the limit of every Limit mode channel to `step` more than its members.
+async fn raise_channel_limits(server: &Arc<Mutex<Server>>, step: u64) {
+    let channels = server.lock().await.get_channels().await;
+
+    for channel in channels.values() {
+        if channel.read().await.has_option(ChannelOptions::Limit) {
+            channel.write().await.raise_limit(step).await;
+        }
+    }
+}
+
+/// Sets Available agents who have not done anything for `auto_away` seconds to Away.
+async fn check_idle_agents(server: &Arc<Mutex<Server>>, auto_away: u64) {
+    let mut idle_agents: Vec<Arc<RwLock<Client>>> = Vec::new();
+
+    if let Some(clients) = server.lock().await.get_clients().await {
+        for client in clients.lock().await.values() {
+            let lock = client.read().await;
+
+            if lock.status() as u64 != ClientStatus::Connected as u64 || lock.gettype() as u8 != ClientType::Agent as u8 {
+                continue;
+            }
+
+            if lock.presence().await == Presence::Available && lock.last_active().elapsed().unwrap_or_default().as_secs() > auto_away {
+                idle_agents.push(client.clone());
+            }
+        }
+    }
+
+    for agent in idle_agents {
+        CommandHandler::set_presence(server, &agent, Presence::Away, true).await;
+    }
+}
+
+/// Removes expired zombies, sending the Quit their channels never saw.
+async fn check_zombies(server: &Arc<Mutex<Server>>, resume_grace: u64) {
+    let mut expired_clients: Vec<Arc<RwLock<Client>>> = Vec::new();
+
+    if let Some(clients) = server.lock().await.get_clients().await {
+        for client in clients.lock().await.values() {
+            let lock = client.read().await;
+
+            if lock.status() as u64 != ClientStatus::Zombie as u64 {
+                continue;
+            }
+
+            if let Some(since) = lock.zombie_since() {
+                if since.elapsed().unwrap_or_default().as_secs() > resume_grace {
+                    expired_clients.push(client.clone());
+                }
+            }
+        }
+    }
+
+    for expired in expired_clients {
+        let mut lock = expired.write().await;
+        lock.set_status(ClientStatus::Closing);
+        let client_id = lock.id();
+        let client_name = lock.name();
+        let client_ip = lock.ip();
+        let channels = lock.channels().await;
+        lock.clear_channels().await;
+        std::mem::drop(lock);
+        info!(client = %client_id, name = %client_name, ip = %client_ip, "purge: zombie did not resume");
+
+        for (uid, channel_name) in channels {
+            let channel_ref = server.lock().await.get_channel_ref(uid).await;
+
+            if let Some(channel_ref) = channel_ref {
+                channel_ref.write().await.remove_zombie(client_id);
+                quit_channel(server, &channel_ref, client_id, &client_name, client_ip, uid, &channel_name, "connection lost").await;
+            }
+        }
+
+        server.lock().await.remove_client(client_id).await;
+    }
+}
+
+/// Removes a quitting client from one channel, tells the others and destroys the channel if empty.
+#[allow(clippy::too_many_arguments)]
+async fn quit_channel(server: &Arc<Mutex<Server>>, channel_ref: &RwLock<Channel>, client_id: Uuid, client_name: &str, client_ip: IpAddr, uid: Uuid, channel_name: &str, reason: &str) {
+    channel_ref.write().await.remove_member(client_id).await;
+    let cmessage = CMessage::new(MessageType::Quit(reason.to_string()), client_id, uid, Some(client_name.to_string()));
+
+    let is_hidden = channel_ref.read().await.has_option(ChannelOptions::HiddenMemberList);
+    let is_invisible = channel_ref.read().await.has_option(ChannelOptions::Invisible);
+    let is_persist = channel_ref.read().await.has_option(ChannelOptions::Persist);
+
+    if is_hidden || is_invisible {
+        let _ = channel_ref.write().await.sendto_agents(&cmessage).await;
+    } else {
+        let _ = channel_ref.write().await.sendto_all_butone(client_id, &cmessage).await;
+    }
+
+    if channel_ref.read().await.count_members().await == 0 && !is_persist {
+        channel_ref.read().await.to_log(format!("{:?} / DESTROY CHANNEL: {} ({}@{}) [Client Error Disconnect]", std::time::SystemTime::now(), channel_name, client_name, client_ip)).await;
+        server.lock().await.remove_channel(uid).await;
+    }
 }
 
 async fn handle_client_error(client: &RwLock<Client>, server: &Arc<Mutex<Server>>, reason: String) {
@@ -318,25 +785,8 @@ async fn handle_client_error(client: &RwLock<Client>, server: &Arc<Mutex<Server>
     for (uid, channel_name) in channels {
         let channel_ref = server.lock().await.get_channel_ref(uid).await;
 
-        if channel_ref.is_some() {
-            let channel_ref = channel_ref.unwrap();
-            channel_ref.write().await.remove_member(client_id.clone()).await;
-            let cmessage = CMessage::new(MessageType::Quit(reason.clone()), client_id.clone() , uid.clone(), Some(client_name.clone()));
-            
-            let is_hidden = channel_ref.read().await.has_option(ChannelOptions::HiddenMemberList);
-            let is_invisible = channel_ref.read().await.has_option(ChannelOptions::Invisible);
-            let is_persist = channel_ref.read().await.has_option(ChannelOptions::Persist);
- 
-            if is_hidden || is_invisible {
-                let _ = channel_ref.write().await.sendto_agents(&cmessage).await;
-            } else {
-                let _ = channel_ref.write().await.sendto_all_butone(client_id, &cmessage).await;
-            }
-
-            if channel_ref.read().await.count_members().await == 0 && !is_persist {
-                channel_ref.read().await.to_log(format!("{:?} / DESTROY CHANNEL: {} ({}@{}) [Client Error Disconnect]", std::time::SystemTime::now(), channel_name, client_name, client_ip)).await;
-                server.lock().await.remove_channel(uid).await;
-            }
+        if let Some(channel_ref) = channel_ref {
+            quit_channel(server, &channel_ref, client_id, &client_name, client_ip, uid, &channel_name, &reason).await;
         }
     }
 
@@ -357,6 +807,11 @@ async fn check_client_pings(health_server: &Arc<Mutex<Server>>) {
 
             for client in clients.lock().await.values() {
                 let mut lock = client.write().await;
+
+                // zombies have no connection to ping, check_zombies reaps them
+                if lock.status() as u64 == ClientStatus::Zombie as u64 {
+                    continue;
+                }
             
                 if lock.last_action() < too_long {
                     if lock.last_ping_time() < kick_long {
@@ -364,6 +819,7 @@ async fn check_client_pings(health_server: &Arc<Mutex<Server>>) {
                         // println!("*** {}@{} has disconnected (Ping Timeout: {} seconds)", lock.name(), lock.ip(), PING_TIMEOUT);
                         let _ = lock.sender().lock().await.close().await;
                         expired_clients.push(lock.id());
+                        metrics::count_ping_timeout();
                         lock.set_status(ClientStatus::Zombie); // everything cleared,
                     }
                     else if lock.last_ping_time() < too_long {
@@ -379,7 +835,7 @@ async fn check_client_pings(health_server: &Arc<Mutex<Server>>) {
         }
     
     for expired in expired_clients {
-        println!("purge: {}", expired);
+        info!(client = %expired, "purge: ping timeout");
         let client_refs = health_server.lock().await.get_client_ref(expired).await;
 
         match client_refs {
@@ -417,14 +873,24 @@ async fn check_client_pings(health_server: &Arc<Mutex<Server>>) {
     }
 }
 
-async fn handle_client_command(server: &Arc<Mutex<Server>>, config: Config, my_client: &Arc<RwLock<Client>>, authfinder: Arc<Mutex<Box<dyn AuthFinder + Send + Sync>>>, message: CMessage) {
+async fn handle_client_command(server: &Arc<Mutex<Server>>, config: Config, my_client: &Arc<RwLock<Client>>, authfinder: Arc<RwLock<Box<dyn AuthFinder + Send + Sync>>>, message: CMessage) {
     let mut c_lock = my_client.write().await;
     c_lock.update_last_action_time();
     c_lock.update_last_ping_time();
     let cmessage = message;
+    let is_pong = matches!(cmessage.r#type, MessageType::Pong(_));
+
+    if !is_pong {
+        c_lock.update_last_active_time();
+    }
 
     // if we have no auth, we must have auth, otherwise, close with no fingerprint
     if c_lock.status() as u64 == ClientStatus::PendingAuth as u64 {
+        // nothing else can be done until the login being checked has an answer
+        if c_lock.authenticating() {
+            return;
+        }
+
         // we try to do message parsing twice, once here so we can fail quietly, and
         // once later so we can fail loudly
        match cmessage.r#type {
@@ -469,7 +935,8 @@ async fn handle_client_command(server: &Arc<Mutex<Server>>, config: Config, my_c
                 server.lock().await.guest_count += 1;
                 let id = c_lock.id();
                 let name = c_lock.name();
-                let _ = c_lock.sender().lock().await.send(CMessage::new(MessageType::AuthOk(id.to_owned(), name.to_owned()), Uuid::nil(), Uuid::nil(), None ).into()).await;
+                let token = c_lock.resume_token();
+                let _ = c_lock.sender().lock().await.send(CMessage::new(MessageType::AuthOk(id.to_owned(), name.to_owned(), token), Uuid::nil(), Uuid::nil(), None ).into()).await;
                 let motd = server.lock().await.motd_guests.clone();
                 
                 if motd.len() > 0 {
@@ -477,109 +944,75 @@ async fn handle_client_command(server: &Arc<Mutex<Server>>, config: Config, my_c
                 }
 
                 if config.use_global_lobby {
-                    if let Some(global_channel) = server.lock().await.get_channel_by_name("Global Lobby".to_string()).await {
-                        let mut lock = global_channel.write().await;
-                        println!("*** adding client {} to Global Lobby", 1+lock.count_members().await);
-                        lock.add_member(c_lock.clone()).await;
-                        c_lock.add_channel(lock.id(), lock.name()).await;
-
-                        if !lock.has_option(ChannelOptions::Invisible) || c_lock.gettype() as u8 == ClientType::Agent as u8 {
-                            let _ = c_lock.sender().lock().await.send(CMessage::new(MessageType::Join(name.to_owned(), IpAddr::V4(Ipv4Addr::new(0,0,0,0))), id.to_owned(), lock.id(), Some(lock.name())).into()).await;
-                            
-                            if !lock.has_option(ChannelOptions::HiddenMemberList) {
-                                let mut members_list: Vec<(Uuid, String)> = Vec::new();
-                                for (id,cl) in lock.get_members().await.iter() {
-                                    members_list.push((id.to_owned(), cl.lock().await.name()));
-                                }
-                                
-                                if members_list.is_empty() {
-                                    let _ = c_lock.sender().lock().await.send(CMessage::new(MessageType::UserList(None), Uuid::nil(), lock.id(), None).into()).await;
-                                } else {
-                                let _ = c_lock.sender().lock().await.send(CMessage::new(MessageType::UserList(Some(members_list)), Uuid::nil(), lock.id(), None).into()).await;
-                                }
-                            }
-
-                            if lock.topic().is_some() {
-                                let _ = c_lock.sender().lock().await.send(CMessage::new(MessageType::Topic(lock.topic().unwrap()), Uuid::nil(), lock.id(), None).into()).await;
-                            }
-                        }
-
-                        lock.to_log(format!("{:?} / JOIN CHANNEL: {} ({}@{})", std::time::SystemTime::now(), lock.name(), c_lock.name(), c_lock.ip())).await;
-
-                    }
+                    CommandHandler::join_lobby(server, &mut c_lock, "Global Lobby").await;
                 }
 
                 if config.use_guest_lobby {
-                    if let Some(global_channel) = server.lock().await.get_channel_by_name("Guest Lobby".to_string()).await {
-                            let mut lock = global_channel.write().await;
-                            println!("*** adding client {} to Guest Lobby", 1+lock.count_members().await);
-                            lock.add_member(c_lock.clone()).await;
-                            c_lock.add_channel(lock.id(), lock.name()).await;
-
-                            if !lock.has_option(ChannelOptions::Invisible) || c_lock.gettype() as u8 == ClientType::Agent as u8 {
-                                let _ = c_lock.sender().lock().await.send(CMessage::new(MessageType::Join(name.to_owned(), IpAddr::V4(Ipv4Addr::new(0,0,0,0))), id.to_owned(), lock.id(), Some(lock.name())).into()).await;
-
-                                if !lock.has_option(ChannelOptions::HiddenMemberList) {
-                                    let mut members_list: Vec<(Uuid, String)> = Vec::new();
-                                    for (id,cl) in lock.get_members().await.iter() {
-                                        members_list.push((id.to_owned(), cl.lock().await.name()));
-                                    }
-                                    
-                                    if members_list.is_empty() {
-                                        let _ = c_lock.sender().lock().await.send(CMessage::new(MessageType::UserList(None), Uuid::nil(), lock.id(), None).into()).await;
-                                    } else {
-                                    let _ = c_lock.sender().lock().await.send(CMessage::new(MessageType::UserList(Some(members_list)), Uuid::nil(), lock.id(), None).into()).await;
-                                    }
-                                }
-                                if lock.topic().is_some() {
-                                    let _ = c_lock.sender().lock().await.send(CMessage::new(MessageType::Topic(lock.topic().unwrap()), Uuid::nil(), lock.id(), None).into()).await;
-                                }
-                            }
-                        
-                        lock.to_log(format!("{:?} / JOIN CHANNEL: {} ({}@{})", std::time::SystemTime::now(), lock.name(), c_lock.name(), c_lock.ip())).await;
-
-                        }
-
+                    CommandHandler::join_lobby(server, &mut c_lock, "Guest Lobby").await;
                 }
 
                 return;
             },
             MessageType::AuthDevice(deviceid, username) => {
-
+                std::mem::drop(c_lock);
+                CommandHandler::auth_device(server.as_ref(), my_client.as_ref(), authfinder.clone(), config.clone(), deviceid, username).await;
+                return;
+            },
+            MessageType::Resume(id, token) => {
+                std::mem::drop(c_lock);
+                CommandHandler::resume(server, my_client, config.clone(), id, token).await;
+                return;
             },
             MessageType::AuthAgent(nickname, username, password) => {
+                // the password KDF is slow, so it is checked without holding up every other client
+                c_lock.set_authenticating(true);
                 std::mem::drop(c_lock);
-                CommandHandler::auth_agent(server.as_ref(), my_client.as_ref(), authfinder.clone(), config.clone(), nickname, username, password).await;
+                let (server, my_client) = (server.clone(), my_client.clone());
+
+                tokio::spawn(async move {
+                    CommandHandler::auth_agent(server.as_ref(), my_client.as_ref(), authfinder, config, nickname, username, password).await;
+                    my_client.write().await.set_authenticating(false);
+                });
                 return;
             },
             _   => {
-                println!("unsure what is");
+                info!(client = %c_lock.id(), ip = %c_lock.ip(), "rejected: expected auth");
                 // bad request, we only allow auth at this stage.
                 c_lock.set_status(ClientStatus::Closing);
                 std::mem::drop(c_lock);
+                server.lock().await.invalid_connects += 1;
                 handle_client_error(my_client.as_ref(), server, "bad request pre-auth".to_string()).await;
                 return;
             }
 
         }
-        return;
     } // end of authing
 
      // DROP LOCKS -- THIS MATCH CASE WILL BE HANDLED BY ANOTHER FUNCTION
+    let was_auto_away = c_lock.is_auto_away();
     std::mem::drop(c_lock);
 
+    // an agent the idle check set Away is back as soon as they do something
+    if was_auto_away && !is_pong {
+        CommandHandler::set_presence(server, my_client, Presence::Available, false).await;
+    }
+
+    if matches!(cmessage.r#type, MessageType::Message(_) | MessageType::Private(..)) && !check_flood(server, &config, my_client, &cmessage).await {
+        return;
+    }
+
     match cmessage.r#type {
         MessageType::SetChannelModes(modes) => {
             CommandHandler::set_channel_modes(&server, &my_client, cmessage.target, modes).await;
-            return;
+        },
+        MessageType::ChannelLimit(limit) => {
+            CommandHandler::set_channel_limit(server, my_client, cmessage.target, limit).await;
         },
         MessageType::Topic(topic) => {
             CommandHandler::set_channel_topic(&server, &my_client, cmessage.target, topic, config.max_topic_length).await;
-            return;
         },
         MessageType::Channels => {
             CommandHandler::channel_list(&server, &my_client).await;
-            return;
         },
         MessageType::Join(channel, _) => {
             if channel.len() == 0 {
@@ -587,56 +1020,170 @@ async fn handle_client_command(server: &Arc<Mutex<Server>>, config: Config, my_c
             } else {
                 CommandHandler::join(&server, &my_client, channel, false).await;
             }
-            return;
         },
         MessageType::Part(_, channel, _) => {
             CommandHandler::part(&server, &my_client, channel).await;
-            return;
         },
         MessageType::Kick(channel, userid, reason) => {
             CommandHandler::kick(&server, &my_client, channel, userid, reason).await;
-            return;
         },
         MessageType::Kill(userid, reason) => {
             CommandHandler::kill(&server, &my_client, Uuid::from_str(&userid).unwrap_or(Uuid::nil()), reason).await;
-            return;
         },
         MessageType::Kline(ip, expires_sec, reason) => {
-            CommandHandler::kline(&server, &my_client, IpAddr::V4(Ipv4Addr::from_str(&ip).unwrap_or(Ipv4Addr::new(0, 0, 0, 0))), reason, expires_sec).await;
-            return;
+            CommandHandler::kline(server, my_client, ip, reason, expires_sec).await;
+        },
+
+        MessageType::Stats => {
+            CommandHandler::stats(server, my_client).await;
+        },
+
+        MessageType::Unkline(ip) => {
+            CommandHandler::unkline(server, my_client, ip).await;
+        },
+
+        MessageType::Bans => {
+            CommandHandler::ban_list(server, my_client).await;
         },
         MessageType::Pong(_reply) => {
-            println!("PONG: {}", my_client.as_ref().read().await.name());
             // we technically dont need to do anything, and dont care at this point.
         },
 
         MessageType::Typing(_, target) => {
             CommandHandler::typing(&server, &my_client, target).await;
-            return;
+        },
+
+        MessageType::Agents => {
+            CommandHandler::agent_list(my_client, authfinder.clone()).await;
+        },
+
+        MessageType::AgentAdd(username, password, permissions) => {
+            CommandHandler::agent_add(server, my_client, authfinder.clone(), username, password, permissions).await;
+        },
+
+        MessageType::AgentDelete(username) => {
+            CommandHandler::agent_delete(server, my_client, authfinder.clone(), username).await;
+        },
+
+        MessageType::AgentDisable(username, disabled) => {
+            CommandHandler::agent_disable(server, my_client, authfinder.clone(), username, disabled).await;
+        },
+
+        MessageType::AgentPassword(username, password) => {
+            CommandHandler::agent_password(server, my_client, authfinder.clone(), username, password).await;
+        },
+
+        MessageType::AgentPermissions(username, permissions) => {
+            CommandHandler::agent_permissions(server, my_client, authfinder.clone(), username, permissions).await;
+        },
+
+        MessageType::Wall(message) => {
+            CommandHandler::wall(server, my_client, message).await;
+        },
+
+        MessageType::Wallop(message) => {
+            CommandHandler::wallop(server, my_client, message).await;
+        },
+
+        MessageType::Walladmin(message) => {
+            CommandHandler::walladmin(server, my_client, message).await;
+        },
+
+        MessageType::RegisterDevice(target, username) => {
+            CommandHandler::register_device(server, my_client, authfinder.clone(), target, username).await;
+        },
+
+        MessageType::RevokeDevice(username) => {
+            CommandHandler::revoke_device(server, my_client, authfinder.clone(), username).await;
+        },
+
+        MessageType::Invite(channel, target) => {
+            CommandHandler::invite(server, my_client, channel, target, config.invite_expiry).await;
+        },
+
+        MessageType::Whois(target) => {
+            CommandHandler::whois(server, my_client, target).await;
+        },
+
+        MessageType::History(count, before) => {
+            CommandHandler::history(server, my_client, &config, cmessage.target, count, before).await;
+        },
+
+        MessageType::Presence(presence) => {
+            CommandHandler::set_presence(server, my_client, presence, false).await;
+        },
+
+        MessageType::RequestHelp(question) => {
+            CommandHandler::request_help(server, my_client, &config, question).await;
+        },
+
+        MessageType::Search(search) => {
+            CommandHandler::search(server, my_client, &config, search).await;
+        },
+
+        MessageType::Private(_, target, message) => {
+            CommandHandler::private_message(server, my_client, &config, target, message).await;
         },
 
         MessageType::Message(message) => {
             let c_lock = my_client.read().await;
             let clean_message = CMessage::sanitize_text_message(message.clone());
-            if let Some(chanref) = server.lock().await.get_channel_ref(cmessage.target).await {
+            let store = server.lock().await.channel_store();
+            let chanref = server.lock().await.get_channel_ref(cmessage.target).await;
+            if let Some(chanref) = chanref {
                 //ensure user is member of the channel
                 if chanref.read().await.is_member(c_lock.id()).await {
-                    if chanref.read().await.has_option(ChannelOptions::HiddenMessages) {
-                        let _ = chanref.write().await.sendto_agents(&CMessage::new(MessageType::Message(clean_message.to_owned()), c_lock.id(), cmessage.target, Some(c_lock.name()))).await;
+                    let outgoing = CMessage::new(MessageType::Message(clean_message.to_owned()), c_lock.id(), cmessage.target, Some(c_lock.name()));
+                    let hidden = chanref.read().await.has_option(ChannelOptions::HiddenMessages);
+                    server.lock().await.count_message();
+
+                    if hidden {
+                        let _ = chanref.write().await.sendto_agents(&outgoing).await;
                     } else {
-                        let _ = chanref.write().await.sendto_all_butone(c_lock.id(), &CMessage::new(MessageType::Message(clean_message.to_owned()), c_lock.id(), cmessage.target, Some(c_lock.name()))).await;
+                        let _ = chanref.write().await.sendto_all_butone(c_lock.id(), &outgoing).await;
+                    }
+
+                    // nobody can answer yet, hold the message for the first agent to join
+                    if c_lock.gettype() as u8 != ClientType::Agent as u8 && chanref.read().await.has_option(ChannelOptions::WaitForAgent) && !chanref.read().await.has_agent().await {
+                        let mut chan_lock = chanref.write().await;
+                        let first = chan_lock.queue_message(outgoing.clone(), config.wait_queue_max as usize);
+                        let queued = chan_lock.queue_len();
+                        let since = chan_lock.waiting_since().unwrap_or(SystemTime::now());
+                        let chan_name = chan_lock.name();
+                        std::mem::drop(chan_lock);
+
+                        if first {
+                            server.lock().await.sendto_wallops(CMessage::new(MessageType::Wallop(format!("<i>{}</i> is waiting for an agent in <i>{}</i> ({})", c_lock.name(), chan_name, cmessage.target)), Uuid::nil(), Uuid::nil(), None)).await;
+                        }
+
+                        let position = server.lock().await.queue_position(since).await;
+                        let _ = chanref.write().await.sendto_nonagents(&CMessage::new(MessageType::QueueState(position, queued), Uuid::nil(), cmessage.target, None)).await;
                     }
+                    // messages are kept in the history table, which replay and search read. the
+                    // channel log only gets them when there is no store or it could not be written
                     if chanref.read().await.has_option(ChannelOptions::SaveHistory) {
-                        let logfile = OpenOptions::new()
-                            .append(true)
-                            .create(true)
-                            .open(format!("logs/{}.log",chanref.read().await.id()));
+                        let saved = match &store {
+                            Some(store) => match store.add_history(&*chanref.read().await, &outgoing, c_lock.ip()).await {
+                                Ok(())  => true,
+                                Err(e)  => {
+                                    error!(channel = %cmessage.target, error = %e, "channel store: unable to save history");
+                                    false
+                                }
+                            },
+                            None        => false
+                        };
+
+                        if !saved {
+                            let logfile = OpenOptions::new()
+                                .append(true)
+                                .create(true)
+                                .open(format!("logs/{}.log",chanref.read().await.id()));
 
-                        if logfile.is_ok() {
-                            println!("logfile is ok");
-                            let log_entry = format!("{:?} / {} ({}): {}\n", std::time::SystemTime::now(), c_lock.ip(), c_lock.name(), clean_message);
+                            if logfile.is_ok() {
+                                let log_entry = format!("{:?} / {} ({}): {}\n", std::time::SystemTime::now(), c_lock.ip(), c_lock.name(), clean_message);
 
-                            let _ = logfile.unwrap().write(log_entry.as_bytes());
+                                let _ = logfile.unwrap().write(log_entry.as_bytes());
+                            }
                         }
                     }
                 }
@@ -644,8 +1191,7 @@ async fn handle_client_command(server: &Arc<Mutex<Server>>, config: Config, my_c
         },
         
         _   => {
-            println!("unknown command from client");
-            return;
+            debug!(client = %cmessage.source, "unknown command from client");
         }
     }
 }
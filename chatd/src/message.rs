@@ -18,7 +18,11 @@ pub enum MessageType {
     AuthGuest(Option<String>),  // username
     AuthDevice(String, String), // deviceuuid, username
     AuthAgent(String, String, String), // nickname, username, password
-    AuthOk(Uuid, String), // accepted uuid and username
+    AuthOk(Uuid, String, String), // accepted uuid, username and resume token
+    Resume(Uuid, String), // previous uuid and resume token
+    RegisterDevice(Uuid, String), // client to register, username they will log in with
+    DeviceRegistered(String, String), // deviceuuid, username -- sent to the registered client
+    RevokeDevice(String), // username whose devices are revoked
     Ping(u64), // ping with current systemtime
     Pong(u64), // reply, sending back the systemtime from PING
     // client name, client ip, send channel name if source is current client in message
@@ -27,26 +31,128 @@ pub enum MessageType {
     Kick(Uuid, Uuid, String),
     ChannelModes(Option<Vec<String>>),
     SetChannelModes(u64),
+    ChannelLimit(u64), // member limit of the target channel, 0 is unlimited
     Quit(String), // quit reason or message
     Kill(String, String), // kicker, reason -- use target in message
     Kline(String, u64, String), // ip, expiry in seconds, reason
-    Whois(),
+    Unkline(String), // ip or cidr range to remove from the ban list
+    Bans, // request the ban list (agent only)
+    BanList(Vec<BanEntry>), // bans that have not expired
+    Whois(Uuid), // client to look up
+    History(u64, Option<u64>), // count, only messages before this message id -- use target for the channel
+    HistoryReply(Vec<HistoryEntry>), // oldest first -- target is the channel
+    Search(HistorySearch), // search saved history of every channel (agent only)
+    SearchReply(u64, bool, Vec<SearchEntry>), // page, more pages available, newest first
+    WhoisReply(WhoisEntry),
+    Stats, // request live server counters (agent only)
+    StatsReply(ServerStats),
     Message(String),
+    Presence(Presence), // set our presence (agent only) -- sent to agents with source and name in message on change
+    RequestHelp(Option<String>), // guest asks to be given an agent, with an optional question
+    QueueState(u64, u64), // place among channels waiting for an agent (0 once an agent joined), messages queued
     Motd(String),
     Topic(String),
     Private(Uuid, Uuid, String),
+    Invite(Uuid, Uuid), // channel, invited client -- channel name is sent in message to the invited client
     File,
     Typing(Uuid, Uuid),
     Users(Uuid),
-    UserList(Option<Vec<(Uuid,String)>>),
+    UserList(Option<Vec<(Uuid, String, Option<Presence>)>>), // id, name, presence for agents
     Channels,
     ChannelList(Vec<(Uuid, String, String, u64, u64)>), // id, name, channel topic usercount, channel modes
+    Agents, // request the agent account list (admin only)
+    AgentList(Vec<(String, u64, bool)>), // username, permissions, disabled
+    AgentAdd(String, String, u64), // username, password, permissions
+    AgentDelete(String), // username
+    AgentDisable(String, bool), // username, disabled
+    AgentPassword(String, String), // username, new password
+    AgentPermissions(String, u64), // username, permissions
     Wall(String), // message to all connections
     Wallop(String), // message to agent connections
     Walladmin(String), // message to all admins
     Problem(ProblemCode) // code 
 }
 
+/// Client details returned by a Whois request.
+///
+/// `ip` and `options` are only filled in when the requester is an agent.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WhoisEntry {
+    pub id: Uuid,
+    pub name: String,
+    pub r#type: ClientType,
+    pub connected: u64, // unix time in seconds
+    pub idle: u64, // seconds since last action
+    pub channels: Vec<(Uuid, String)>,
+    pub ip: Option<IpAddr>,
+    pub options: Option<u64>,
+    pub presence: Option<Presence> // agents only
+}
+
+/// Live server counters returned by a Stats request.
+///
+/// Counters ending in `_since_start` are reset when the server restarts.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ServerStats {
+    pub version: String,
+    pub uptime: u64, // seconds
+    pub guests: u64, // connected clients by type
+    pub contacts: u64,
+    pub agents: u64,
+    pub pending_auth: u64, // connected but not yet authenticated
+    pub zombies: u64, // lost connections that may still resume
+    pub channels: u64,
+    pub bans: u64,
+    pub connections_since_start: u64,
+    pub invalid_connects: u64, // failed tls, websocket or auth
+    pub banned_connects: u64,
+    pub guests_since_start: u64,
+    pub messages_since_start: u64, // channel and private messages delivered
+    pub messages_last_minute: u64,
+}
+
+/// A ban as sent to agents by a Bans request.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BanEntry {
+    pub network: String, // ip address or cidr range
+    pub reason: String,
+    pub added_by: String,
+    pub added_on: u64, // unix time in seconds
+    pub expires: u64, // unix time in seconds
+}
+
+/// A message stored in a channels history.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HistoryEntry {
+    pub id: u64, // id of the original message
+    pub time: u64, // unix time in seconds, set by the server
+    pub source: Uuid,
+    pub name: String,
+    pub r#type: String, // Message, Topic
+    pub message: String
+}
+
+/// Filters for a history Search, every field that is set must match.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HistorySearch {
+    pub text: Option<String>, // words that must all appear in the message
+    pub name: Option<String>, // sender name, case insensitive
+    pub ip: Option<IpAddr>,
+    pub channel: Option<Uuid>,
+    pub from: Option<u64>, // unix time in seconds
+    pub to: Option<u64>, // unix time in seconds
+    pub page: u64 // starts at 0
+}
+
+/// A Search result, with the channel and sender ip the history entry came from.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SearchEntry {
+    pub channel: Uuid,
+    pub channel_name: String,
+    pub ip: Option<IpAddr>,
+    pub entry: HistoryEntry
+}
+
 impl From<Message> for TMessage {
     fn from(data: Message) -> Self {
         let json = serde_json::to_string(&data).expect("unable to serialize CMessage to Message");
@@ -55,9 +161,14 @@ impl From<Message> for TMessage {
 }
 
 impl Message {
+    /// Random message id that still fits a javascript number without losing precision.
+    pub fn generate_id() -> u64 {
+        rand::random::<u64>() >> 11
+    }
+
     pub fn new(r#type: MessageType, source: Uuid, target: Uuid, message: Option<String>) -> Self {
         Message {
-            id: rand::random::<u64>(),
+            id: Message::generate_id(),
             r#type,
             source,
             target,
@@ -68,7 +179,7 @@ impl Message {
     pub fn new_problem(problem: ProblemCode, target: Option<Uuid>, message: String) -> Self {
         let target = target.unwrap_or_else(|| Uuid::nil());
         Message {
-            id: rand::random::<u64>(),
+            id: Message::generate_id(),
             r#type: MessageType::Problem(problem), 
             source: Uuid::nil(),
             target,
@@ -107,4 +218,7 @@ pub enum ProblemCode {
     ChannelNameBad,     // channel name has bad chars
     ChannelInvalid,     // channel doesnt exist.
     KickedFromServer,   // kicked from the server
+    InviteOnly,         // channel requires an invite to join
+    Flooding,           // message was dropped by flood protection or slow mode
+    ChannelFull,        // channel has reached its member limit
 }
@@ -0,0 +1,156 @@
+use std::fmt::Write as FmtWrite;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+use crate::server::Server;
+
+/// Writes to a client socket that failed, counted by every sendto_* function.
+pub static SEND_ERRORS: AtomicU64 = AtomicU64::new(0);
+/// Clients disconnected for not answering pings.
+pub static PING_TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+
+pub fn count_send_error() {
+    SEND_ERRORS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn count_ping_timeout() {
+    PING_TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Serve `GET /metrics` in the Prometheus text format.
+///
+/// This is a plain http listener meant for a scraper on a private address, it does not use tls.
+pub async fn serve(server: Arc<Mutex<Server>>, address: IpAddr, port: u16) {
+    let listener = match TcpListener::bind(SocketAddr::new(address, port)).await {
+        Ok(bind)    => bind,
+        Err(e)      => {
+            error!(address = %address, port, error = %e, "unable to bind metrics");
+            return;
+        }
+    };
+
+    info!(address = %address, port, "metrics listening");
+
+    loop {
+        // errors like running out of file descriptors pass, so wait a moment and keep listening
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e)          => {
+                warn!(error = %e, "metrics: accept failed");
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let server = server.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = answer(stream, &server).await {
+                warn!(error = %e, "metrics: request failed");
+            }
+        });
+    }
+}
+
+async fn answer(mut stream: TcpStream, server: &Mutex<Server>) -> std::io::Result<()> {
+    let mut buffer = [0u8; 1024];
+    let read = stream.read(&mut buffer).await?;
+    let request = String::from_utf8_lossy(&buffer[..read]);
+
+    let response = if request.starts_with("GET /metrics ") {
+        let body = render(server).await;
+        format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// One metric with its help and type lines, `values` are (labels, value).
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, values: &[(&str, f64)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+
+    for (labels, value) in values {
+        let _ = writeln!(out, "{}{} {}", name, labels, value);
+    }
+}
+
+async fn render(server: &Mutex<Server>) -> String {
+    let mut slock = server.lock().await;
+    let stats = slock.stats().await;
+    let auth_failures = slock.auth_failures;
+    std::mem::drop(slock);
+
+    let mut out = String::new();
+    metric(&mut out, "chatd_uptime_seconds", "gauge", "Seconds since the server started.", &[("", stats.uptime as f64)]);
+    metric(&mut out, "chatd_clients", "gauge", "Connected clients by type.", &[
+        ("{type=\"guest\"}", stats.guests as f64),
+        ("{type=\"contact\"}", stats.contacts as f64),
+        ("{type=\"agent\"}", stats.agents as f64),
+        ("{type=\"pending_auth\"}", stats.pending_auth as f64),
+        ("{type=\"zombie\"}", stats.zombies as f64),
+    ]);
+    metric(&mut out, "chatd_channels", "gauge", "Open channels.", &[("", stats.channels as f64)]);
+    metric(&mut out, "chatd_bans", "gauge", "Bans that have not expired.", &[("", stats.bans as f64)]);
+    metric(&mut out, "chatd_messages_total", "counter", "Channel and private messages delivered.", &[("", stats.messages_since_start as f64)]);
+    metric(&mut out, "chatd_messages_per_second", "gauge", "Messages delivered per second over the last minute.", &[("", stats.messages_last_minute as f64 / 60.0)]);
+    metric(&mut out, "chatd_connections_total", "counter", "Connections accepted.", &[("", stats.connections_since_start as f64)]);
+    metric(&mut out, "chatd_invalid_connections_total", "counter", "Connections that failed tls, websocket or auth.", &[("", stats.invalid_connects as f64)]);
+    metric(&mut out, "chatd_auth_failures_total", "counter", "Failed logins and session resumes.", &[("", auth_failures as f64)]);
+    metric(&mut out, "chatd_banned_connections_total", "counter", "Connections refused by a ban.", &[("", stats.banned_connects as f64)]);
+    metric(&mut out, "chatd_send_errors_total", "counter", "Failed writes to client sockets.", &[("", SEND_ERRORS.load(Ordering::Relaxed) as f64)]);
+    metric(&mut out, "chatd_ping_timeouts_total", "counter", "Clients disconnected for not answering pings.", &[("", PING_TIMEOUTS.load(Ordering::Relaxed) as f64)]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+    use crate::server::tests::test_config;
+
+    async fn request(server: &Arc<Mutex<Server>>, request: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        let answering = tokio::spawn({
+            let server = server.clone();
+            async move { answer(accepted, &server).await }
+        });
+
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        answering.await.unwrap().unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn metrics_are_served_in_the_text_format() {
+        let (_, server_rx) = mpsc::unbounded_channel();
+        let server = Arc::new(Mutex::new(Server::new(server_rx, test_config())));
+        server.lock().await.count_message();
+
+        let response = request(&server, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())));
+        assert!(body.contains("# TYPE chatd_messages_total counter\nchatd_messages_total 1\n"));
+        assert!(body.contains("chatd_clients{type=\"guest\"} 0\n"));
+
+        // every sample follows its help and type lines
+        for line in body.lines().filter(|line| !line.starts_with('#')) {
+            let name = line.split(['{', ' ']).next().unwrap();
+            assert!(body.contains(&format!("# TYPE {} ", name)), "{} has no type", name);
+        }
+
+        let response = request(&server, "GET / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
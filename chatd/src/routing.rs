@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+use serde::{Serialize, Deserialize};
+use futures_util::SinkExt;
+use crate::channel::*;
+use crate::client::*;
+use crate::commands::CommandHandler;
+use crate::config::Config;
+use crate::message::{Message as CMessage, MessageSendable, MessageType};
+use crate::server::Server;
+use crate::{Mutex, RwLock, Uuid};
+use tracing::info;
+
+/// How the router picks an agent for a waiting guest.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum RoutingStrategy {
+    RoundRobin,  // take turns in agent id order
+    LeastLoaded, // fewest open support channels
+    LongestIdle, // longest since the agent was last given a guest
+}
+
+/// Tracks the support channels made for guests asking for help.
+///
+/// A support channel is waiting while no agent is a member, so a channel an agent
+/// left is routed again.
+pub struct Router {
+    support: Vec<(Uuid, Uuid)>, // channel, guest -- in request order
+    last_assigned: HashMap<Uuid, SystemTime>, // agent and when they were last given a guest
+    last_agent: Option<Uuid>, // last agent picked, for round robin
+    last_position: HashMap<Uuid, u64>, // channel and the queue place its guest was last told
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router {
+            support: Vec::new(),
+            last_assigned: HashMap::new(),
+            last_agent: None,
+            last_position: HashMap::new(),
+        }
+    }
+
+    pub fn add_support_channel(&mut self, channel: Uuid, guest: Uuid) {
+        self.support.push((channel, guest));
+    }
+
+    pub fn forget_channel(&mut self, channel: Uuid) {
+        self.support.retain(|(c, _)| *c != channel);
+        self.last_position.remove(&channel);
+    }
+
+    /// The support channel a guest already has, if any.
+    pub fn guest_channel(&self, guest: Uuid) -> Option<Uuid> {
+        self.support.iter().find(|(_, g)| *g == guest).map(|(c, _)| *c)
+    }
+
+    pub fn support_channels(&self) -> Vec<Uuid> {
+        self.support.iter().map(|(c, _)| *c).collect()
+    }
+
+    /// Pick an agent from those with room for another guest, `agents` holds (id, load).
+    fn pick(&mut self, strategy: RoutingStrategy, agents: &[(Uuid, u64)]) -> Option<Uuid> {
+        let picked = match strategy {
+            RoutingStrategy::RoundRobin  => {
+                let mut ids: Vec<Uuid> = agents.iter().map(|(id, _)| *id).collect();
+                ids.sort();
+                match self.last_agent {
+                    Some(last)  => ids.iter().find(|id| **id > last).or(ids.first()).copied(),
+                    None        => ids.first().copied()
+                }
+            },
+            RoutingStrategy::LeastLoaded => agents.iter().min_by_key(|(id, load)| (*load, *id)).map(|(id, _)| *id),
+            RoutingStrategy::LongestIdle => agents.iter()
+                .min_by_key(|(id, _)| (self.last_assigned.get(id).copied().unwrap_or(SystemTime::UNIX_EPOCH), *id))
+                .map(|(id, _)| *id),
+        };
+
+        if let Some(agent) = picked {
+            self.last_agent = Some(agent);
+            self.last_assigned.insert(agent, SystemTime::now());
+        }
+        picked
+    }
+}
+
+/// Give waiting support channels to free agents, and tell guests still waiting their place.
+///
+/// Only Available agents are picked, and those at `agent_capacity` open support channels
+/// are skipped, 0 means no limit.
+pub async fn route_pending(server: &Mutex<Server>, config: &Config) {
+    let (support, clients) = {
+        let slock = server.lock().await;
+        (slock.router.support_channels(), slock.get_clients().await)
+    };
+
+    let mut agents: Vec<(Uuid, Arc<RwLock<Client>>)> = Vec::new();
+
+    if let Some(clients) = clients {
+        for client in clients.lock().await.values() {
+            let lock = client.read().await;
+
+            if lock.status() as u64 == ClientStatus::Connected as u64 && lock.gettype() as u8 == ClientType::Agent as u8 && lock.presence().await == Presence::Available {
+                agents.push((lock.id(), client.clone()));
+            }
+        }
+    }
+
+    let mut loads: HashMap<Uuid, u64> = agents.iter().map(|(id, _)| (*id, 0u64)).collect();
+    let mut pending: Vec<Arc<RwLock<Channel>>> = Vec::new();
+
+    for channel_id in support {
+        let chan_ref = match server.lock().await.get_channel_ref(channel_id).await {
+            Some(c) => c,
+            None    => { server.lock().await.router.forget_channel(channel_id); continue; }
+        };
+
+        let mut has_agent = false;
+
+        for (agent_id, _) in agents.iter() {
+            if chan_ref.read().await.is_member(*agent_id).await {
+                *loads.entry(*agent_id).or_default() += 1;
+                has_agent = true;
+            }
+        }
+
+        if !has_agent {
+            pending.push(chan_ref);
+        }
+    }
+
+    for chan_ref in pending {
+        let available: Vec<(Uuid, u64)> = loads.iter()
+            .filter(|(_, load)| config.agent_capacity == 0 || **load < config.agent_capacity)
+            .map(|(id, load)| (*id, *load))
+            .collect();
+
+        let picked = server.lock().await.router.pick(config.routing_strategy, &available);
+
+        match picked.and_then(|id| agents.iter().find(|(a, _)| *a == id)) {
+            Some((agent_id, agent)) => {
+                *loads.entry(*agent_id).or_default() += 1;
+                assign(server, agent, &chan_ref).await;
+            },
+            None                    => {
+                let (channel_id, since, queued) = {
+                    let lock = chan_ref.read().await;
+                    (lock.id(), lock.waiting_since().unwrap_or(SystemTime::now()), lock.queue_len())
+                };
+                let position = server.lock().await.queue_position(since).await;
+                let last = server.lock().await.router.last_position.insert(channel_id, position);
+
+                if last != Some(position) {
+                    let _ = chan_ref.write().await.sendto_nonagents(&CMessage::new(MessageType::QueueState(position, queued), Uuid::nil(), channel_id, None)).await;
+                }
+            }
+        }
+    }
+}
+
+/// Join an agent to a support channel and hand over anything the guest said so far.
+///
+/// The server lock is taken before the agent and channel locks, never while holding them.
+async fn assign(server: &Mutex<Server>, agent: &RwLock<Client>, chan_ref: &RwLock<Channel>) {
+    let channel_id = chan_ref.read().await.id();
+    server.lock().await.router.last_position.remove(&channel_id);
+
+    let mut a_lock = agent.write().await;
+    let mut chan = chan_ref.write().await;
+
+    chan.add_member(a_lock.clone()).await;
+    a_lock.add_channel(channel_id, chan.name()).await;
+    CommandHandler::send_channel_state(&mut a_lock, &chan).await;
+
+    for queued in chan.take_queue() {
+        let _ = a_lock.sender().lock().await.send(queued.into()).await;
+    }
+
+    let _ = chan.sendto_nonagents(&CMessage::new(MessageType::QueueState(0, 0), Uuid::nil(), channel_id, None)).await;
+    chan.to_log(format!("{:?} / ROUTED: {} ({}@{})", SystemTime::now(), chan.name(), a_lock.name(), a_lock.ip())).await;
+    info!(channel = %channel_id, client = %a_lock.id(), "routing: channel given to agent");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn ids(count: usize) -> Vec<Uuid> {
+        let mut ids: Vec<Uuid> = (0..count).map(|_| Uuid::new_v4()).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn round_robin_wraps_around() {
+        let mut router = Router::new();
+        let ids = ids(3);
+        let agents: Vec<(Uuid, u64)> = vec![(ids[2], 0), (ids[0], 5), (ids[1], 0)];
+
+        let picks: Vec<Option<Uuid>> = (0..4).map(|_| router.pick(RoutingStrategy::RoundRobin, &agents)).collect();
+        assert_eq!(picks, vec![Some(ids[0]), Some(ids[1]), Some(ids[2]), Some(ids[0])]);
+
+        // the last agent left, the turn goes on from where it was
+        router.last_agent = Some(ids[1]);
+        assert_eq!(router.pick(RoutingStrategy::RoundRobin, &[(ids[0], 0), (ids[2], 0)]), Some(ids[2]));
+        assert_eq!(router.pick(RoutingStrategy::RoundRobin, &[]), None);
+    }
+
+    #[test]
+    fn least_loaded_picks_the_fewest_channels() {
+        let mut router = Router::new();
+        let ids = ids(3);
+
+        assert_eq!(router.pick(RoutingStrategy::LeastLoaded, &[(ids[0], 3), (ids[1], 1), (ids[2], 2)]), Some(ids[1]));
+        // ties go to the lowest id
+        assert_eq!(router.pick(RoutingStrategy::LeastLoaded, &[(ids[2], 1), (ids[1], 1), (ids[0], 4)]), Some(ids[1]));
+    }
+
+    #[test]
+    fn longest_idle_prefers_agents_never_assigned() {
+        let mut router = Router::new();
+        let ids = ids(3);
+        let now = SystemTime::now();
+        router.last_assigned.insert(ids[0], now - Duration::from_secs(60));
+        router.last_assigned.insert(ids[1], now - Duration::from_secs(600));
+
+        let agents: Vec<(Uuid, u64)> = vec![(ids[0], 0), (ids[1], 0), (ids[2], 9)];
+        assert_eq!(router.pick(RoutingStrategy::LongestIdle, &agents), Some(ids[2]));
+        assert_eq!(router.pick(RoutingStrategy::LongestIdle, &agents), Some(ids[1]));
+        assert_eq!(router.pick(RoutingStrategy::LongestIdle, &agents), Some(ids[0]));
+    }
+}
@@ -1,17 +1,21 @@
 use crate::client::*;
 use crate::channel::*;
 use crate::Ban;
+use crate::store::ChannelStore;
+use crate::routing::Router;
 use crate::config::Config;
 use crate::SystemTime;
 use crate::IpAddr;
+use ipnet::IpNet;
 // use crate::handle_client_error;
-use crate::message::Message as CMessage;
+use crate::message::{Message as CMessage, MessageType, ServerStats};
 use crate::AuthFinder;
 use crate::VERSION;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::io::Read;
 use std::sync::Arc;
 use futures_util::SinkExt;
+use tracing::{debug, error, warn};
 // use std::sync::RwLock;
 use std::fs::File;
 // use std::io::prelude;
@@ -26,11 +30,18 @@ pub struct Server {
     clients: Arc<Mutex<HashMap<Uuid, Arc<RwLock<Client>>>>>,
     channels: Arc<Mutex<HashMap<Uuid, Arc<RwLock<Channel>>>>>,
     bans: Arc<RwLock<Vec<Ban>>>,
+    channel_store: Option<Arc<ChannelStore>>,
     // pub authfinder: Arc<Mutex<Box<dyn AuthFinder>>>,
     pub connections_since_start: u64,
     pub invalid_connects: u64, 
     pub banned_connects: u64,
     pub guest_count: u64,
+    pub auth_failures: u64,
+    failed_auths: HashMap<IpAddr, (SystemTime, u64)>, // failed logins by ip since the window started
+    started: SystemTime,
+    messages_since_start: u64,
+    recent_messages: VecDeque<SystemTime>, // delivery times of messages in the last minute
+    pub router: Router,
     pub motd_guests: String,
     pub motd_clients: String,
     pub motd_agents: String,
@@ -44,10 +55,17 @@ impl Server {
             clients: Arc::new(Mutex::new(HashMap::new())),
             channels: Arc::new(Mutex::new(HashMap::new())),
             bans: Arc::new(RwLock::new(Server::load_bans_db(&config.ban_db))),
+            channel_store: None,
             connections_since_start: 0,
             invalid_connects: 0,
             banned_connects: 0,
             guest_count: 0,
+            auth_failures: 0,
+            failed_auths: HashMap::new(),
+            started: SystemTime::now(),
+            messages_since_start: 0,
+            recent_messages: VecDeque::new(),
+            router: Router::new(),
             motd_guests: Server::load_motd_file(&config.motd_file_guests),
             motd_clients: Server::load_motd_file(&config.motd_file_clients),
             motd_agents: Server::load_motd_file(&config.motd_file_agents),
@@ -66,32 +84,140 @@ impl Server {
     pub async fn count_bans(&self) -> usize {
         self.bans.read().await.len()
     }
-    pub async fn add_ban(&mut self, ip: IpAddr, reason: &str, expires: SystemTime, added_by: &str) -> Result<(), String> {
 
-        if Ban::exists(&self.bans, ip).await {
+    /// Count a failed login or resume, for metrics and the per ip auth throttle.
+    pub fn count_auth_failure(&mut self, ip: IpAddr) {
+        let now = SystemTime::now();
+        let window = self.conf.auth_failure_window;
+        self.auth_failures += 1;
+
+        if self.failed_auths.len() > 4096 {
+            self.failed_auths.retain(|_, (since, _)| now.duration_since(*since).unwrap_or_default().as_secs() < window);
+        }
+
+        let entry = self.failed_auths.entry(ip.to_canonical()).or_insert((now, 0));
+
+        if now.duration_since(entry.0).unwrap_or_default().as_secs() >= window {
+            *entry = (now, 0);
+        }
+        entry.1 += 1;
+    }
+
+    /// True when an ip has failed auth_max_failures logins within auth_failure_window seconds.
+    pub fn auth_throttled(&self, ip: IpAddr) -> bool {
+        if self.conf.auth_max_failures == 0 {
+            return false;
+        }
+
+        match self.failed_auths.get(&ip.to_canonical()) {
+            Some((since, count)) => *count >= self.conf.auth_max_failures && since.elapsed().unwrap_or_default().as_secs() < self.conf.auth_failure_window,
+            None    => false
+        }
+    }
+
+    /// Count a delivered channel or private message for the Stats throughput.
+    pub fn count_message(&mut self) {
+        let now = SystemTime::now();
+        self.messages_since_start += 1;
+        self.recent_messages.push_back(now);
+        self.prune_recent_messages(now);
+    }
+
+    fn prune_recent_messages(&mut self, now: SystemTime) {
+        while let Some(oldest) = self.recent_messages.front() {
+            if now.duration_since(*oldest).unwrap_or_default().as_secs() < 60 {
+                break;
+            }
+            self.recent_messages.pop_front();
+        }
+    }
+
+    /// Collect the counters for a Stats reply. Locks every client, so none may be held.
+    pub async fn stats(&mut self) -> ServerStats {
+        let mut stats = ServerStats {
+            version: VERSION.to_string(),
+            uptime: self.started.elapsed().unwrap_or_default().as_secs(),
+            guests: 0,
+            contacts: 0,
+            agents: 0,
+            pending_auth: 0,
+            zombies: 0,
+            channels: self.channels.lock().await.len() as u64,
+            bans: self.bans.read().await.iter().filter(|ban| !ban.is_expired()).count() as u64,
+            connections_since_start: self.connections_since_start,
+            invalid_connects: self.invalid_connects,
+            banned_connects: self.banned_connects,
+            guests_since_start: self.guest_count,
+            messages_since_start: self.messages_since_start,
+            messages_last_minute: 0,
+        };
+
+        for client in self.clients.lock().await.values() {
+            let lock = client.read().await;
+
+            if lock.status() as u64 == ClientStatus::Zombie as u64 {
+                stats.zombies += 1;
+                continue;
+            }
+
+            if lock.status() as u64 == ClientStatus::PendingAuth as u64 {
+                stats.pending_auth += 1;
+                continue;
+            }
+
+            match lock.gettype() {
+                ClientType::Guest   => stats.guests += 1,
+                ClientType::Contact => stats.contacts += 1,
+                ClientType::Agent   => stats.agents += 1,
+                ClientType::None    => {}
+            }
+        }
+
+        self.prune_recent_messages(SystemTime::now());
+        stats.messages_last_minute = self.recent_messages.len() as u64;
+        stats
+    }
+    pub async fn add_ban(&mut self, network: IpNet, reason: &str, expires: SystemTime, added_by: &str) -> Result<(), String> {
+
+        if Ban::listed(&self.bans, network).await {
             return Err("the ip already exists in the ban list.".into());
         }
 
         let mut lock = self.bans.write().await;
-        lock.push(Ban::new(ip, reason.into(), expires, added_by.into()));
+        lock.retain(|ban| ban.network() != network); // an expired ban the sweep has not reached
+        lock.push(Ban::new(network, reason.into(), expires, added_by.into()));
 
         Ok(())
     }
 
-    pub async fn remove_ban(&mut self, ip: IpAddr) -> Result<(), String> {
-        if !Ban::exists(&self.bans, ip).await {
+    pub async fn remove_ban(&mut self, network: IpNet) -> Result<(), String> {
+        if !Ban::listed(&self.bans, network).await {
             return Err("the ip is not in the ban-list".to_string());
         }
 
         let mut lock = self.bans.write().await;
-        lock.retain(|ban| ban.ip != ip);
+        lock.retain(|ban| ban.network() != network);
         Ok(())
     }
 
+    /// Drop expired bans from the list, returning how many were removed.
+    pub async fn expire_bans(&mut self) -> usize {
+        let mut lock = self.bans.write().await;
+        let before = lock.len();
+        lock.retain(|ban| !ban.is_expired());
+        before - lock.len()
+    }
+
+    /// True if any ban covers the address.
     pub async fn ban_exists(&mut self, ip: IpAddr) -> bool {
         Ban::exists(&self.bans, ip).await
     }
 
+    /// True if this exact address or range is already in the ban list.
+    pub async fn ban_listed(&mut self, network: IpNet) -> bool {
+        Ban::listed(&self.bans, network).await
+    }
+
     pub async fn save_ban_db(&mut self, filename: &str) -> Result<(), String> {
         if Ban::save_to_disk(filename, self.bans.read().await.clone().as_ref()).is_ok() {
             return Ok(())
@@ -105,14 +231,14 @@ impl Server {
             return bans;
         }
 
-        println!("error: failed to load bans from {}",  filename);
+        warn!(file = %filename, "failed to load bans");
         Vec::new()
     }
 
     pub fn load_motd_file(filename: &String) -> String {
         let mut fhandle = match File::open(filename) {
             Ok(h)   => h,
-            Err(e)  => { println!("motd error: {} [{}]", filename, e); return "".to_string(); }
+            Err(e)  => { warn!(file = %filename, error = %e, "unable to open motd"); return "".to_string(); }
         };
 
         let mut motd_text = String::new();
@@ -122,20 +248,34 @@ impl Server {
             Err(_)  => return "".to_string()
         }
     }
-    pub async fn client_count(&self) -> u64 {
-        self.clients.lock().await.len() as u64
-    }
     pub async fn add_client(&mut self, client: Client) {
         let mut lock = self.clients.lock().await;
         let id = client.id();
-        println!("adding to client");
         lock.insert(id, Arc::new(RwLock::new(client)));
-        println!("addint to client ok");
     }
 
-    pub async fn remove_client(&mut self, id: Uuid) {
+    /// Store a client under a new id, dropping whatever was under the old and new ids.
+    ///
+    /// Used when a connection resumes a zombie session and takes over its id.
+    pub async fn rekey_client(&self, old_id: Uuid, id: Uuid, client: Arc<RwLock<Client>>) {
         let mut lock = self.clients.lock().await;
-        lock.remove(&id);
+        lock.remove(&old_id);
+        lock.insert(id, client);
+    }
+
+    /// Remove a client, agents that were not appearing offline are announced as Offline.
+    pub async fn remove_client(&mut self, id: Uuid) {
+        let removed = self.clients.lock().await.remove(&id);
+
+        if let Some(client) = removed {
+            let lock = client.read().await;
+
+            if lock.gettype() as u8 == ClientType::Agent as u8 && lock.presence().await != Presence::Offline {
+                let message = CMessage::new(MessageType::Presence(Presence::Offline), id, Uuid::nil(), Some(lock.name()));
+                std::mem::drop(lock);
+                self.sendto_wallops(message).await;
+            }
+        }
     }
 
     pub async fn client_exists(&self, id: Uuid) -> bool {
@@ -180,7 +320,7 @@ impl Server {
     pub async fn sendto_wallops(&mut self, message: CMessage) -> u64 {
         let json = match serde_json::to_string(&message) {
             Ok(j)   => j,
-            Err(e)  => { println!("*** failed sendto_wallops(): unable to serialize CMessage [{}]", e); return 0 }
+            Err(e)  => { error!(error = %e, "sendto_wallops: unable to serialize message"); return 0 }
         };
         let mut count = 0u64;
 
@@ -194,7 +334,8 @@ impl Server {
 
                 if client_lock.sender().lock().await.send(Message::text(json.clone())).await.is_err() {
                     client_lock.set_status(ClientStatus::Closing);
-                    println!("*** write error to client during wallop");    
+                    crate::metrics::count_send_error();
+                    debug!(client = %client_lock.id(), "write error during wallop");
                 }
                 count += 1;
             }
@@ -202,44 +343,71 @@ impl Server {
        count 
     }
 
-    pub async fn sendto_wall(&mut self, message: CMessage) -> u64 {
+    pub async fn sendto_walladmins(&mut self, message: CMessage) -> u64 {
         let json = match serde_json::to_string(&message) {
             Ok(j)   => j,
-            Err(e)  => { println!("*** failed sendto_wall(): unable to serialize CMessage [{}]", e); return 0 }
+            Err(e)  => { error!(error = %e, "sendto_walladmins: unable to serialize message"); return 0 }
         };
         let mut count = 0u64;
 
         for client in self.clients.lock().await.values() {
             if client.read().await.status() as u64 == ClientStatus::Connected as u64 {
+                if !client.read().await.has_options(ClientOptions::Admin) {
+                    continue;
+                }
+
                 let mut client_lock = client.write().await;
-                count += 1;
 
                 if client_lock.sender().lock().await.send(Message::text(json.clone())).await.is_err() {
                     client_lock.set_status(ClientStatus::Closing);
-                    println!("*** write error to client during wall");    
+                    crate::metrics::count_send_error();
+                    debug!(client = %client_lock.id(), "write error during walladmin");
                 }
+                count += 1;
             }
         }
        count 
     }
 
-    pub async fn sendto_one(&mut self, id: Uuid, message: CMessage) -> u64 {
+    pub async fn sendto_wall(&mut self, message: CMessage) -> u64 {
         let json = match serde_json::to_string(&message) {
             Ok(j)   => j,
-            Err(e)  => { println!("*** failed sendto_wall(): unable to serialize CMessage [{}]", e); return 0 }
+            Err(e)  => { error!(error = %e, "sendto_wall: unable to serialize message"); return 0 }
         };
+        let mut count = 0u64;
 
         for client in self.clients.lock().await.values() {
-            if client.read().await.id() == id {
+            if client.read().await.status() as u64 == ClientStatus::Connected as u64 {
                 let mut client_lock = client.write().await;
+                count += 1;
 
                 if client_lock.sender().lock().await.send(Message::text(json.clone())).await.is_err() {
                     client_lock.set_status(ClientStatus::Closing);
-                    println!("*** write error to client during wall");    
+                    crate::metrics::count_send_error();
+                    debug!(client = %client_lock.id(), "write error during wall");
                 }
+            }
+        }
+       count 
+    }
+
+    pub async fn sendto_one(&mut self, id: Uuid, message: CMessage) -> u64 {
+        let json = match serde_json::to_string(&message) {
+            Ok(j)   => j,
+            Err(e)  => { error!(error = %e, "sendto_one: unable to serialize message"); return 0 }
+        };
 
-                1;
+        if let Some(client) = self.clients.lock().await.get(&id) {
+            let mut client_lock = client.write().await;
+
+            if client_lock.sender().lock().await.send(Message::text(json)).await.is_err() {
+                client_lock.set_status(ClientStatus::Closing);
+                crate::metrics::count_send_error();
+                debug!(client = %client_lock.id(), "write error during sendto_one");
+                return 0;
             }
+
+            return 1;
         }
         0
     }
@@ -254,6 +422,42 @@ impl Server {
 
     pub async fn remove_channel(&mut self, id: Uuid) {
         self.channels.lock().await.remove(&id);
+        self.router.forget_channel(id);
+
+        if let Some(store) = &self.channel_store {
+            if let Err(e) = store.delete(id).await {
+                error!(channel = %id, error = %e, "channel store: unable to delete");
+            }
+        }
+    }
+
+    pub fn set_channel_store(&mut self, store: ChannelStore) {
+        self.channel_store = Some(Arc::new(store));
+    }
+
+    pub fn channel_store(&self) -> Option<Arc<ChannelStore>> {
+        self.channel_store.clone()
+    }
+
+    /// Load persisted channels from the channel store, returns how many were restored.
+    pub async fn restore_channels(&mut self) -> usize {
+        let store = match &self.channel_store {
+            Some(store) => store.clone(),
+            None        => return 0
+        };
+
+        let channels = match store.load().await {
+            Ok(channels)    => channels,
+            Err(e)          => { error!(error = %e, "channel store: unable to load channels"); return 0; }
+        };
+
+        let count = channels.len();
+
+        for channel in channels {
+            self.add_channel(channel).await;
+        }
+
+        count
     }
 
     pub async fn get_channel_ref(&self, id: Uuid) -> Option<Arc<RwLock<Channel>>> {
@@ -292,6 +496,20 @@ impl Server {
         None
     }
 
+    /// Place of a waiting channel among all channels waiting for an agent, 1 is next in line.
+    pub async fn queue_position(&self, since: SystemTime) -> u64 {
+        let mut position = 0u64;
+
+        for chan in self.get_channels().await.values() {
+            if let Some(waiting) = chan.read().await.waiting_since() {
+                if waiting <= since {
+                    position += 1;
+                }
+            }
+        }
+        position
+    }
+
     pub async fn channel_count(&self) -> u64 {
         self.channels.lock().await.len() as u64
     }
@@ -299,7 +517,8 @@ impl Server {
     pub async fn create_default_channels(&mut self, config: &Config) {
         let mut chan_creator: Channel;
 
-        if config.use_global_lobby {
+        // lobbies restored from the channel store keep their id, topic and modes
+        if config.use_global_lobby && self.get_channel_by_name("Global Lobby".to_string()).await.is_none() {
             chan_creator = Channel::new("Global Lobby".to_string(), None, false);
             chan_creator.set_options(
                 ChannelOptions::Persist
@@ -309,10 +528,13 @@ impl Server {
                     // .or(ChannelOptions::Invisible)
             );
             chan_creator.set_topic(Some(format!("InvenT chatd v{} (unreleased) - imightanswer@clearchat.club", VERSION).to_string())).await;
+            if let Some(store) = &self.channel_store {
+                store.persist(&chan_creator).await;
+            }
             self.add_channel(chan_creator).await;
         }
 
-        if config.use_staff_lobby {
+        if config.use_staff_lobby && self.get_channel_by_name("Staff Lobby".to_string()).await.is_none() {
             chan_creator = Channel::new("Staff Lobby".to_string(), None, false);
             chan_creator.set_options(
                 ChannelOptions::Persist
@@ -323,10 +545,13 @@ impl Server {
                     .or(ChannelOptions::CanNotLeave)
             );
             chan_creator.set_topic(Some("DO NOT GIVE OUT YOUR PASSWORDS".to_string())).await;
+            if let Some(store) = &self.channel_store {
+                store.persist(&chan_creator).await;
+            }
             self.add_channel(chan_creator).await;
         }
 
-        if config.use_guest_lobby {
+        if config.use_guest_lobby && self.get_channel_by_name("Guest Lobby".to_string()).await.is_none() {
             chan_creator = Channel::new("Guest Lobby".to_string(), None, false);
             chan_creator.set_options(
                 ChannelOptions::Persist
@@ -336,8 +561,77 @@ impl Server {
                     .or(ChannelOptions::CanNotLeave)
                     // .or(ChannelOptions::Invisible)
             );
+            if let Some(store) = &self.channel_store {
+                store.persist(&chan_creator).await;
+            }
             self.add_channel(chan_creator).await;
         }
 
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// The default config with the ban list in the temp directory instead of the cwd.
+    pub(crate) fn test_config() -> Config {
+        let ban_db = std::env::temp_dir().join(format!("chatd-test-bans-{}.db", Uuid::new_v4()));
+        Config { ban_db: ban_db.to_string_lossy().to_string(), ..Config::default() }
+    }
+
+    #[tokio::test]
+    async fn queue_position_counts_earlier_waits() {
+        let (_, server_rx) = mpsc::unbounded_channel();
+        let mut server = Server::new(server_rx, test_config());
+        let mut waits: Vec<SystemTime> = Vec::new();
+
+        for name in ["Support", "Sales", "Billing"] {
+            let mut channel = Channel::new(name.to_string(), None, false);
+            channel.start_waiting();
+            waits.push(channel.waiting_since().unwrap());
+            server.add_channel(channel).await;
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        server.add_channel(Channel::new("Lobby".to_string(), None, false)).await;
+
+        for (place, since) in waits.iter().enumerate() {
+            assert_eq!(server.queue_position(*since).await, place as u64 + 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn stats_count_clients_by_state_and_type() {
+        let (server_tx, server_rx) = mpsc::unbounded_channel();
+        let mut server = Server::new(server_rx, test_config());
+        let mut far_ends = Vec::new();
+        let states = [
+            (ClientType::Guest, ClientStatus::Connected),
+            (ClientType::Guest, ClientStatus::Connected),
+            (ClientType::Agent, ClientStatus::Connected),
+            (ClientType::Guest, ClientStatus::Zombie),
+            (ClientType::None, ClientStatus::PendingAuth),
+        ];
+
+        for (kind, status) in states {
+            let (mut client, far_end) = crate::client::tests::connected_client(server_tx.clone()).await;
+            client.set_type(kind);
+            client.set_status(status);
+            far_ends.push(far_end);
+            server.add_client(client).await;
+        }
+        server.add_channel(Channel::new("Lobby".to_string(), None, false)).await;
+        server.add_ban("10.0.0.0/8".parse().unwrap(), "test", SystemTime::now() + Duration::from_secs(60), "test").await.unwrap();
+        server.add_ban("10.1.0.1/32".parse().unwrap(), "test", SystemTime::now() - Duration::from_secs(60), "test").await.unwrap();
+        server.count_message();
+        server.count_message();
+
+        let stats = server.stats().await;
+        assert_eq!((stats.guests, stats.contacts, stats.agents), (2, 0, 1));
+        assert_eq!((stats.zombies, stats.pending_auth), (1, 1));
+        assert_eq!((stats.channels, stats.bans), (1, 1));
+        assert_eq!((stats.messages_since_start, stats.messages_last_minute), (2, 2));
+        assert_eq!(stats.version, VERSION);
+    }
+}
@@ -0,0 +1,442 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+use sqlx::{sqlite::{SqliteConnectOptions, SqlitePoolOptions}, Pool, Sqlite, Row};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::channel::*;
+use crate::message::{Message as CMessage, MessageType, HistoryEntry, HistorySearch, SearchEntry};
+use crate::Uuid;
+use tracing::error;
+
+/// SQLite storage for channels with the Persist option, so they survive a restart,
+/// and for the history of channels with SaveHistory.
+///
+/// Channels are stored with their Uuid so `logs/{uuid}.log` still matches once restored.
+pub struct ChannelStore {
+    connection: Pool<Sqlite>,
+}
+
+impl ChannelStore {
+    pub async fn new(file: &str) -> Result<Self, String> {
+        let options = match SqliteConnectOptions::from_str(file) {
+            Ok(o)   => o.create_if_missing(true),
+            Err(e)  => return Err(e.to_string())
+        };
+
+        match SqlitePoolOptions::new().max_connections(2).connect_with(options).await {
+            Ok(connection)  => Ok(ChannelStore { connection }),
+            Err(e)          => Err(e.to_string())
+        }
+    }
+
+    pub async fn create_tables(&self) -> Result<(), String> {
+        let query = sqlx::query("CREATE TABLE IF NOT EXISTS `channels` (
+            `id` TEXT PRIMARY KEY NOT NULL,
+            `name` TEXT NOT NULL,
+            `owner` TEXT,
+            `topic` TEXT,
+            `options` INTEGER NOT NULL DEFAULT 0,
+            `member_limit` INTEGER NOT NULL DEFAULT 0)")
+            .execute(&self.connection)
+            .await;
+
+        if let Err(e) = query {
+            return Err(e.to_string());
+        }
+
+        let query = sqlx::query("CREATE TABLE IF NOT EXISTS `history` (
+            `seq` INTEGER PRIMARY KEY AUTOINCREMENT,
+            `channel` TEXT NOT NULL,
+            `id` INTEGER NOT NULL,
+            `time` INTEGER NOT NULL,
+            `source` TEXT NOT NULL,
+            `name` TEXT NOT NULL,
+            `type` TEXT NOT NULL,
+            `message` TEXT NOT NULL,
+            `hidden` INTEGER NOT NULL DEFAULT 0,
+            `channel_name` TEXT NOT NULL DEFAULT '',
+            `ip` TEXT)")
+            .execute(&self.connection)
+            .await;
+
+        if let Err(e) = query {
+            return Err(e.to_string());
+        }
+
+        // older databases predate these columns, this fails harmlessly once they exist
+        let _ = sqlx::query("ALTER TABLE `channels` ADD COLUMN `member_limit` INTEGER NOT NULL DEFAULT 0")
+            .execute(&self.connection)
+            .await;
+        let _ = sqlx::query("ALTER TABLE `history` ADD COLUMN `channel_name` TEXT NOT NULL DEFAULT ''")
+            .execute(&self.connection)
+            .await;
+        let _ = sqlx::query("ALTER TABLE `history` ADD COLUMN `ip` TEXT")
+            .execute(&self.connection)
+            .await;
+
+        if let Err(e) = sqlx::query("CREATE INDEX IF NOT EXISTS `history_channel` ON `history` (`channel`, `seq`)").execute(&self.connection).await {
+            return Err(e.to_string());
+        }
+
+        self.create_search_index().await
+    }
+
+    /// Full text index over history messages, kept in sync by triggers.
+    ///
+    /// Existing history is indexed the first time the index is created.
+    async fn create_search_index(&self) -> Result<(), String> {
+        let exists = sqlx::query("SELECT `name` FROM `sqlite_master` WHERE `type` = 'table' AND `name` = 'history_fts'")
+            .fetch_optional(&self.connection)
+            .await;
+
+        let exists = match exists {
+            Ok(row) => row.is_some(),
+            Err(e)  => return Err(e.to_string())
+        };
+
+        let statements = [
+            "CREATE VIRTUAL TABLE IF NOT EXISTS `history_fts` USING fts5(`message`, content='history', content_rowid='seq')",
+            "CREATE TRIGGER IF NOT EXISTS `history_fts_insert` AFTER INSERT ON `history` BEGIN
+                INSERT INTO `history_fts` (`rowid`, `message`) VALUES (new.`seq`, new.`message`);
+            END",
+            "CREATE TRIGGER IF NOT EXISTS `history_fts_delete` AFTER DELETE ON `history` BEGIN
+                INSERT INTO `history_fts` (`history_fts`, `rowid`, `message`) VALUES ('delete', old.`seq`, old.`message`);
+            END",
+        ];
+
+        for statement in statements {
+            if let Err(e) = sqlx::query(statement).execute(&self.connection).await {
+                return Err(e.to_string());
+            }
+        }
+
+        if !exists {
+            if let Err(e) = sqlx::query("INSERT INTO `history_fts` (`history_fts`) VALUES ('rebuild')").execute(&self.connection).await {
+                return Err(e.to_string());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Insert or update a channel.
+    pub async fn save(&self, channel: &Channel) -> Result<(), String> {
+        let query = sqlx::query("INSERT OR REPLACE INTO `channels` (`id`, `name`, `owner`, `topic`, `options`, `member_limit`) VALUES (?, ?, ?, ?, ?, ?)")
+            .bind(channel.id().to_string())
+            .bind(channel.name())
+            .bind(channel.owner_id().map(|o| o.to_string()))
+            .bind(channel.topic())
+            .bind(channel.options().bits() as i64)
+            .bind(channel.member_limit() as i64)
+            .execute(&self.connection)
+            .await;
+
+        match query {
+            Ok(_)   => Ok(()),
+            Err(e)  => Err(e.to_string())
+        }
+    }
+
+    /// Save a channel if it has Persist, otherwise remove it from the store.
+    ///
+    /// Call after changing anything that is stored: topic, options, owner or member limit.
+    pub async fn persist(&self, channel: &Channel) {
+        let result = if channel.has_option(ChannelOptions::Persist) {
+            self.save(channel).await
+        } else {
+            self.delete(channel.id()).await
+        };
+
+        if let Err(e) = result {
+            error!(channel = %channel.id(), error = %e, "channel store: unable to save");
+        }
+    }
+
+    pub async fn delete(&self, id: Uuid) -> Result<(), String> {
+        let query = sqlx::query("DELETE FROM `channels` WHERE `id` = ?")
+            .bind(id.to_string())
+            .execute(&self.connection)
+            .await;
+
+        match query {
+            Ok(_)   => Ok(()),
+            Err(e)  => Err(e.to_string())
+        }
+    }
+
+    /// Load every stored channel. Rows with an unreadable id are skipped.
+    pub async fn load(&self) -> Result<Vec<Channel>, String> {
+        let rows = match sqlx::query("SELECT `id`, `name`, `owner`, `topic`, `options`, `member_limit` FROM `channels`")
+            .fetch_all(&self.connection)
+            .await {
+            Ok(rows)    => rows,
+            Err(e)      => return Err(e.to_string())
+        };
+
+        let mut channels: Vec<Channel> = Vec::new();
+
+        for row in rows {
+            let id = match Uuid::from_str(&row.get::<String, _>("id")) {
+                Ok(id)  => id,
+                Err(_)  => continue
+            };
+            let owner = row.get::<Option<String>, _>("owner").and_then(|o| Uuid::from_str(&o).ok());
+            let options = row.get::<i64, _>("options") as u64;
+            let member_limit = row.get::<i64, _>("member_limit") as u64;
+            channels.push(Channel::restore(id, row.get("name"), owner, row.get("topic"), options, member_limit));
+        }
+
+        Ok(channels)
+    }
+
+    /// Add a channel message to the history. Only Message and Topic are stored.
+    ///
+    /// Messages of HiddenMessages channels are marked hidden and only replayed to agents.
+    pub async fn add_history(&self, channel: &Channel, message: &CMessage, ip: IpAddr) -> Result<(), String> {
+        let (r#type, text) = match &message.r#type {
+            MessageType::Message(text)  => ("Message", text),
+            MessageType::Topic(text)    => ("Topic", text),
+            _                           => return Ok(())
+        };
+
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let query = sqlx::query("INSERT INTO `history` (`channel`, `id`, `time`, `source`, `name`, `type`, `message`, `hidden`, `channel_name`, `ip`) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")
+            .bind(channel.id().to_string())
+            .bind(message.id as i64)
+            .bind(time as i64)
+            .bind(message.source.to_string())
+            .bind(&message.message)
+            .bind(r#type)
+            .bind(text)
+            .bind(channel.has_option(ChannelOptions::HiddenMessages))
+            .bind(channel.name())
+            .bind(ip.to_string())
+            .execute(&self.connection)
+            .await;
+
+        match query {
+            Ok(_)   => Ok(()),
+            Err(e)  => Err(e.to_string())
+        }
+    }
+
+    /// Delete history older than `before` (unix seconds) and return how many entries went.
+    ///
+    /// With `channel` only that channel is pruned, otherwise every channel but those in `except`.
+    pub async fn delete_history_before(&self, before: u64, channel: Option<Uuid>, except: &[Uuid]) -> Result<u64, String> {
+        let mut sql = "DELETE FROM `history` WHERE `time` < ?".to_string();
+
+        if channel.is_some() {
+            sql.push_str(" AND `channel` = ?");
+        } else if !except.is_empty() {
+            sql.push_str(&format!(" AND `channel` NOT IN ({})", vec!["?"; except.len()].join(", ")));
+        }
+
+        let mut query = sqlx::query(&sql).bind(before.min(i64::MAX as u64) as i64);
+
+        match channel {
+            Some(id)    => query = query.bind(id.to_string()),
+            None        => for id in except {
+                query = query.bind(id.to_string());
+            }
+        }
+
+        match query.execute(&self.connection).await {
+            Ok(result)  => Ok(result.rows_affected()),
+            Err(e)      => Err(e.to_string())
+        }
+    }
+
+    /// Fetch up to `count` history entries of a channel, oldest first.
+    ///
+    /// With `before`, only entries older than that message id are returned.
+    pub async fn history(&self, channel: Uuid, count: u64, before: Option<u64>, include_hidden: bool) -> Result<Vec<HistoryEntry>, String> {
+        let query = match before {
+            Some(id)    => sqlx::query("SELECT `id`, `time`, `source`, `name`, `type`, `message` FROM `history`
+                    WHERE `channel` = ? AND (`hidden` = 0 OR ?)
+                    AND `seq` < (SELECT `seq` FROM `history` WHERE `channel` = ? AND `id` = ?)
+                    ORDER BY `seq` DESC LIMIT ?")
+                .bind(channel.to_string())
+                .bind(include_hidden)
+                .bind(channel.to_string())
+                .bind(id as i64)
+                .bind(count as i64),
+            None        => sqlx::query("SELECT `id`, `time`, `source`, `name`, `type`, `message` FROM `history`
+                    WHERE `channel` = ? AND (`hidden` = 0 OR ?)
+                    ORDER BY `seq` DESC LIMIT ?")
+                .bind(channel.to_string())
+                .bind(include_hidden)
+                .bind(count as i64)
+        };
+
+        let rows = match query.fetch_all(&self.connection).await {
+            Ok(rows)    => rows,
+            Err(e)      => return Err(e.to_string())
+        };
+
+        let mut entries: Vec<HistoryEntry> = rows.iter().map(|row| HistoryEntry {
+            id: row.get::<i64, _>("id") as u64,
+            time: row.get::<i64, _>("time") as u64,
+            source: Uuid::from_str(&row.get::<String, _>("source")).unwrap_or_default(),
+            name: row.get("name"),
+            r#type: row.get("type"),
+            message: row.get("message")
+        }).collect();
+
+        entries.reverse();
+        Ok(entries)
+    }
+
+    /// Rows skipped to reach a search page, None when the page is too large to exist.
+    pub fn search_offset(page: u64, page_size: u64) -> Option<i64> {
+        page.checked_mul(page_size).and_then(|offset| i64::try_from(offset).ok())
+    }
+
+    /// Search the history of every channel, newest first. Returns the page and if there are more.
+    ///
+    /// Every word of `text` must appear in the message, words are quoted so no fts syntax leaks through.
+    pub async fn search(&self, search: &HistorySearch, page_size: u64) -> Result<(Vec<SearchEntry>, bool), String> {
+        let text = search.text.as_ref()
+            .map(|t| t.split_whitespace().map(|w| format!("\"{}\"", w.replace('"', "\"\""))).collect::<Vec<String>>().join(" "))
+            .filter(|t| !t.is_empty());
+
+        let query = sqlx::query("SELECT `channel`, `channel_name`, `id`, `time`, `source`, `name`, `ip`, `type`, `message` FROM `history`
+                WHERE (?1 IS NULL OR `seq` IN (SELECT `rowid` FROM `history_fts` WHERE `history_fts` MATCH ?1))
+                AND (?2 IS NULL OR `name` = ?2 COLLATE NOCASE)
+                AND (?3 IS NULL OR `ip` = ?3)
+                AND (?4 IS NULL OR `channel` = ?4)
+                AND (?5 IS NULL OR `time` >= ?5)
+                AND (?6 IS NULL OR `time` <= ?6)
+                ORDER BY `seq` DESC LIMIT ?7 OFFSET ?8")
+            .bind(text)
+            .bind(search.name.clone())
+            .bind(search.ip.map(|ip| ip.to_string()))
+            .bind(search.channel.map(|c| c.to_string()))
+            .bind(search.from.map(|f| f as i64))
+            .bind(search.to.map(|t| t as i64))
+            .bind(page_size.saturating_add(1).min(i64::MAX as u64) as i64)
+            .bind(match ChannelStore::search_offset(search.page, page_size) {
+                Some(offset)    => offset,
+                None            => return Err(format!("page {} is out of range", search.page))
+            });
+
+        let rows = match query.fetch_all(&self.connection).await {
+            Ok(rows)    => rows,
+            Err(e)      => return Err(e.to_string())
+        };
+
+        let more = rows.len() as u64 > page_size;
+        let results = rows.iter().take(page_size as usize).map(|row| SearchEntry {
+            channel: Uuid::from_str(&row.get::<String, _>("channel")).unwrap_or_default(),
+            channel_name: row.get("channel_name"),
+            ip: row.get::<Option<String>, _>("ip").and_then(|ip| IpAddr::from_str(&ip).ok()),
+            entry: HistoryEntry {
+                id: row.get::<i64, _>("id") as u64,
+                time: row.get::<i64, _>("time") as u64,
+                source: Uuid::from_str(&row.get::<String, _>("source")).unwrap_or_default(),
+                name: row.get("name"),
+                r#type: row.get("type"),
+                message: row.get("message")
+            }
+        }).collect();
+
+        Ok((results, more))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    pub(crate) async fn store() -> ChannelStore {
+        let file = std::env::temp_dir().join(format!("chatd-test-{}.db", Uuid::new_v4()));
+        let store = ChannelStore::new(&file.to_string_lossy()).await.unwrap();
+        store.create_tables().await.unwrap();
+        store
+    }
+
+    /// Saves `count` messages numbered from 0, returning their message ids in order.
+    pub(crate) async fn say(store: &ChannelStore, channel: &Channel, count: u64) -> Vec<u64> {
+        let mut ids = Vec::new();
+
+        for n in 0..count {
+            let message = CMessage::new(MessageType::Message(format!("message {}", n)), Uuid::new_v4(), channel.id(), Some("Guest-1".to_string()));
+            store.add_history(channel, &message, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))).await.unwrap();
+            ids.push(message.id);
+        }
+        ids
+    }
+
+    fn search(text: Option<&str>, page: u64) -> HistorySearch {
+        HistorySearch { text: text.map(|t| t.to_string()), name: None, ip: None, channel: None, from: None, to: None, page }
+    }
+
+    #[tokio::test]
+    async fn history_pages_back_from_a_message() {
+        let store = store().await;
+        let channel = Channel::new("Support".to_string(), None, false);
+        let ids = say(&store, &channel, 10).await;
+
+        let latest = store.history(channel.id(), 4, None, false).await.unwrap();
+        assert_eq!(latest.iter().map(|e| e.id).collect::<Vec<u64>>(), ids[6..10].to_vec());
+
+        let older = store.history(channel.id(), 4, Some(ids[6]), false).await.unwrap();
+        assert_eq!(older.iter().map(|e| e.id).collect::<Vec<u64>>(), ids[2..6].to_vec());
+
+        let oldest = store.history(channel.id(), 4, Some(ids[2]), false).await.unwrap();
+        assert_eq!(oldest.iter().map(|e| e.id).collect::<Vec<u64>>(), ids[0..2].to_vec());
+    }
+
+    #[tokio::test]
+    async fn search_pages_newest_first() {
+        let store = store().await;
+        let channel = Channel::new("Support".to_string(), None, false);
+        say(&store, &channel, 5).await;
+
+        let (first, more) = store.search(&search(Some("message"), 0), 2).await.unwrap();
+        assert!(more);
+        assert_eq!(first.iter().map(|r| r.entry.message.clone()).collect::<Vec<String>>(), vec!["message 4", "message 3"]);
+
+        let (last, more) = store.search(&search(Some("message"), 2), 2).await.unwrap();
+        assert!(!more);
+        assert_eq!(last.len(), 1);
+        assert_eq!(last[0].entry.message, "message 0");
+
+        let (found, _) = store.search(&search(Some("3"), 0), 2).await.unwrap();
+        assert_eq!(found.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn search_rejects_pages_past_the_end() {
+        let store = store().await;
+        assert!(ChannelStore::search_offset(u64::MAX, 50).is_none());
+        assert!(ChannelStore::search_offset(u64::MAX / 50, 50).is_none());
+        assert_eq!(ChannelStore::search_offset(3, 50), Some(150));
+        assert!(store.search(&search(None, u64::MAX), 50).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn persistent_channels_load_back() {
+        let store = store().await;
+        let owner = Uuid::new_v4();
+        let mut kept = Channel::new("Sales".to_string(), Some(owner), false);
+        kept.add_option(ChannelOptions::Persist);
+        kept.set_topic(Some("Ask us anything".to_string())).await;
+        kept.set_member_limit(12);
+        let passing = Channel::new("Support".to_string(), None, false);
+        store.persist(&kept).await;
+        store.persist(&passing).await;
+
+        let loaded = store.load().await.unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!((loaded[0].id(), loaded[0].name(), loaded[0].owner_id()), (kept.id(), kept.name(), Some(owner)));
+        assert_eq!(loaded[0].topic().as_deref(), Some("Ask us anything"));
+        assert_eq!(loaded[0].member_limit(), 12);
+        assert!(loaded[0].options() == kept.options());
+
+        // dropping Persist removes the channel from the store
+        kept.set_options(kept.options().xor(ChannelOptions::Persist));
+        store.persist(&kept).await;
+        assert!(store.load().await.unwrap().is_empty());
+    }
+}
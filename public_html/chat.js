@@ -13,6 +13,9 @@ class ChannelOptions {
   static HiddenMessages  = 1 << 9; // messages are hidden from non agents
   static Invisible       = 1 << 10; //channel will not show up as active to non agents
   static Secret          = 1 << 11; // channel will not show up in channel listings to non agents
+  static Throttle        = 1 << 12; // non agents may only send a message every so often
+  static AntiFlood       = 1 << 13; // sustained flooding will get the user banned
+  static Limit           = 1 << 14; // only a few more members may join every so often
 }
 
 class MessageType {
@@ -20,7 +23,16 @@ class MessageType {
     return { "AuthGuest": username || null };
   }
   static AuthDevice(deviceuuid, username) {
-    // TODO
+    return { "AuthDevice": [deviceuuid, username] };
+  }
+  static Resume(userid, token) {
+    return { "Resume": [userid, token] };
+  }
+  static RegisterDevice(userid, username) {
+    return { "RegisterDevice": [userid, username] };
+  }
+  static RevokeDevice(username) {
+    return { "RevokeDevice": username };
   }
   static AuthAgent(nickname, username, password) {
     return { "AuthAgent": [nickname, username, password] };
@@ -55,9 +67,66 @@ class MessageType {
   static SetChannelModes(modes) {
     return { "SetChannelModes": modes };
   }
+  static ChannelLimit(limit) {
+    return { "ChannelLimit": limit };
+  }
   static Typing(channel) {
     return { "Typing": [VOID_UUID, channel] };
   } 
+  static Agents() {
+    return { "Agents": null };
+  }
+  static Unkline(ip) {
+    return { "Unkline": ip };
+  }
+  static Bans() {
+    return { "Bans": null };
+  }
+  static Stats() {
+    return { "Stats": null };
+  }
+  static AgentAdd(username, password, permissions) {
+    return { "AgentAdd": [username, password, permissions] };
+  }
+  static AgentDelete(username) {
+    return { "AgentDelete": username };
+  }
+  static AgentDisable(username, disabled) {
+    return { "AgentDisable": [username, disabled] };
+  }
+  static AgentPassword(username, password) {
+    return { "AgentPassword": [username, password] };
+  }
+  static AgentPermissions(username, permissions) {
+    return { "AgentPermissions": [username, permissions] };
+  }
+  static Wall(message) {
+    return { "Wall": message };
+  }
+  static Wallop(message) {
+    return { "Wallop": message };
+  }
+  static Walladmin(message) {
+    return { "Walladmin": message };
+  }
+  static Invite(channel, userid) {
+    return { "Invite": [channel, userid] };
+  }
+  static Whois(userid) {
+    return { "Whois": userid };
+  }
+  static Presence(presence) {
+    return { "Presence": presence };
+  }
+  static RequestHelp(question) {
+    return { "RequestHelp": question || null };
+  }
+  static History(count, before) {
+    return { "History": [count, before || null] };
+  }
+  static Search(text, name, ip, channel, from, to, page) {
+    return { "Search": { text: text || null, name: name || null, ip: ip || null, channel: channel || null, from: from || null, to: to || null, page: page || 0 } };
+  }
 }
 class CMessage {
   constructor(type, target, message) {
@@ -78,6 +147,8 @@ class ChatClient {
     this.channels = {};
     this.members = [];
     this.is_agent = false;
+    this.resuming = false;
+    this.seen_messages = new Set();
   }
 
   // Connect to the WebSocket server
@@ -93,7 +164,21 @@ class ChatClient {
       var username = document.getElementById('ichatusername').value || null;
       var password = document.getElementById('ichatpassword').value || null;
       
-      if (asGuest) {
+      var device = localStorage.getItem('ichat_device');
+      var resume = sessionStorage.getItem('ichat_resume');
+
+      if (resume) {
+        // page was reloaded, try to pick up the old session
+        var [resume_id, resume_token] = JSON.parse(resume);
+        var message = new CMessage(MessageType.Resume(resume_id, resume_token), VOID_UUID, "");
+        this.sendMessage(message);
+        this.resuming = true;
+      }
+      else if (asGuest && device) {
+        var message = new CMessage(MessageType.AuthDevice(device, localStorage.getItem('ichat_device_username')), VOID_UUID, "");
+        this.sendMessage(message);
+      }
+      else if (asGuest) {
         var message = new CMessage(MessageType.AuthGuest(guestname), VOID_UUID, "");
         this.sendMessage(message);
       }
@@ -123,6 +208,12 @@ class ChatClient {
           ichat_handle_chanmodes(data.type.ChannelModes, data.target);
           break;
 
+        case 'ChannelLimit':
+          if (this.channels[data.target]) {
+            this.channels[data.target].limit = data.type.ChannelLimit;
+          }
+          break;
+
         case 'Topic':
           ichat_handle_channel_topic(data.type.Topic, data.target);
           break;
@@ -131,7 +222,63 @@ class ChatClient {
           ichat_handle_wallop(this, data.type.Wallop);
           break;
 
+        case 'Wall':
+          ichat_show_motd("Announcement from " + data.message, data.type.Wall);
+          break;
+
+        case 'Walladmin':
+          ichat_handle_wallop(this, data.type.Walladmin);
+          break;
+
+        case 'AgentList':
+          ichat_handle_agentlist(data.type.AgentList);
+          break;
+
+        case 'BanList':
+          ichat_handle_banlist(data.type.BanList);
+          break;
+
+        case 'StatsReply':
+          ichat_handle_stats(data.type.StatsReply);
+          break;
+
+        case 'DeviceRegistered':
+          localStorage.setItem('ichat_device', data.type.DeviceRegistered[0]);
+          localStorage.setItem('ichat_device_username', data.type.DeviceRegistered[1]);
+          break;
+
+        case 'Invite':
+          ichat_handle_invite(this, data);
+          break;
+
+        case 'SearchReply':
+          ichat_handle_search(this, data.type.SearchReply);
+          break;
+
+        case 'QueueState':
+          ichat_handle_queuestate(this, data);
+          break;
+
+        case 'Presence':
+          ichat_handle_presence(this, data);
+          break;
+
+        case 'HistoryReply':
+          ichat_handle_history(this, data);
+          break;
+
+        case 'WhoisReply':
+          ichat_handle_whois(data.type.WhoisReply);
+          break;
+
         case 'Problem':
+          if (this.resuming && data.type.Problem == 'InvalidAuth') {
+            // the session expired, go back to the login form
+            sessionStorage.removeItem('ichat_resume');
+            this.resuming = false;
+            document.getElementById('ichatloginbutton').disabled = false;
+            break;
+          }
           ichat_show_error(null, data.type.Problem, data.message);
           break;
           
@@ -160,7 +307,9 @@ class ChatClient {
           break;
 
         case 'AuthOk':
-          [this.myid, this.myname] = data.type.AuthOk;
+          [this.myid, this.myname, this.mytoken] = data.type.AuthOk;
+          sessionStorage.setItem('ichat_resume', JSON.stringify([this.myid, this.mytoken]));
+          this.resuming = false;
           document.getElementById('ichatlogin').style.display = 'none';
           document.getElementById('ichatcontainer').style.display = 'block';
           break;
@@ -202,6 +351,7 @@ class ChatClient {
             document.getElementById('ichat-message-list').innerHTML += room_container + chanmen; 
             document.getElementById('ichat-user-list').innerHTML += room_users + generate_user_menu(data.source, data.target, this.myname, this.is_agent);
             document.getElementById('ichat-text-container').innerHTML += room_textarea;
+            this.sendMessage(new CMessage(MessageType.History(50, null), data.target, ""));
 
             // console.log(this.channels);
           } else {
@@ -299,6 +449,8 @@ class ChatClient {
 
   // Disconnect from the WebSocket server
   disconnect() {
+    sessionStorage.removeItem('ichat_resume');
+
     if (this.socket) {
       this.socket.close();
       document.getElementById('ichatloginbutton').disabled = false;
@@ -313,6 +465,61 @@ function ichat_handle_wallop(mychatclient, data) {
   document.querySelectorAll('.ichat-room-container').forEach(element => element.innerHTML += message_div);
 }
 
+function ichat_handle_invite(mychatclient, data) {
+  let [channel, _userid] = data.type.Invite;
+
+  if (confirm("You have been invited to join '" + data.message + "'.\n\nJoin now?")) {
+    mychatclient.sendMessage(new CMessage(MessageType.Join(""), channel, ""));
+  }
+}
+
+function ichat_handle_agentlist(agents) {
+  let content = '';
+
+  agents.forEach(([username, permissions, disabled]) => content += `<li>${username} (${permissions})${disabled ? ' - disabled' : ''}</li>`);
+  ichat_show_motd("Agent Accounts", `<ul class="ichat-modes-list">${content}</ul>`);
+}
+
+function ichat_handle_banlist(bans) {
+  let content = '';
+
+  bans.forEach(ban => content += `<li>${ban.network} by ${ban.added_by} until ${new Date(ban.expires * 1000).toString()} (${ban.reason})</li>`);
+  ichat_show_motd("Server Bans", bans.length == 0 ? 'There are no bans.' : `<ul class="ichat-modes-list">${content}</ul>`);
+}
+
+function ichat_handle_stats(stats) {
+  let message = `<strong>chatd ${stats.version}</strong><br />Uptime: ${stats.uptime} seconds<br />`;
+
+  message += `Clients: ${stats.guests} guests, ${stats.contacts} contacts, ${stats.agents} agents, ${stats.pending_auth} authenticating, ${stats.zombies} resumable<br />`;
+  message += `Channels: ${stats.channels}<br />Bans: ${stats.bans}<br />`;
+  message += `Connections: ${stats.connections_since_start} (${stats.invalid_connects} invalid, ${stats.banned_connects} banned)<br />Guests since start: ${stats.guests_since_start}<br />`;
+  message += `Messages: ${stats.messages_since_start} (${stats.messages_last_minute} in the last minute)`;
+  ichat_show_motd("Server Stats", message);
+}
+
+function ichat_whois(userid) {
+  chatclient.sendMessage(new CMessage(MessageType.Whois(userid), userid, ""));
+}
+
+function ichat_handle_whois(whois) {
+  let connected = new Date(whois.connected * 1000);
+  let channels = '';
+
+  whois.channels.forEach(([id, name]) => channels += '<li>' + name + '</li>');
+  let message = `<strong>${whois.name}</strong> (${whois.type})<br />Connected: ${connected.toString()}<br />Idle: ${whois.idle} seconds<br />`;
+
+  if (whois.presence != null) {
+    message += `Presence: ${whois.presence}<br />`;
+  }
+
+  if (whois.ip != null) {
+    message += `Address: ${whois.ip}<br />Options: ${whois.options}<br />`;
+  }
+
+  message += `Channels:<ul class="ichat-modes-list">${channels}</ul>`;
+  ichat_show_motd("Whois " + whois.name, message);
+}
+
 function ichat_handle_kick(mychatclient, data) {
   [channel, target, reason] = data.type.Kick;
 
@@ -506,8 +713,55 @@ function ichat_parse_command_string(channelid, command, args, mychatclient) {
       return new CMessage(MessageType.Channels(), VOID_UUID, "");
 
     case 'WHOIS':
+      if (args == null || !args[0].match(/^[0-9a-f]{8}-[0-9a-f]{4}-4[0-9a-f]{3}-[89ab][0-9a-f]{3}-[0-9a-f]{12}$/i)) {
+        ichat_show_error(null, "Invalid Whois Argument", "The WHOIS command requires a user uuid parameter.");
+        return -1;
+      }
 
-      break;
+      return new CMessage(MessageType.Whois(args[0]), args[0], "");
+
+    case 'AWAY':
+      return new CMessage(MessageType.Presence('Away'), VOID_UUID, "");
+
+    case 'BUSY':
+      return new CMessage(MessageType.Presence('Busy'), VOID_UUID, "");
+
+    case 'BACK':
+    case 'AVAILABLE':
+      return new CMessage(MessageType.Presence('Available'), VOID_UUID, "");
+
+    case 'OFFLINE':
+      // stay connected but appear offline to the other agents
+      return new CMessage(MessageType.Presence('Offline'), VOID_UUID, "");
+
+    case 'SUPPORT':
+      // ask to be given an agent in a private support channel
+      return new CMessage(MessageType.RequestHelp(args == null ? null : args.join(" ")), VOID_UUID, "");
+
+    case 'SEARCH':
+      // /search [name:x] [ip:x] [channel:uuid] [from:yyyy-mm-dd] [to:yyyy-mm-dd] [page:n] words
+      if (args == null) {
+        ichat_show_error(null, "Missing Search Argument", "The SEARCH command requires words to search for, or a name:, ip:, channel:, from: or to: filter.");
+        return -1;
+      }
+
+      let search = { text: [] };
+
+      for (const arg of args) {
+        let split = arg.indexOf(':');
+        let key = split > 0 ? arg.substr(0, split).toLowerCase() : '';
+        let value = arg.substr(split + 1);
+
+        if (['name', 'ip', 'channel', 'page'].includes(key)) {
+          search[key] = value;
+        } else if (key == 'from' || key == 'to') {
+          search[key] = Math.floor(Date.parse(value) / 1000) || null;
+        } else {
+          search.text.push(arg);
+        }
+      }
+
+      return new CMessage(MessageType.Search(search.text.join(' '), search.name, search.ip, search.channel, search.from, search.to, parseInt(search.page) || 0), VOID_UUID, "");
 
     case 'TITLE':
 
@@ -533,8 +787,9 @@ function ichat_parse_command_string(channelid, command, args, mychatclient) {
         reason = "none given";
       }
 
-      if (!ip.match(/^[0-9]{1,3}\.[0-9]{1,3}\.[0-9]{1,3}\.[0-9]{1,3}$/)) {
-        ichat_show_error(null, "Invalid KLine Ip", "The paramaters are <ip> <expiry_in_seconds> <reason>");
+      // ipv4 or ipv6 with an optional /prefix, the server checks it properly
+      if (ip == null || !ip.match(/^[0-9a-f:.]+(\/[0-9]{1,3})?$/i)) {
+        ichat_show_error(null, "Invalid KLine Ip", "The paramaters are <ip or cidr range> <expiry_in_seconds> <reason>");
         return -1;
       }
 
@@ -544,9 +799,100 @@ function ichat_parse_command_string(channelid, command, args, mychatclient) {
 
       break;
 
-    case 'STATS':
+    case 'UNKLINE':
+      if (args == null) {
+        ichat_show_error(null, "Invalid UnKLine Ip", "The paramater is the <ip or cidr range> of the ban");
+        return -1;
+      }
 
-      break;
+      return new CMessage(MessageType.Unkline(args[0]), VOID_UUID, "");
+
+    case 'BANS':
+      return new CMessage(MessageType.Bans(), VOID_UUID, "");
+
+    case 'AGENTS':
+      return new CMessage(MessageType.Agents(), VOID_UUID, "");
+
+    case 'AGENTADD':
+      if (args == null || args.length < 3) {
+        ichat_show_error(null, "Missing Agent Arguments", "The parameters are &lt;username&gt; &lt;password&gt; &lt;permissions&gt;");
+        return -1;
+      }
+
+      return new CMessage(MessageType.AgentAdd(args[0], args[1], parseInt(args[2]) || 0), VOID_UUID, "");
+
+    case 'AGENTDEL':
+    case 'AGENTDISABLE':
+    case 'AGENTENABLE':
+      if (args == null) {
+        ichat_show_error(null, "Missing Agent Argument", "The " + command.toUpperCase() + " command requires a username.");
+        return -1;
+      }
+
+      if (command.toUpperCase() == 'AGENTDEL') {
+        return new CMessage(MessageType.AgentDelete(args[0]), VOID_UUID, "");
+      }
+
+      return new CMessage(MessageType.AgentDisable(args[0], command.toUpperCase() == 'AGENTDISABLE'), VOID_UUID, "");
+
+    case 'AGENTPASS':
+    case 'AGENTPERMS':
+      if (args == null || args.length < 2) {
+        ichat_show_error(null, "Missing Agent Arguments", "The " + command.toUpperCase() + " command requires a username and a value.");
+        return -1;
+      }
+
+      if (command.toUpperCase() == 'AGENTPASS') {
+        return new CMessage(MessageType.AgentPassword(args[0], args[1]), VOID_UUID, "");
+      }
+
+      return new CMessage(MessageType.AgentPermissions(args[0], parseInt(args[1]) || 0), VOID_UUID, "");
+
+    case 'REGISTER':
+      if (args == null || args.length < 2) {
+        ichat_show_error(null, "Missing Register Arguments", "The REGISTER command requires a user uuid and a username.");
+        return -1;
+      }
+
+      return new CMessage(MessageType.RegisterDevice(args.shift(), args.join(" ")), VOID_UUID, "");
+
+    case 'REVOKE':
+      if (args == null) {
+        ichat_show_error(null, "Missing Revoke Argument", "The REVOKE command requires a username.");
+        return -1;
+      }
+
+      return new CMessage(MessageType.RevokeDevice(args.join(" ")), VOID_UUID, "");
+
+    case 'INVITE':
+      if (args == null || !args[0].match(/^[0-9a-f]{8}-[0-9a-f]{4}-4[0-9a-f]{3}-[89ab][0-9a-f]{3}-[0-9a-f]{12}$/i)) {
+        ichat_show_error(null, "Invalid Invite Argument", "The INVITE command requires a user uuid parameter.");
+        return -1;
+      }
+
+      return new CMessage(MessageType.Invite(channelid, args[0]), channelid, "");
+
+    case 'WALL':
+    case 'WALLOP':
+    case 'WALLADMIN':
+      if (args == null) {
+        ichat_show_error(null, "Missing Message Argument", "The " + command.toUpperCase() + " command requires a message.");
+        return -1;
+      }
+
+      args = args.join(" ");
+
+      switch (command.toUpperCase()) {
+        case 'WALL':
+          return new CMessage(MessageType.Wall(args), VOID_UUID, "");
+        case 'WALLOP':
+          return new CMessage(MessageType.Wallop(args), VOID_UUID, "");
+        default:
+          return new CMessage(MessageType.Walladmin(args), VOID_UUID, "");
+      }
+
+    case 'STATS':
+      return new CMessage(MessageType.Stats(), VOID_UUID, "");
 
     case 'TYPING':
 
@@ -606,6 +952,12 @@ function ichat_save_channel_settings(mychatclient) {
     mychatclient.sendMessage(new CMessage(MessageType.Topic(topic), chanid, ""));
   }
 
+  let limit = parseInt(document.getElementById('ichat-settings-channel-limit').value) || 0;
+
+  if ((mychatclient.channels[chanid].limit || 0) != limit) {
+    mychatclient.sendMessage(new CMessage(MessageType.ChannelLimit(limit), chanid, ""));
+  }
+
   let modes = 0;
   
   if (document.getElementById('ichat-setting-chan-clientinvites').checked)
@@ -644,6 +996,15 @@ function ichat_save_channel_settings(mychatclient) {
   if (document.getElementById('ichat-setting-chan-secret').checked)
     modes |= ChannelOptions.Secret;
 
+  if (document.getElementById('ichat-setting-chan-throttle').checked)
+    modes |= ChannelOptions.Throttle;
+
+  if (document.getElementById('ichat-setting-chan-antiflood').checked)
+    modes |= ChannelOptions.AntiFlood;
+
+  if (document.getElementById('ichat-setting-chan-limit').checked)
+    modes |= ChannelOptions.Limit;
+
   if (modes != mychatclient.channels[chanid].modes) {
     mychatclient.sendMessage(new CMessage(MessageType.SetChannelModes(modes), chanid, ""));
   }
@@ -654,6 +1015,7 @@ function ichat_save_channel_settings(mychatclient) {
 function ichat_get_channel_info(mychatclient, id) {
   document.getElementById('ichat-settings-channel-name').value = mychatclient.channels[id].name;
   document.getElementById('ichat-settings-channel-topic').value = mychatclient.channels[id].topic || "";
+  document.getElementById('ichat-settings-channel-limit').value = mychatclient.channels[id].limit || 0;
   document.getElementById('ichat-settings-channel-id').value = id;
 
   console.log("modes:" + mychatclient.channels[id].modes);
@@ -730,6 +1092,24 @@ function ichat_get_channel_info(mychatclient, id) {
     document.getElementById('ichat-setting-chan-secret').checked = false;
   }
 
+  if (mychatclient.channels[id].modes.includes('Slow Mode')) {
+    document.getElementById('ichat-setting-chan-throttle').checked = true;
+  }else {
+    document.getElementById('ichat-setting-chan-throttle').checked = false;
+  }
+
+  if (mychatclient.channels[id].modes.includes('Anti Flood')) {
+    document.getElementById('ichat-setting-chan-antiflood').checked = true;
+  }else {
+    document.getElementById('ichat-setting-chan-antiflood').checked = false;
+  }
+
+  if (mychatclient.channels[id].modes.includes('Rising Limit')) {
+    document.getElementById('ichat-setting-chan-limit').checked = true;
+  }else {
+    document.getElementById('ichat-setting-chan-limit').checked = false;
+  }
+
   document.getElementById('ichat-channel-settings-dialog').style.display = 'block';
 }
 
@@ -810,10 +1190,31 @@ function ichat_handle_userlist(obj, data) {
         user_container.innerHTML += generate_user_menu(grouping[0], data.target, grouping[1], obj.is_agent);
       }
     }
+
+    // only agents have a presence
+    if (grouping[2] != null) {
+      ichat_set_presence(data.target, grouping[0], grouping[2]);
+    }
   });
   user_count.innerText = parseInt(user_count.innerText) + users.length;
 }
 
+function ichat_set_presence(channel, userid, presence) {
+  let entry = document.getElementById('ichat-user-entry-' + channel + '-' + userid);
+
+  if (entry) {
+    entry.classList.remove('ichat-presence-Available', 'ichat-presence-Away', 'ichat-presence-Busy', 'ichat-presence-Offline');
+    entry.classList.add('ichat-presence-' + presence);
+    entry.title = presence;
+  }
+}
+
+function ichat_handle_presence(obj, data) {
+  let presence = data.type.Presence;
+
+  Object.keys(obj.channels).forEach(channel => ichat_set_presence(channel, data.source, presence));
+}
+
 function ichat_handle_quit(obj, data) {
   let reason = data.type.Quit;
   let container = document.getElementById('ichat-roomcontainer-' + data.target);
@@ -841,6 +1242,9 @@ function ichat_handle_message(obj, data) {
   if (data.source == obj.myid) {
     return; // skip our own as we already print it.
   } 
+
+  // queued messages may also come back in the history reply
+  obj.seen_messages.add(data.id);
   let channel_tab = document.getElementById('ichat-roomtab-' + data.target);
 
   if ((!channel_tab.classList.contains('ichat-roomtab-newmessage')) && (!channel_tab.classList.contains('ichat-roomtab-active'))) {
@@ -858,6 +1262,79 @@ function ichat_handle_message(obj, data) {
   });
 }
 
+function ichat_handle_queuestate(obj, data) {
+  let [position, queued] = data.type.QueueState;
+  let container = document.getElementById('ichat-roomcontainer-' + data.target);
+
+  if (!container) {
+    return;
+  }
+
+  let mydate = new Date();
+  let text = position == 0
+    ? 'An agent has joined and can see your messages.'
+    : `Waiting for an agent, you are number ${position} in line. ${queued} message(s) will be shown to the agent when they join.`;
+
+  container.innerHTML += `<div title="${mydate.toString()}" class="ichat-message-status"><i class="fa fa-clock-o"> </i> ${text}</div>`;
+  container.scrollTo({
+    top: container.scrollHeight,
+    behavior: 'smooth'
+  });
+}
+
+function ichat_handle_history(obj, data) {
+  let container = document.getElementById('ichat-roomcontainer-' + data.target);
+
+  if (!container || data.type.HistoryReply.length == 0) {
+    return;
+  }
+
+  let history = '';
+
+  for (const entry of data.type.HistoryReply) {
+    let mydate = new Date(entry.time * 1000);
+
+    if (obj.seen_messages.has(entry.id)) {
+      continue;
+    }
+
+    if (entry.type == 'Topic') {
+      history += `<div title="${mydate.toString()}" class="ichat-message-status ichat-history"><i class="fa fa-info"> </i> ${entry.name} set the topic to: ${entry.message}</div>`;
+    } else if (entry.source == obj.myid) {
+      history += `<div title="${mydate.toString()}" class="ichat-message-mine ichat-history"><span class="ichat-namedisplay">You:</span> <span class="ichat-messagedisplay">${entry.message}</span></div>`;
+    } else {
+      history += `<div title="sent: ${mydate.toString()}" class="ichat-message-other ichat-history"><span class="ichat-namedisplay">${entry.name}:</span> <span class="ichat-messagedisplay">${entry.message}</span></div>`;
+    }
+  }
+
+  // history goes above everything said since we joined
+  container.innerHTML = history + container.innerHTML;
+  container.scrollTo({
+    top: container.scrollHeight,
+    behavior: 'smooth'
+  });
+}
+
+function ichat_handle_search(obj, [page, more, results]) {
+  let message = '';
+
+  if (results.length == 0) {
+    message = 'No saved messages matched the search.';
+  }
+
+  for (const result of results) {
+    let mydate = new Date(result.entry.time * 1000);
+    message += `<div class="ichat-message-other" title="${result.channel}"><small>${mydate.toLocaleString()} in <strong>${result.channel_name}</strong> from ${result.ip || 'unknown'}</small><br />`
+      + `<span class="ichat-namedisplay">${result.entry.name}:</span> <span class="ichat-messagedisplay">${result.entry.message}</span></div>`;
+  }
+
+  if (more) {
+    message += `<br />More results: /search page:${page + 1} with the same filters.`;
+  }
+
+  ichat_show_motd("Search Results (page " + (page + 1) + ")", message);
+}
+
 function ichat_swap_channels(id) {
   let channel_tab = document.getElementById('ichat-roomtab-' + id);
   let channel_messages = document.getElementById('ichat-roomcontainer-' + id);